@@ -0,0 +1,316 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To handle files
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+// To manage paths
+use std::path::Path;
+// To manage date and time
+use std::time::{SystemTime, UNIX_EPOCH};
+// To hash event lines
+use hex::encode;
+use sha3::{Sha3_512, Digest};
+// To handle JSON objects
+use serde_json::{json, Value};
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+// To read rotated events files
+use crate::rotation;
+// To read event fields
+use crate::format;
+// To generate seeds
+use crate::utils;
+
+// Global constants definitions
+pub const SIDECAR_EXTENSION: &str = ".chain";
+const TAIL_CHUNK: u64 = 65536;
+
+// ----------------------------------------------------------------------------
+
+// Hash of an event line as written in the events file
+pub fn get_hash(line: &str) -> String {
+    let mut hasher = Sha3_512::new();
+    hasher.update(line.as_bytes());
+    encode(hasher.finalize())
+}
+
+// ----------------------------------------------------------------------------
+
+// Get the sidecar path where chain seeds of an events file are recorded
+pub fn get_sidecar(file: &str) -> String {
+    format!("{}{}", file, SIDECAR_EXTENSION)
+}
+
+// ----------------------------------------------------------------------------
+
+// Read the last line of a file without reading all of it
+pub fn get_last_line(path: &str) -> Option<String> {
+    let mut file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    let mut start = len;
+    let mut data: Vec<u8> = Vec::new();
+    loop {
+        let from = start.saturating_sub(TAIL_CHUNK);
+        let mut chunk = vec![0; (start - from) as usize];
+        file.seek(SeekFrom::Start(from)).ok()?;
+        file.read_exact(&mut chunk).ok()?;
+        chunk.extend_from_slice(&data);
+        data = chunk;
+        start = from;
+
+        let content = data.strip_suffix(b"\n").unwrap_or(&data);
+        if let Some(pos) = content.iter().rposition(|c| *c == b'\n') {
+            return Some(String::from_utf8_lossy(&content[pos + 1..]).to_string())
+        }
+        if start == 0 {
+            return if content.is_empty() { None } else { Some(String::from_utf8_lossy(content).to_string()) }
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Extract the previous hash stored in an event line of any format
+pub fn get_prev_hash(line: &str) -> Option<String> {
+    format::get_field(line, "prev_hash")
+}
+
+// ----------------------------------------------------------------------------
+
+// Hash chain state of the events writer
+pub struct Chain {
+    pub enabled: bool,
+    sidecar: String,
+    last: String
+}
+
+impl Chain {
+    pub fn new(config: &config::Config) -> Self {
+        Chain {
+            enabled: config.chain,
+            sidecar: get_sidecar(&config.events_file),
+            last: String::new()
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Called when the events file is opened, starts a new chain segment with
+    // a recorded seed unless the file ends with the last chained event
+    pub fn resume(&mut self, file: &str) {
+        if ! self.enabled { return }
+        let previous = get_last_line(file).map(|line| get_hash(&line)).unwrap_or_default();
+        if ! self.last.is_empty() && previous == self.last { return }
+
+        let seed = get_hash(&format!("{}{}", utils::get_uuid(), previous));
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis();
+        let record = json!({ "seed": seed, "previous": previous, "timestamp": format!("{}", timestamp) });
+        let written = OpenOptions::new().create(true).append(true).open(&self.sidecar)
+            .and_then(|mut f| { writeln!(f, "{}", record)?; f.sync_data() });
+        match written {
+            Ok(_) => info!("Events hash chain seeded in {}", self.sidecar),
+            Err(e) => error!("Events hash chain seed could not be recorded in {}, Err: [{}]", self.sidecar, e)
+        }
+        self.last = seed;
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Add the previous hash to an event object
+    pub fn link(&self, obj: &mut Value) {
+        if self.enabled { obj["prev_hash"] = json!(self.last.clone()); }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Record the line written as the new end of the chain
+    pub fn push(&mut self, line: &str) {
+        if self.enabled { self.last = get_hash(line); }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Read recorded seeds as (seed, hash of the line before the seed)
+pub fn read_seeds(sidecar: &str) -> Vec<(String, String)> {
+    let file = match File::open(sidecar) {
+        Ok(f) => f,
+        Err(_) => return Vec::new()
+    };
+    std::io::BufReader::new(file).lines().map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str::<Value>(&line).ok())
+        .map(|obj| (String::from(obj["seed"].as_str().unwrap_or("")),
+            String::from(obj["previous"].as_str().unwrap_or(""))))
+        .collect()
+}
+
+// ----------------------------------------------------------------------------
+
+// Walk an events file and return the number of verified lines, or the line
+// number and reason where the chain breaks
+pub fn verify(path: &Path, seeds: &[(String, String)]) -> Result<usize, (usize, String)> {
+    let reader = rotation::open_reader(path).map_err(|e| (0, format!("cannot read file: {}", e)))?;
+    let mut last: Option<String> = None;
+    let mut count = 0;
+    for (index, line) in reader.lines().enumerate() {
+        let number = index + 1;
+        let line = line.map_err(|e| (number, format!("cannot read line: {}", e)))?;
+        let prev = match get_prev_hash(&line) {
+            Some(hash) => hash,
+            None => return Err((number, String::from("event has no prev_hash")))
+        };
+        let linked = match &last {
+            Some(hash) => prev == *hash,
+            None => false
+        };
+        if ! linked {
+            let previous = last.clone().unwrap_or_default();
+            // A new segment is valid only if it was seeded right after the previous line
+            match seeds.iter().find(|(seed, _)| *seed == prev) {
+                Some((_, seeded)) if last.is_none() || *seeded == previous => {},
+                Some(_) => return Err((number, String::from("events missing before this line"))),
+                None => return Err((number, format!("prev_hash {} does not match previous event", prev)))
+            }
+        }
+        last = Some(get_hash(&line));
+        count = number;
+    }
+    Ok(count)
+}
+
+// ----------------------------------------------------------------------------
+
+// Command line entry point of `ifim verify-log [file] [--chain sidecar]`
+pub fn verify_command(config: &config::Config, args: &[String]) -> i32 {
+    let mut file = config.events_file.clone();
+    let mut sidecar = get_sidecar(&config.events_file);
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--chain" => match iter.next() {
+                Some(value) => sidecar = value.clone(),
+                None => { eprintln!("Missing value of --chain"); return 2 }
+            },
+            value => file = String::from(value)
+        }
+    }
+
+    let seeds = read_seeds(&sidecar);
+    match verify(Path::new(&file), &seeds) {
+        Ok(count) => {
+            println!("{}: hash chain verified, {} events", file, count);
+            0
+        },
+        Err((line, reason)) => {
+            println!("{}: hash chain broken at line {}: {}", file, line, reason);
+            1
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+    use std::fs;
+
+    fn write_chain(dir: &str, count: usize) -> (String, Vec<String>) {
+        fs::create_dir_all(dir).unwrap();
+        let file = format!("{}/events.json", dir);
+        let mut config = create_test_config("info");
+        config.events_file = file.clone();
+        config.chain = true;
+
+        let mut chain = Chain::new(&config);
+        chain.resume(&file);
+        let mut lines = Vec::new();
+        for i in 0..count {
+            let mut obj = json!({ "id": format!("{}", i) });
+            chain.link(&mut obj);
+            let line = obj.to_string();
+            chain.push(&line);
+            lines.push(line);
+        }
+        fs::write(&file, lines.join("\n") + "\n").unwrap();
+        (file, lines)
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_get_last_line() {
+        let filename = "test_get_last_line";
+        fs::write(filename, "first\nsecond\n").unwrap();
+        assert_eq!(get_last_line(filename), Some(String::from("second")));
+        fs::write(filename, "").unwrap();
+        assert_eq!(get_last_line(filename), None);
+        fs::remove_file(filename).unwrap();
+        assert_eq!(get_last_line(filename), None);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_get_prev_hash() {
+        assert_eq!(get_prev_hash("{\"prev_hash\":\"ab12\"}"), Some(String::from("ab12")));
+        assert_eq!(get_prev_hash("CEF:0|a|b|c|d|e|5|illumosIfimPrevHash=ab12 act=MODIFY"), Some(String::from("ab12")));
+        assert_eq!(get_prev_hash("{\"id\":\"1\"}"), None);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_verify_ok() {
+        let dir = "test_verify_ok";
+        let (file, _) = write_chain(dir, 5);
+        let seeds = read_seeds(&get_sidecar(&file));
+        assert_eq!(verify(Path::new(&file), &seeds), Ok(5));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_verify_deleted_line() {
+        let dir = "test_verify_deleted";
+        let (file, mut lines) = write_chain(dir, 5);
+        lines.remove(2);
+        fs::write(&file, lines.join("\n") + "\n").unwrap();
+        let seeds = read_seeds(&get_sidecar(&file));
+        assert_eq!(verify(Path::new(&file), &seeds).unwrap_err().0, 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_verify_resumed_chain() {
+        let dir = "test_verify_resumed";
+        let (file, lines) = write_chain(dir, 2);
+        let mut config = create_test_config("info");
+        config.events_file = file.clone();
+        config.chain = true;
+
+        // A restart seeds a new segment linked to the current last line
+        let mut chain = Chain::new(&config);
+        chain.resume(&file);
+        let mut obj = json!({ "id": "2" });
+        chain.link(&mut obj);
+        let mut all = lines.clone();
+        all.push(obj.to_string());
+        fs::write(&file, all.join("\n") + "\n").unwrap();
+        let seeds = read_seeds(&get_sidecar(&file));
+        assert_eq!(verify(Path::new(&file), &seeds), Ok(3));
+
+        // Deleting the line before the new segment is detected
+        all.remove(1);
+        fs::write(&file, all.join("\n") + "\n").unwrap();
+        assert_eq!(verify(Path::new(&file), &seeds).unwrap_err().0, 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To manage paths
+use std::path::PathBuf;
+// To keep bursts by path
+use std::collections::HashMap;
+// To manage date and time
+use std::time::{Duration, Instant};
+
+// Global constants definitions
+// A burst is emitted once open this many windows, even if still active
+const MAX_WINDOWS: u32 = 10;
+
+// ----------------------------------------------------------------------------
+
+// Operations seen on a path while its events are coalesced
+pub struct Burst {
+    pub path: PathBuf,
+    pub label: String,
+    pub operations: Vec<String>,
+    window: Duration,
+    first: Instant,
+    last: Instant
+}
+
+impl Burst {
+    // Operation that summarizes the burst, removal is checked on emission
+    pub fn get_operation(&self) -> String {
+        for operation in ["CREATE", "MODIFY"] {
+            if self.operations.iter().any(|o| o == operation) { return String::from(operation) }
+        }
+        self.operations.last().cloned().unwrap_or_else(|| String::from("UNKNOWN"))
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn is_settled(&self, now: Instant) -> bool {
+        now.duration_since(self.last) >= self.window ||
+            now.duration_since(self.first) >= self.window * MAX_WINDOWS
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[derive(Default)]
+pub struct Coalescer {
+    bursts: HashMap<PathBuf, Burst>
+}
+
+impl Coalescer {
+    // Add an event operation to the burst of its path
+    pub fn push(&mut self, path: PathBuf, label: String, operation: String, window: Duration) {
+        let now = Instant::now();
+        let burst = self.bursts.entry(path.clone()).or_insert_with(|| Burst {
+            path, label, operations: Vec::new(), window, first: now, last: now
+        });
+        burst.last = now;
+        if ! burst.operations.contains(&operation) { burst.operations.push(operation); }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Take the bursts with no events during their window, oldest first
+    pub fn settled(&mut self, now: Instant) -> Vec<Burst> {
+        let paths: Vec<PathBuf> = self.bursts.iter()
+            .filter(|(_, burst)| burst.is_settled(now))
+            .map(|(path, _)| path.clone()).collect();
+        let mut bursts: Vec<Burst> = paths.iter().filter_map(|p| self.bursts.remove(p)).collect();
+        bursts.sort_by_key(|burst| burst.first);
+        bursts
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Take every burst, used when the agent stops
+    pub fn drain(&mut self) -> Vec<Burst> {
+        let mut bursts: Vec<Burst> = self.bursts.drain().map(|(_, burst)| burst).collect();
+        bursts.sort_by_key(|burst| burst.first);
+        bursts
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_merges_operations() {
+        let mut coalescer = Coalescer::default();
+        let window = Duration::from_millis(100);
+        for operation in ["CREATE", "MODIFY", "MODIFY", "ACCESS"] {
+            coalescer.push(PathBuf::from("/tmp/a"), String::from("tmp"), String::from(operation), window);
+        }
+        coalescer.push(PathBuf::from("/tmp/b"), String::from("tmp"), String::from("MODIFY"), window);
+        assert_eq!(coalescer.bursts.len(), 2);
+
+        assert!(coalescer.settled(Instant::now()).is_empty());
+        let bursts = coalescer.settled(Instant::now() + window);
+        assert_eq!(bursts.len(), 2);
+        assert_eq!(bursts[0].path, PathBuf::from("/tmp/a"));
+        assert_eq!(bursts[0].operations, vec!["CREATE", "MODIFY", "ACCESS"]);
+        assert_eq!(bursts[0].get_operation(), "CREATE");
+        assert_eq!(bursts[1].get_operation(), "MODIFY");
+        assert_eq!(coalescer.bursts.len(), 0);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_settled_max_windows() {
+        let mut coalescer = Coalescer::default();
+        let window = Duration::from_millis(100);
+        coalescer.push(PathBuf::from("/tmp/a"), String::new(), String::from("MODIFY"), window);
+        // Still active bursts are emitted after the maximum duration
+        coalescer.bursts.get_mut(&PathBuf::from("/tmp/a")).unwrap().last += window * MAX_WINDOWS;
+        assert_eq!(coalescer.settled(Instant::now() + window * MAX_WINDOWS).len(), 1);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_drain() {
+        let mut coalescer = Coalescer::default();
+        coalescer.push(PathBuf::from("/tmp/a"), String::new(), String::from("ACCESS"),
+            Duration::from_secs(60));
+        let bursts = coalescer.drain();
+        assert_eq!(bursts.len(), 1);
+        assert_eq!(bursts[0].get_operation(), "ACCESS");
+        assert_eq!(coalescer.bursts.len(), 0);
+    }
+}
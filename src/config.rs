@@ -4,6 +4,8 @@
 // Global constants definitions
 pub const VERSION: &str = "0.1";
 const CONFIG_PATH: &str = "/etc/ifim/config.yml";
+// Lines of diffs hidden unless other redaction patterns are configured
+const DEFAULT_REDACT: &str = "(?i)(password|passwd|secret|token|api[_-]?key|private[_-]?key)";
 
 // To parse files in yaml format
 use yaml_rust::yaml::{Yaml, YamlLoader, Array};
@@ -17,6 +19,10 @@ use std::path::Path;
 use simplelog::LevelFilter;
 // To manage common functions
 use crate::utils;
+// To get default event severities
+use crate::format;
+// To get the default number of workers
+use crate::pipeline;
 use colored::Colorize;
 
 // ----------------------------------------------------------------------------
@@ -25,10 +31,52 @@ pub struct Config {
     pub version: String,
     pub path: String,
     pub events_file: String,
+    pub events_format: String,
+    pub syslog_address: String,
+    pub syslog_protocol: String,
+    pub syslog_format: String,
+    pub flush_interval: u64,
+    pub fsync: bool,
+    pub chain: bool,
+    pub signing_key: String,
+    pub signing_interval: u64,
+    pub rotation_size: u64,
+    pub rotation_interval: u64,
+    pub rotation_keep: usize,
+    pub rotation_compress: String,
+    pub rotation_external: bool,
+    pub spool_dir: String,
+    pub spool_max_size: u64,
+    pub spool_policy: String,
+    pub workers: usize,
+    pub queue_size: usize,
+    pub scan_interval: u64,
+    pub metrics_address: String,
+    pub database_path: String,
+    pub control_socket: String,
+    pub hooks_timeout: u64,
+    pub hooks_concurrency: usize,
+    pub detection_window: u64,
+    pub detection_modifications: usize,
+    pub detection_renames: usize,
+    pub detection_entropy: f64,
+    pub diff_dir: String,
+    pub diff_max_file_size: u64,
+    pub diff_max_size: usize,
+    pub diff_redact: Vec<String>,
+    pub vault_dir: String,
+    pub vault_max_file_size: u64,
+    pub vault_max_versions: usize,
+    pub vault_max_age: u64,
     pub monitor: Array,
+    pub maintenance: Array,
+    pub rules: Array,
     pub log_file: String,
     pub log_level: String,
-    pub system: String
+    pub system: String,
+    pub hostname: String,
+    pub agent_id: String,
+    pub tags: Vec<String>
 }
 
 impl Config {
@@ -38,10 +86,52 @@ impl Config {
             version: self.version.clone(),
             path: self.path.clone(),
             events_file: self.events_file.clone(),
+            events_format: self.events_format.clone(),
+            syslog_address: self.syslog_address.clone(),
+            syslog_protocol: self.syslog_protocol.clone(),
+            syslog_format: self.syslog_format.clone(),
+            flush_interval: self.flush_interval,
+            fsync: self.fsync,
+            chain: self.chain,
+            signing_key: self.signing_key.clone(),
+            signing_interval: self.signing_interval,
+            rotation_size: self.rotation_size,
+            rotation_interval: self.rotation_interval,
+            rotation_keep: self.rotation_keep,
+            rotation_compress: self.rotation_compress.clone(),
+            rotation_external: self.rotation_external,
+            spool_dir: self.spool_dir.clone(),
+            spool_max_size: self.spool_max_size,
+            spool_policy: self.spool_policy.clone(),
+            workers: self.workers,
+            queue_size: self.queue_size,
+            scan_interval: self.scan_interval,
+            metrics_address: self.metrics_address.clone(),
+            database_path: self.database_path.clone(),
+            control_socket: self.control_socket.clone(),
+            hooks_timeout: self.hooks_timeout,
+            hooks_concurrency: self.hooks_concurrency,
+            detection_window: self.detection_window,
+            detection_modifications: self.detection_modifications,
+            detection_renames: self.detection_renames,
+            detection_entropy: self.detection_entropy,
+            diff_dir: self.diff_dir.clone(),
+            diff_max_file_size: self.diff_max_file_size,
+            diff_max_size: self.diff_max_size,
+            diff_redact: self.diff_redact.clone(),
+            vault_dir: self.vault_dir.clone(),
+            vault_max_file_size: self.vault_max_file_size,
+            vault_max_versions: self.vault_max_versions,
+            vault_max_age: self.vault_max_age,
             monitor: self.monitor.clone(),
+            maintenance: self.maintenance.clone(),
+            rules: self.rules.clone(),
             log_file: self.log_file.clone(),
             log_level: self.log_level.clone(),
-            system: self.system.clone()
+            system: self.system.clone(),
+            hostname: self.hostname.clone(),
+            agent_id: self.agent_id.clone(),
+            tags: self.tags.clone()
         }
     }
 
@@ -50,6 +140,29 @@ impl Config {
         let config_path = get_config_path();
         println!("{}: {}", "Loaded config from".green(), config_path);
         let yaml = read_config(config_path.clone());
+        match Config::parse(&yaml, config_path, system) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("{}", e.red());
+                panic!("{}", e.red());
+            }
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Load the configuration file of a path, used on reloads where an invalid
+    // file is reported and the running configuration kept
+    pub fn load(path: &str, system: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let yaml = YamlLoader::load_from_str(&contents).map_err(|e| format!("{}: {}", path, e))?;
+        Config::parse(&yaml, String::from(path), system).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn parse(yaml: &[Yaml], config_path: String, system: &str) -> Result<Self, String> {
+        if yaml.is_empty() { return Err(String::from("empty configuration")) }
 
         // Manage null value on events->file value
         let events_file = match yaml[0]["events"]["file"].as_str() {
@@ -59,19 +172,166 @@ impl Config {
             }
         };
 
+        // Manage null value on events->format value
+        let events_format = match yaml[0]["events"]["format"].as_str() {
+            Some(value) => String::from(value),
+            None => String::from("json")
+        };
+
+        // Manage null value on events->syslog->address value, empty means disabled
+        let syslog_address = match yaml[0]["events"]["syslog"]["address"].as_str() {
+            Some(value) => String::from(value),
+            None => String::new()
+        };
+
+        // Manage null value on events->syslog->protocol value
+        let syslog_protocol = match yaml[0]["events"]["syslog"]["protocol"].as_str() {
+            Some(value) => String::from(value),
+            None => String::from("udp")
+        };
+
+        // Manage null value on events->syslog->format value, defaults to events->format
+        let syslog_format = match yaml[0]["events"]["syslog"]["format"].as_str() {
+            Some(value) => String::from(value),
+            None => events_format.clone()
+        };
+
+        // Manage null value on events->flush_interval value, in milliseconds
+        let flush_interval = yaml[0]["events"]["flush_interval"].as_i64().unwrap_or(1000).max(0) as u64;
+
+        // Manage null value on events->fsync value
+        let fsync = yaml[0]["events"]["fsync"].as_bool().unwrap_or(false);
+
+        // Manage null value on events->chain value
+        let chain = yaml[0]["events"]["chain"].as_bool().unwrap_or(false);
+
+        // Manage null value on events->signing->key value, empty disables checkpoints
+        let signing_key = match yaml[0]["events"]["signing"]["key"].as_str() {
+            Some(value) => String::from(value),
+            None => String::new()
+        };
+
+        // Manage null value on events->signing->interval value, in seconds
+        let signing_interval = yaml[0]["events"]["signing"]["interval"].as_i64().unwrap_or(300).max(1) as u64;
+
+        // Manage null values on events->rotation values, zero disables a limit
+        let rotation = &yaml[0]["events"]["rotation"];
+        let rotation_size = rotation["size"].as_i64().unwrap_or(0).max(0) as u64;
+        let rotation_interval = rotation["interval"].as_i64().unwrap_or(0).max(0) as u64;
+        let rotation_keep = rotation["keep"].as_i64().unwrap_or(5).max(1) as usize;
+        let rotation_compress = match rotation["compress"].as_str() {
+            Some(value) => String::from(value),
+            None => String::from("none")
+        };
+        // Set when an external tool like logrotate rotates the events file
+        let rotation_external = rotation["external"].as_bool().unwrap_or(false);
+
+        // Manage null values on spool values, undelivered network events
+        let spool = &yaml[0]["spool"];
+        let spool_dir = match spool["dir"].as_str() {
+            Some(value) => String::from(value),
+            None => String::from("/var/lib/ifim/spool")
+        };
+        let spool_max_size = spool["max_size"].as_i64().unwrap_or(104857600).max(1) as u64;
+        let spool_policy = match spool["policy"].as_str() {
+            Some(value) => String::from(value),
+            None => String::from("drop_oldest")
+        };
+
+        // Manage null values on pipeline->workers and pipeline->queue_size values
+        let workers = match yaml[0]["pipeline"]["workers"].as_i64() {
+            Some(value) => value.max(1) as usize,
+            None => pipeline::get_default_workers()
+        };
+        let queue_size = yaml[0]["pipeline"]["queue_size"].as_i64().unwrap_or(4096).max(1) as usize;
+
+        // Manage null value on scan_interval value, in seconds
+        let scan_interval = yaml[0]["scan_interval"].as_i64().unwrap_or(300).max(1) as u64;
+
+        // Manage null value on metrics->address value, empty disables the endpoint
+        let metrics_address = match yaml[0]["metrics"]["address"].as_str() {
+            Some(value) => String::from(value),
+            None => String::new()
+        };
+
+        // Manage null value on database->path value, empty disables the SQLite store
+        let database_path = match yaml[0]["database"]["path"].as_str() {
+            Some(value) => String::from(value),
+            None => String::new()
+        };
+
+        // Manage null value on control->socket value, empty puts it next to the status file
+        let control_socket = match yaml[0]["control"]["socket"].as_str() {
+            Some(value) => String::from(value),
+            None => String::new()
+        };
+
+        // Manage null values on hooks->timeout, in seconds, and hooks->concurrency values
+        let hooks_timeout = yaml[0]["hooks"]["timeout"].as_i64().unwrap_or(30).max(1) as u64;
+        let hooks_concurrency = yaml[0]["hooks"]["concurrency"].as_i64().unwrap_or(4).max(1) as usize;
+
+        // Manage null values on detection values, the window is in seconds and
+        // thresholds set to 0 are disabled, the entropy one is a share (0-1)
+        let detection_window = yaml[0]["detection"]["window"].as_i64().unwrap_or(60).max(1) as u64;
+        let detection_modifications = yaml[0]["detection"]["modifications"].as_i64().unwrap_or(0).max(0) as usize;
+        let detection_renames = yaml[0]["detection"]["renames"].as_i64().unwrap_or(0).max(0) as usize;
+        let detection_entropy = yaml[0]["detection"]["entropy"].as_f64().unwrap_or(0.0).clamp(0.0, 1.0);
+
+        // Manage null values on diff values, copies of the text files of monitor
+        // entries with diff enabled and the redaction patterns of their diffs
+        let diff = &yaml[0]["diff"];
+        let diff_dir = match diff["dir"].as_str() {
+            Some(value) => String::from(value),
+            None => String::from("/var/lib/ifim/diffs")
+        };
+        let diff_max_file_size = diff["max_file_size"].as_i64().unwrap_or(65536).max(1) as u64;
+        let diff_max_size = diff["max_size"].as_i64().unwrap_or(16384).max(1) as usize;
+        let diff_redact = match diff["redact"].as_vec() {
+            Some(value) => value.iter().filter_map(|p| p.as_str().map(String::from)).collect(),
+            None => vec![String::from(DEFAULT_REDACT)]
+        };
+
+        // Manage null values on vault values, stored versions of the files of
+        // monitor entries with vault enabled, max_age is in days and 0 keeps them
+        let vault = &yaml[0]["vault"];
+        let vault_dir = match vault["dir"].as_str() {
+            Some(value) => String::from(value),
+            None => String::from("/var/lib/ifim/vault")
+        };
+        let vault_max_file_size = vault["max_file_size"].as_i64().unwrap_or(10485760).max(1) as u64;
+        let vault_max_versions = vault["max_versions"].as_i64().unwrap_or(10).max(1) as usize;
+        let vault_max_age = vault["max_age"].as_i64().unwrap_or(30).max(0) as u64;
+
         // Manage null value on monitor value
         let monitor = match yaml[0]["monitor"].as_vec() {
             Some(value) => value.to_vec(),
             None => Vec::new()
         };
+        for (index, entry) in monitor.iter().enumerate() {
+            if entry["path"].as_str().is_none() {
+                return Err(format!("monitor entry {} has no path", index + 1))
+            }
+            if entry["ignore"].as_vec().is_some_and(|ignore| ignore.iter().any(|i| i.as_str().is_none())) {
+                return Err(format!("monitor entry {} has an ignore value that is not a string", index + 1))
+            }
+        }
+
+        // Manage null value on maintenance value
+        let maintenance = match yaml[0]["maintenance"].as_vec() {
+            Some(value) => value.to_vec(),
+            None => Vec::new()
+        };
+
+        // Manage null value on rules value
+        let rules = match yaml[0]["rules"].as_vec() {
+            Some(value) => value.to_vec(),
+            None => Vec::new()
+        };
 
         // Manage null value on log->file value
         let log_file = match yaml[0]["log"]["file"].as_str() {
             Some(value) => String::from(value),
-            None => {
-                println!("{}", "log->file not found in config.yml.".red());
-                panic!("{}", "log->file not found in config.yml.".red());
-            }
+            None => return Err(String::from("log->file not found in config.yml."))
         };
 
         // Manage null value on log->level value
@@ -83,20 +343,70 @@ impl Config {
             }
         };
 
-        Config {
+        // Manage null value on tags value
+        let tags = match yaml[0]["tags"].as_vec() {
+            Some(value) => value.iter().filter_map(|t| t.as_str().map(String::from)).collect(),
+            None => Vec::new()
+        };
+
+        Ok(Config {
             version: String::from(VERSION),
             path: config_path,
             events_file,
+            events_format,
+            syslog_address,
+            syslog_protocol,
+            syslog_format,
+            flush_interval,
+            fsync,
+            chain,
+            signing_key,
+            signing_interval,
+            rotation_size,
+            rotation_interval,
+            rotation_keep,
+            rotation_compress,
+            rotation_external,
+            spool_dir,
+            spool_max_size,
+            spool_policy,
+            workers,
+            queue_size,
+            scan_interval,
+            metrics_address,
+            database_path,
+            control_socket,
+            hooks_timeout,
+            hooks_concurrency,
+            detection_window,
+            detection_modifications,
+            detection_renames,
+            detection_entropy,
+            diff_dir,
+            diff_max_file_size,
+            diff_max_size,
+            diff_redact,
+            vault_dir,
+            vault_max_file_size,
+            vault_max_versions,
+            vault_max_age,
             monitor,
+            maintenance,
+            rules,
             log_file,
             log_level,
-            system: String::from(system)
-        }
+            system: String::from(system),
+            hostname: utils::get_hostname(),
+            // Set on startup once the events directory exists
+            agent_id: String::new(),
+            tags
+        })
     }
 
     // ------------------------------------------------------------------------
 
     // To process log level set on config file
+    #[allow(clippy::ineffective_open_options)]
     pub fn get_level_filter(&self) -> LevelFilter {
         let mut log = OpenOptions::new()
             .create(true)
@@ -147,6 +457,71 @@ impl Config {
 
     // ------------------------------------------------------------------------
 
+    // Get the coalescing window of a monitor entry in milliseconds, 0 disables it
+    pub fn get_coalesce(&self, index: usize) -> u64 {
+        self.monitor[index]["coalesce"].as_i64().unwrap_or(0).max(0) as u64
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Get the hook command run on every event of a monitor entry, if any
+    pub fn get_exec(&self, index: usize) -> Option<String> {
+        self.monitor.get(index)?["exec"].as_str().filter(|c| ! c.is_empty()).map(String::from)
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Whether the contents of the files of a monitor entry are analyzed
+    pub fn get_analyze(&self, index: usize) -> bool {
+        self.monitor.get(index).and_then(|entry| entry["analyze"].as_bool()).unwrap_or(false)
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Whether modifications of the text files of a monitor entry are diffed
+    pub fn get_diff(&self, index: usize) -> bool {
+        self.monitor.get(index).and_then(|entry| entry["diff"].as_bool()).unwrap_or(false)
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Whether the versions of the files of a monitor entry are stored in the vault
+    pub fn get_vault(&self, index: usize) -> bool {
+        self.monitor.get(index).and_then(|entry| entry["vault"].as_bool()).unwrap_or(false)
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Get the watcher backend of a monitor entry, native or poll
+    pub fn get_backend(&self, index: usize) -> String {
+        match self.monitor[index]["backend"].as_str() {
+            Some(value) => String::from(value),
+            None => String::from("native")
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Get the polling interval of a monitor entry in seconds
+    pub fn get_poll_interval(&self, index: usize) -> u64 {
+        self.monitor[index]["interval"].as_i64().unwrap_or(10).max(1) as u64
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Get event severity (0-10) from the monitor entry of a label or its operation
+    pub fn get_severity(&self, label: &str, operation: &str) -> u8 {
+        let severity = self.monitor.iter()
+            .find(|it| !label.is_empty() && it["label"].as_str() == Some(label))
+            .and_then(|it| it["severity"].as_i64());
+        match severity {
+            Some(value) => value.clamp(0, 10) as u8,
+            None => format::get_severity(operation)
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
     pub fn match_ignore(&self, index: usize, filename: &str, array: Array) -> bool {
         match array[index]["ignore"].as_vec() {
             Some(igv) => igv.to_vec().iter().any(|ignore| filename.contains(ignore.as_str().unwrap()) ),
@@ -190,6 +565,7 @@ pub fn read_config(path: String) -> Vec<Yaml> {
 
 // ----------------------------------------------------------------------------
 
+#[allow(clippy::useless_format)]
 pub fn get_config_path() -> String {
     // Select directory where to load config.yml it depends on system
     let default_path = format!("./config/config.yml");
@@ -207,23 +583,67 @@ pub fn get_config_path() -> String {
 
 // ----------------------------------------------------------------------------
 
+// Config with defaults used by the tests of every module
 #[cfg(test)]
-mod tests {
-    use super::*;
+pub fn create_test_config(filter: &str) -> Config {
+    Config {
+        version: String::from(VERSION),
+        path: String::from("test"),
+        events_file: String::from("test"),
+        events_format: String::from("json"),
+        syslog_address: String::new(),
+        syslog_protocol: String::from("udp"),
+        syslog_format: String::from("json"),
+        flush_interval: 0,
+        fsync: false,
+        chain: false,
+        signing_key: String::new(),
+        signing_interval: 300,
+        rotation_size: 0,
+        rotation_interval: 0,
+        rotation_keep: 5,
+        rotation_compress: String::from("none"),
+        rotation_external: false,
+        spool_dir: String::from("test_spool"),
+        spool_max_size: 1048576,
+        spool_policy: String::from("drop_oldest"),
+        workers: 1,
+        queue_size: 16,
+        scan_interval: 300,
+        metrics_address: String::new(),
+        database_path: String::new(),
+        control_socket: String::new(),
+        hooks_timeout: 30,
+        hooks_concurrency: 4,
+        detection_window: 60,
+        detection_modifications: 0,
+        detection_renames: 0,
+        detection_entropy: 0.0,
+        diff_dir: String::from("test_diffs"),
+        diff_max_file_size: 65536,
+        diff_max_size: 16384,
+        diff_redact: vec![String::from(DEFAULT_REDACT)],
+        vault_dir: String::from("test_vault"),
+        vault_max_file_size: 10485760,
+        vault_max_versions: 10,
+        vault_max_age: 30,
+        monitor: Array::new(),
+        maintenance: Array::new(),
+        rules: Array::new(),
+        log_file: std::env::temp_dir().join("ifim_test.log").display().to_string(),
+        log_level: String::from(filter),
+        system: String::from("test"),
+        hostname: String::from("test"),
+        agent_id: String::from("test_agent"),
+        tags: Vec::new()
+    }
+}
 
-    // ------------------------------------------------------------------------
+// ----------------------------------------------------------------------------
 
-    pub fn create_test_config(filter: &str) -> Config {
-        Config {
-            version: String::from(VERSION),
-            path: String::from("test"),
-            events_file: String::from("test"),
-            monitor: Array::new(),
-            log_file: String::from("./test.log"),
-            log_level: String::from(filter),
-            system: String::from("test")
-        }
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
     // ------------------------------------------------------------------------
 
@@ -234,10 +654,52 @@ mod tests {
         assert_eq!(config.version, cloned.version);
         assert_eq!(config.path, cloned.path);
         assert_eq!(config.events_file, cloned.events_file);
+        assert_eq!(config.events_format, cloned.events_format);
+        assert_eq!(config.syslog_address, cloned.syslog_address);
+        assert_eq!(config.syslog_protocol, cloned.syslog_protocol);
+        assert_eq!(config.syslog_format, cloned.syslog_format);
+        assert_eq!(config.flush_interval, cloned.flush_interval);
+        assert_eq!(config.fsync, cloned.fsync);
+        assert_eq!(config.chain, cloned.chain);
+        assert_eq!(config.signing_key, cloned.signing_key);
+        assert_eq!(config.signing_interval, cloned.signing_interval);
+        assert_eq!(config.rotation_size, cloned.rotation_size);
+        assert_eq!(config.rotation_interval, cloned.rotation_interval);
+        assert_eq!(config.rotation_keep, cloned.rotation_keep);
+        assert_eq!(config.rotation_compress, cloned.rotation_compress);
+        assert_eq!(config.rotation_external, cloned.rotation_external);
+        assert_eq!(config.spool_dir, cloned.spool_dir);
+        assert_eq!(config.spool_max_size, cloned.spool_max_size);
+        assert_eq!(config.spool_policy, cloned.spool_policy);
+        assert_eq!(config.workers, cloned.workers);
+        assert_eq!(config.queue_size, cloned.queue_size);
+        assert_eq!(config.scan_interval, cloned.scan_interval);
+        assert_eq!(config.metrics_address, cloned.metrics_address);
+        assert_eq!(config.database_path, cloned.database_path);
+        assert_eq!(config.control_socket, cloned.control_socket);
+        assert_eq!(config.hooks_timeout, cloned.hooks_timeout);
+        assert_eq!(config.hooks_concurrency, cloned.hooks_concurrency);
+        assert_eq!(config.detection_window, cloned.detection_window);
+        assert_eq!(config.detection_modifications, cloned.detection_modifications);
+        assert_eq!(config.detection_renames, cloned.detection_renames);
+        assert_eq!(config.detection_entropy, cloned.detection_entropy);
+        assert_eq!(config.diff_dir, cloned.diff_dir);
+        assert_eq!(config.diff_max_file_size, cloned.diff_max_file_size);
+        assert_eq!(config.diff_max_size, cloned.diff_max_size);
+        assert_eq!(config.diff_redact, cloned.diff_redact);
+        assert_eq!(config.vault_dir, cloned.vault_dir);
+        assert_eq!(config.vault_max_file_size, cloned.vault_max_file_size);
+        assert_eq!(config.vault_max_versions, cloned.vault_max_versions);
+        assert_eq!(config.vault_max_age, cloned.vault_max_age);
         assert_eq!(config.monitor, cloned.monitor);
+        assert_eq!(config.maintenance, cloned.maintenance);
+        assert_eq!(config.rules, cloned.rules);
         assert_eq!(config.log_file, cloned.log_file);
         assert_eq!(config.log_level, cloned.log_level);
         assert_eq!(config.system, cloned.system);
+        assert_eq!(config.hostname, cloned.hostname);
+        assert_eq!(config.agent_id, cloned.agent_id);
+        assert_eq!(config.tags, cloned.tags);
     }
 
     // ------------------------------------------------------------------------
@@ -247,10 +709,51 @@ mod tests {
         let config = Config::new("illumos");
         assert_eq!(config.version, String::from(VERSION));
         assert_eq!(config.events_file, String::from("/var/lib/ifim/events.json"));
+        assert_eq!(config.events_format, String::from("json"));
+        assert_eq!(config.syslog_address, String::new());
+        assert_eq!(config.flush_interval, 1000);
+        assert!(!config.fsync);
+        assert!(!config.chain);
+        assert_eq!(config.signing_key, String::new());
+        assert_eq!(config.signing_interval, 300);
+        assert_eq!(config.rotation_size, 104857600);
+        assert_eq!(config.rotation_interval, 86400);
+        assert_eq!(config.rotation_keep, 7);
+        assert_eq!(config.rotation_compress, String::from("gzip"));
+        assert!(!config.rotation_external);
+        assert_eq!(config.spool_dir, String::from("/var/lib/ifim/spool"));
+        assert_eq!(config.spool_max_size, 104857600);
+        assert_eq!(config.spool_policy, String::from("drop_oldest"));
+        assert_eq!(config.workers, 4);
+        assert_eq!(config.queue_size, 4096);
+        assert_eq!(config.scan_interval, 300);
+        assert_eq!(config.metrics_address, String::new());
+        assert_eq!(config.database_path, String::new());
+        assert_eq!(config.control_socket, String::new());
+        assert_eq!(config.hooks_timeout, 10);
+        assert_eq!(config.hooks_concurrency, 2);
+        assert_eq!(config.detection_window, 60);
+        assert_eq!(config.detection_modifications, 500);
+        assert_eq!(config.detection_renames, 200);
+        assert_eq!(config.detection_entropy, 0.8);
+        assert_eq!(config.diff_dir, String::from("/var/lib/ifim/diffs"));
+        assert_eq!(config.diff_max_file_size, 65536);
+        assert_eq!(config.diff_max_size, 16384);
+        assert_eq!(config.vault_dir, String::from("/var/lib/ifim/vault"));
+        assert_eq!(config.vault_max_file_size, 1048576);
+        assert_eq!(config.vault_max_versions, 20);
+        assert_eq!(config.vault_max_age, 90);
+        assert_eq!(config.diff_redact, vec![String::from(DEFAULT_REDACT), String::from("^\\s*[A-Za-z0-9_]*KEY=")]);
         // monitor
+        assert!(config.maintenance.is_empty());
+        assert_eq!(config.rules.len(), 1);
+        assert_eq!(config.rules[0]["name"].as_str(), Some("setuid binaries"));
         assert_eq!(config.log_file, String::from("/var/log/ifim/ifim.log"));
         assert_eq!(config.log_level, String::from("info"));
         assert_eq!(config.system, String::from("illumos"));
+        assert_eq!(config.hostname, utils::get_hostname());
+        assert_eq!(config.agent_id, String::new());
+        assert_eq!(config.tags, vec![String::from("production")]);
     }
 
     // ------------------------------------------------------------------------
@@ -361,6 +864,26 @@ mod tests {
 
     // ------------------------------------------------------------------------
 
+    #[test]
+    fn test_load() {
+        let config = Config::load("config/config.yml", "illumos").unwrap();
+        assert_eq!(config.path, String::from("config/config.yml"));
+        assert_eq!(config.log_file, String::from("/var/log/ifim/ifim.log"));
+        assert!(Config::load("NotFound", "illumos").is_err());
+        assert!(Config::load("README.md", "illumos").is_err());
+
+        let filename = "test_config_load.yml";
+        std::fs::write(filename, "monitor:\n  - path: /tmp\n").unwrap();
+        assert!(Config::load(filename, "illumos").err().unwrap().contains("log->file"));
+        std::fs::write(filename, "log:\n  file: /tmp/ifim.log\nmonitor:\n  - label: tmp\n").unwrap();
+        assert!(Config::load(filename, "illumos").err().unwrap().contains("has no path"));
+        std::fs::write(filename, "").unwrap();
+        assert!(Config::load(filename, "illumos").is_err());
+        std::fs::remove_file(filename).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
     #[test]
     fn test_get_config_path() {
         let default_path = "./config/config.yml";
@@ -393,6 +916,64 @@ mod tests {
 
     // ------------------------------------------------------------------------
 
+    #[test]
+    fn test_get_coalesce() {
+        let config = Config::new(&utils::get_os());
+        assert_eq!(config.get_coalesce(0), 0);
+        assert_eq!(config.get_coalesce(2), 500);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_get_backend() {
+        let mut config = create_test_config("info");
+        config.monitor = YamlLoader::load_from_str("- path: /mnt/nfs\n  backend: poll\n  interval: 30\n- path: /etc\n")
+            .unwrap()[0].as_vec().unwrap().to_vec();
+        assert_eq!(config.get_backend(0), "poll");
+        assert_eq!(config.get_poll_interval(0), 30);
+        assert_eq!(config.get_backend(1), "native");
+        assert_eq!(config.get_poll_interval(1), 10);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_get_exec() {
+        let mut config = create_test_config("info");
+        config.monitor = YamlLoader::load_from_str("- path: /etc\n  exec: /bin/true\n- path: /bin\n  exec: \"\"\n")
+            .unwrap()[0].as_vec().unwrap().to_vec();
+        assert_eq!(config.get_exec(0), Some(String::from("/bin/true")));
+        assert_eq!(config.get_exec(1), None);
+        assert_eq!(config.get_exec(usize::MAX), None);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_get_analyze() {
+        let config = Config::new(&utils::get_os());
+        assert!(! config.get_analyze(0));
+        assert!(config.get_analyze(1));
+        assert!(! config.get_analyze(usize::MAX));
+        assert!(config.get_diff(2));
+        assert!(! config.get_diff(0));
+        assert!(config.get_vault(2));
+        assert!(! config.get_vault(1));
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_get_severity() {
+        let config = Config::new(&utils::get_os());
+        assert_eq!(config.get_severity("bin", "MODIFY"), 8);
+        assert_eq!(config.get_severity("usr/bin", "REMOVE"), 7);
+        assert_eq!(config.get_severity("", "ACCESS"), 2);
+    }
+
+    // ------------------------------------------------------------------------
+
     #[test]
     fn test_match_ignore() {
         let config = Config::new(&utils::get_os());
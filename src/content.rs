@@ -0,0 +1,92 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To read the start of files
+use std::fs::File;
+use std::io::Read;
+
+// Global constants definitions
+pub const TYPE_ELF: &str = "elf";
+pub const TYPE_SCRIPT: &str = "script";
+pub const TYPE_ARCHIVE: &str = "archive";
+pub const TYPE_TEXT: &str = "text";
+pub const TYPE_DATA: &str = "data";
+pub const TYPE_EMPTY: &str = "empty";
+// Bytes read from the start of a file to recognize its type
+const TYPE_SAMPLE: u64 = 8192;
+// Magic numbers of compressed files and archives
+const ARCHIVE_MAGICS: [&[u8]; 7] = [
+    b"\x1f\x8b",                  // gzip
+    b"PK\x03\x04",                // zip, jar
+    b"BZh",                       // bzip2
+    b"\xfd7zXZ\x00",              // xz
+    b"\x28\xb5\x2f\xfd",          // zstd
+    b"7z\xbc\xaf\x27\x1c",        // 7-Zip
+    b"!<arch>\n"                  // ar, deb
+];
+
+// ----------------------------------------------------------------------------
+
+// Type of a file from the magic number at its start, None if it cannot be read
+pub fn get_file_type(file: &str) -> Option<&'static str> {
+    let mut data = Vec::new();
+    File::open(file).ok()?.take(TYPE_SAMPLE).read_to_end(&mut data).ok()?;
+    Some(get_type(&data))
+}
+
+// ----------------------------------------------------------------------------
+
+fn get_type(data: &[u8]) -> &'static str {
+    if data.is_empty() { return TYPE_EMPTY }
+    if data.starts_with(b"\x7fELF") { return TYPE_ELF }
+    if data.starts_with(b"#!") { return TYPE_SCRIPT }
+    if ARCHIVE_MAGICS.iter().any(|magic| data.starts_with(magic)) ||
+        data.get(257..262) == Some(b"ustar") { return TYPE_ARCHIVE }
+    if is_text(data) { TYPE_TEXT } else { TYPE_DATA }
+}
+
+// ----------------------------------------------------------------------------
+
+// UTF-8 without NUL bytes, a character may be cut at the end of the sample
+pub fn is_text(data: &[u8]) -> bool {
+    if data.contains(&0) { return false }
+    match std::str::from_utf8(data) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none()
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_get_type() {
+        assert_eq!(get_type(b""), TYPE_EMPTY);
+        assert_eq!(get_type(b"\x7fELF\x02\x01\x01\x00"), TYPE_ELF);
+        assert_eq!(get_type(b"#!/bin/sh\necho\n"), TYPE_SCRIPT);
+        assert_eq!(get_type(b"\x1f\x8b\x08\x00"), TYPE_ARCHIVE);
+        assert_eq!(get_type(b"PK\x03\x04\x14\x00"), TYPE_ARCHIVE);
+        let mut tar = vec![b'a'; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(get_type(&tar), TYPE_ARCHIVE);
+        assert_eq!(get_type("root:x:0:0:root:/root:/bin/bash\ncafé\n".as_bytes()), TYPE_TEXT);
+        // A multibyte character cut by the sample is still text
+        assert_eq!(get_type(&"café".as_bytes()[..4]), TYPE_TEXT);
+        assert_eq!(get_type(b"text\x00with nul"), TYPE_DATA);
+        assert_eq!(get_type(b"\xff\xfe\xfa"), TYPE_DATA);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_get_file_type() {
+        let filename = "test_get_file_type";
+        fs::write(filename, "#!/usr/bin/env python3\n").unwrap();
+        assert_eq!(get_file_type(filename), Some(TYPE_SCRIPT));
+        fs::remove_file(filename).unwrap();
+        assert_eq!(get_file_type(filename), None);
+    }
+}
@@ -0,0 +1,272 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To handle files
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+// To manage paths
+use std::path::{Path, PathBuf};
+// To talk to the running agent
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream as ClientStream;
+use std::time::Duration;
+// To serve the control socket
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+// To handle JSON objects
+use serde_json::{json, Value};
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+// To place the socket next to the status file
+use crate::status;
+
+// Global constants definitions
+const SOCKET_FILE: &str = "ifim.sock";
+const CHANNEL_SIZE: usize = 16;
+// Seconds the client waits for an answer, reloads record the files state again
+const CLIENT_TIMEOUT: u64 = 600;
+const USAGE: &str = "Usage: ifim ctl [status | pause <label> | resume <label> | rescan <path> | reload]";
+
+// ----------------------------------------------------------------------------
+
+// Control socket path, next to the status file unless configured
+pub fn get_path(config: &config::Config) -> PathBuf {
+    if ! config.control_socket.is_empty() { return PathBuf::from(&config.control_socket) }
+    status::get_path(config).with_file_name(SOCKET_FILE)
+}
+
+// ----------------------------------------------------------------------------
+
+// Commands accepted by the running agent
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Status,
+    Pause(String),
+    Resume(String),
+    Rescan(PathBuf),
+    Reload
+}
+
+// Parse a request, one JSON object such as {"command": "pause", "label": "etc"}
+pub fn parse(request: &Value) -> Result<Command, String> {
+    let argument = |name: &str| match request[name].as_str() {
+        Some(value) if ! value.is_empty() => Ok(String::from(value)),
+        _ => Err(format!("Missing '{}' argument", name))
+    };
+    match request["command"].as_str() {
+        Some("status") => Ok(Command::Status),
+        Some("pause") => Ok(Command::Pause(argument("label")?)),
+        Some("resume") => Ok(Command::Resume(argument("label")?)),
+        Some("rescan") => Ok(Command::Rescan(PathBuf::from(argument("path")?))),
+        Some("reload") => Ok(Command::Reload),
+        Some(command) => Err(format!("Unknown command '{}'", command)),
+        None => Err(String::from("Missing 'command'"))
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Successful answer with a message for the operator
+pub fn ok(message: String) -> Value {
+    json!({ "ok": true, "message": message })
+}
+
+// ----------------------------------------------------------------------------
+
+pub fn error(message: String) -> Value {
+    json!({ "ok": false, "error": message })
+}
+
+// ----------------------------------------------------------------------------
+
+// A command waiting to be run by the main loop
+pub struct Request {
+    pub command: Command,
+    reply: oneshot::Sender<Value>
+}
+
+impl Request {
+    pub fn reply(self, answer: Value) {
+        // The client may be gone already
+        let _ = self.reply.send(answer);
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Listen on the control socket, requests are handed to the main loop through
+// the returned channel, it is closed if the socket cannot be created
+pub fn spawn(config: &config::Config) -> mpsc::Receiver<Request> {
+    let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+    let path = get_path(config);
+    // A socket left by a previous run would make bind fail
+    if fs::symlink_metadata(&path).is_ok() { let _ = fs::remove_file(&path); }
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Control socket {} could not be created, Err: [{}]", path.display(), e);
+            return rx
+        }
+    };
+    // Only the agent user may control it
+    if let Err(e) = fs::set_permissions(&path, fs::Permissions::from_mode(0o600)) {
+        error!("Control socket {} permissions could not be set, Err: [{}]", path.display(), e);
+    }
+    info!("Control socket: {}", path.display());
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => { tokio::spawn(serve(stream, tx.clone())); },
+                Err(e) => error!("Control connection could not be accepted, Err: [{}]", e)
+            }
+        }
+    });
+    rx
+}
+
+// ----------------------------------------------------------------------------
+
+// Answer the requests of a connection, one JSON object per line
+async fn serve(stream: UnixStream, tx: mpsc::Sender<Request>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = AsyncBufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let command = serde_json::from_str(&line).map_err(|e| format!("Invalid request: {}", e))
+            .and_then(|request| parse(&request));
+        let answer = match command {
+            Ok(command) => {
+                debug!("Control command received: {:?}", command);
+                let (reply, answer) = oneshot::channel();
+                if tx.send(Request { command, reply }).await.is_err() { return }
+                answer.await.unwrap_or_else(|_| error(String::from("Agent is stopping")))
+            },
+            Err(e) => error(e)
+        };
+        if writer.write_all(format!("{}\n", answer).as_bytes()).await.is_err() { return }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Remove the socket when the agent stops
+pub fn remove(config: &config::Config) {
+    let _ = fs::remove_file(get_path(config));
+}
+
+// ----------------------------------------------------------------------------
+
+// Build the request of command line arguments
+fn get_request(args: &[String]) -> Result<Value, String> {
+    match args {
+        [command] if command == "status" || command == "reload" => Ok(json!({ "command": command })),
+        [command, label] if command == "pause" || command == "resume" =>
+            Ok(json!({ "command": command, "label": label })),
+        [command, path] if command == "rescan" => {
+            // Paths are relative to the client, not to the agent
+            let path = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+            Ok(json!({ "command": command, "path": path.display().to_string() }))
+        },
+        _ => Err(String::from(USAGE))
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Send a request to the running agent and wait for its answer
+pub fn send(path: &Path, request: &Value) -> std::io::Result<Value> {
+    let mut stream = ClientStream::connect(path)?;
+    stream.set_read_timeout(Some(Duration::from_secs(CLIENT_TIMEOUT)))?;
+    writeln!(stream, "{}", request)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+// ----------------------------------------------------------------------------
+
+// Command line entry point of `ifim ctl`
+pub fn ctl_command(config: &config::Config, args: &[String]) -> i32 {
+    let request = match get_request(args) {
+        Ok(request) => request,
+        Err(usage) => { eprintln!("{}", usage); return 2 }
+    };
+    let path = get_path(config);
+    let answer = match send(&path, &request) {
+        Ok(answer) => answer,
+        Err(e) => { eprintln!("Cannot reach the agent at {}, is it running? {}", path.display(), e); return 1 }
+    };
+    if answer["ok"] != true {
+        eprintln!("{}", answer["error"].as_str().unwrap_or("Unknown error"));
+        return 1
+    }
+    match answer["message"].as_str() {
+        Some(message) => println!("{}", message),
+        None => println!("{:#}", answer)
+    }
+    0
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(&json!({ "command": "status" })), Ok(Command::Status));
+        assert_eq!(parse(&json!({ "command": "pause", "label": "etc" })), Ok(Command::Pause(String::from("etc"))));
+        assert_eq!(parse(&json!({ "command": "rescan", "path": "/etc" })), Ok(Command::Rescan(PathBuf::from("/etc"))));
+        assert!(parse(&json!({ "command": "resume" })).is_err());
+        assert!(parse(&json!({ "command": "stop" })).is_err());
+        assert_eq!(get_request(&[String::from("pause"), String::from("etc")]).unwrap(),
+            json!({ "command": "pause", "label": "etc" }));
+        assert!(get_request(&[String::from("pause")]).is_err());
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_get_path() {
+        let mut config = create_test_config("info");
+        config.events_file = String::from("/var/lib/ifim/events.json");
+        assert_eq!(get_path(&config), PathBuf::from("/var/lib/ifim/ifim.sock"));
+        config.control_socket = String::from("/run/ifim.sock");
+        assert_eq!(get_path(&config), PathBuf::from("/run/ifim.sock"));
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn test_socket() {
+        let dir = "test_control";
+        fs::create_dir_all(dir).unwrap();
+        let mut config = create_test_config("info");
+        config.events_file = format!("{}/events.json", dir);
+        let mut rx = spawn(&config);
+        tokio::spawn(async move {
+            while let Some(request) = rx.recv().await {
+                let answer = match &request.command {
+                    Command::Pause(label) => ok(format!("Label {} paused", label)),
+                    _ => error(String::from("Unexpected"))
+                };
+                request.reply(answer);
+            }
+        });
+
+        let path = get_path(&config);
+        let answer = tokio::task::spawn_blocking(move || {
+            (send(&path, &json!({ "command": "pause", "label": "etc" })).unwrap(),
+                send(&path, &json!({ "command": "other" })).unwrap())
+        }).await.unwrap();
+        assert_eq!(answer.0, json!({ "ok": true, "message": "Label etc paused" }));
+        assert_eq!(answer.1["ok"], false);
+        remove(&config);
+        assert!(! get_path(&config).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
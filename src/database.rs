@@ -0,0 +1,440 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To create the database directory
+use std::fs;
+use std::path::Path;
+// To keep events that could not be stored yet
+use std::collections::VecDeque;
+// To manage date and time
+use std::time::Duration;
+// To count write errors
+use std::sync::atomic::Ordering;
+// To store events in SQLite
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, Transaction};
+use rusqlite::types::Value as SqlValue;
+// To communicate with the database task
+use tokio::sync::{mpsc, oneshot};
+// To handle JSON objects
+use serde_json::{json, Value};
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+// To count write errors
+use crate::metrics;
+
+// Global constants definitions
+const CHANNEL_SIZE: usize = 1024;
+// Events inserted in a single transaction
+const BATCH_SIZE: usize = 500;
+// Milliseconds between commits of queued events
+const COMMIT_INTERVAL: u64 = 1000;
+// Events kept while the database can not be written, the oldest are dropped
+const MAX_PENDING: usize = 100000;
+// Operations that change the state of a file
+const STATE_OPERATIONS: [&str; 3] = ["CREATE", "MODIFY", "REMOVE"];
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS events (
+        id TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        path TEXT,
+        operation TEXT NOT NULL,
+        label TEXT,
+        uid TEXT,
+        gid TEXT,
+        mode TEXT,
+        checksum TEXT,
+        severity INTEGER NOT NULL,
+        event TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS events_id ON events (id);
+    CREATE INDEX IF NOT EXISTS events_timestamp ON events (timestamp);
+    CREATE INDEX IF NOT EXISTS events_path ON events (path, timestamp);
+    CREATE INDEX IF NOT EXISTS events_operation ON events (operation, timestamp);
+    CREATE INDEX IF NOT EXISTS events_label ON events (label, timestamp);
+    CREATE TABLE IF NOT EXISTS files (
+        path TEXT PRIMARY KEY,
+        label TEXT,
+        operation TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        event_id TEXT NOT NULL,
+        checksum TEXT,
+        uid TEXT,
+        gid TEXT,
+        mode TEXT,
+        filesize TEXT,
+        removed INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX IF NOT EXISTS files_label ON files (label);";
+
+// ----------------------------------------------------------------------------
+
+pub enum Message {
+    // Event object and its severity
+    Event(Value, u8),
+    // Commit all queued events and answer once done
+    Flush(oneshot::Sender<()>)
+}
+
+// ----------------------------------------------------------------------------
+
+// Cloneable handle used to feed the database task
+#[derive(Clone)]
+pub struct Handle {
+    tx: mpsc::Sender<Message>
+}
+
+impl Handle {
+    // Queue an event to be stored, waits while the queue is full
+    pub async fn insert(&self, obj: &Value, severity: u8) {
+        if self.tx.send(Message::Event(obj.clone(), severity)).await.is_err() {
+            error!("Database output is not running, event dropped");
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Wait until every queued event has been committed
+    pub async fn flush(&self) {
+        let (tx, rx) = oneshot::channel();
+        if self.tx.send(Message::Flush(tx)).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+fn get_str<'a>(obj: &'a Value, key: &str) -> Option<&'a str> {
+    obj[key].as_str()
+}
+
+fn get_timestamp(obj: &Value) -> i64 {
+    get_str(obj, "timestamp").and_then(|t| t.parse().ok()).unwrap_or(0)
+}
+
+// Condition on the paths starting with a prefix, as a range so the path
+// index is used
+fn get_range(prefix: &str, sql: &mut String, values: &mut Vec<SqlValue>) {
+    if prefix.is_empty() { return }
+    sql.push_str(&format!(" AND path >= ?{} AND path < ?{}", values.len() + 1, values.len() + 2));
+    values.push(SqlValue::Text(String::from(prefix)));
+    values.push(SqlValue::Text(format!("{}\u{10FFFF}", prefix)));
+}
+
+// Condition on a column being one of the given values, none means any
+fn get_any(column: &str, list: &[String], sql: &mut String, values: &mut Vec<SqlValue>) {
+    if list.is_empty() { return }
+    let params: Vec<String> = (1..=list.len()).map(|index| format!("?{}", values.len() + index)).collect();
+    sql.push_str(&format!(" AND {} IN ({})", column, params.join(", ")));
+    values.extend(list.iter().map(|value| SqlValue::Text(value.clone())));
+}
+
+// ----------------------------------------------------------------------------
+
+// Events with their current state per path, events are kept as written and
+// their main fields are copied to indexed columns
+pub struct Database {
+    conn: Connection,
+    pending: VecDeque<(Value, u8)>,
+    dropped: u64,
+    failing: bool
+}
+
+impl Database {
+    // Open the database, creating it and its tables if needed
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        if let Some(dir) = Path::new(path).parent() {
+            if ! dir.as_os_str().is_empty() { let _ = fs::create_dir_all(dir); }
+        }
+        let conn = Connection::open(path)?;
+        // Readers like `ifim query` do not block the agent
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Database { conn, pending: VecDeque::new(), dropped: 0, failing: false })
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Open an existing database for queries
+    pub fn open_read(path: &str) -> rusqlite::Result<Self> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Ok(Database { conn, pending: VecDeque::new(), dropped: 0, failing: false })
+    }
+
+    // ------------------------------------------------------------------------
+
+    pub fn push(&mut self, obj: Value, severity: u8) {
+        if self.pending.len() >= MAX_PENDING {
+            self.pending.pop_front();
+            self.dropped += 1;
+        }
+        self.pending.push_back((obj, severity));
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Store queued events in a single transaction, they are kept and tried
+    // again on next commit if it fails
+    pub fn commit(&mut self) {
+        if self.pending.is_empty() { return }
+        let pending = &self.pending;
+        let result = self.conn.transaction().and_then(|tx| {
+            for (obj, severity) in pending {
+                insert(&tx, obj, *severity)?;
+                update(&tx, obj)?;
+            }
+            tx.commit()
+        });
+        match result {
+            Ok(_) => {
+                if self.failing {
+                    info!("Database writable again, {} events dropped meanwhile", self.dropped);
+                    self.failing = false;
+                    self.dropped = 0;
+                }
+                self.pending.clear();
+            },
+            Err(e) => {
+                metrics::DATABASE_ERRORS.fetch_add(1, Ordering::Relaxed);
+                if ! self.failing {
+                    error!("{} events could not be stored in the database, keeping them in memory, Err: [{}]",
+                        self.pending.len(), e);
+                    self.failing = true;
+                }
+            }
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Events in time order, of the paths starting with a prefix, within a time
+    // range and of the labels and operations when given
+    pub fn events(&self, prefix: &str, since: Option<u64>, until: Option<u64>, labels: &[String],
+        operations: &[String]) -> rusqlite::Result<Vec<Value>> {
+        let since = since.unwrap_or(0).min(i64::MAX as u64) as i64;
+        let until = until.unwrap_or(i64::MAX as u64).min(i64::MAX as u64) as i64;
+        let mut sql = String::from("SELECT event FROM events WHERE timestamp >= ?1 AND timestamp <= ?2");
+        let mut values = vec![SqlValue::Integer(since), SqlValue::Integer(until)];
+        get_range(prefix, &mut sql, &mut values);
+        get_any("label", labels, &mut sql, &mut values);
+        get_any("operation", operations, &mut sql, &mut values);
+        sql.push_str(" ORDER BY timestamp, rowid");
+        let mut statement = self.conn.prepare(&sql)?;
+        let rows = statement.query_map(params_from_iter(values), |row| row.get::<_, String>(0))?;
+        Ok(rows.filter_map(|row| row.ok()).filter_map(|event| serde_json::from_str(&event).ok()).collect())
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Current state of the paths starting with a prefix, of the labels and last
+    // operations when given, as event like objects
+    pub fn files(&self, prefix: &str, labels: &[String], operations: &[String]) -> rusqlite::Result<Vec<Value>> {
+        let mut sql = String::from("SELECT path, label, operation, timestamp, event_id, checksum, uid, gid, mode,
+            filesize, removed FROM files WHERE 1");
+        let mut values = Vec::new();
+        get_range(prefix, &mut sql, &mut values);
+        get_any("label", labels, &mut sql, &mut values);
+        get_any("operation", operations, &mut sql, &mut values);
+        sql.push_str(" ORDER BY path");
+        let mut statement = self.conn.prepare(&sql)?;
+        let rows = statement.query_map(params_from_iter(values), |row| Ok(json!({
+            "path": row.get::<_, String>(0)?,
+            "label": row.get::<_, Option<String>>(1)?,
+            "operation": row.get::<_, String>(2)?,
+            "timestamp": row.get::<_, i64>(3)?.to_string(),
+            "id": row.get::<_, String>(4)?,
+            "checksum": row.get::<_, Option<String>>(5)?,
+            "uid": row.get::<_, Option<String>>(6)?,
+            "gid": row.get::<_, Option<String>>(7)?,
+            "mode": row.get::<_, Option<String>>(8)?,
+            "filesize": row.get::<_, Option<String>>(9)?,
+            "removed": row.get::<_, bool>(10)?
+        })))?;
+        rows.collect()
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+fn insert(tx: &Transaction, obj: &Value, severity: u8) -> rusqlite::Result<usize> {
+    tx.execute("INSERT INTO events (id, timestamp, path, operation, label, uid, gid, mode, checksum, severity, event)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)", params![
+        get_str(obj, "id").unwrap_or(""), get_timestamp(obj), get_str(obj, "path"),
+        get_str(obj, "operation").unwrap_or(""), get_str(obj, "label"), get_str(obj, "uid"), get_str(obj, "gid"),
+        get_str(obj, "mode"), get_str(obj, "checksum"), severity, obj.to_string()
+    ])
+}
+
+// ----------------------------------------------------------------------------
+
+// Track the state of a file, removed files keep their last known attributes
+fn update(tx: &Transaction, obj: &Value) -> rusqlite::Result<usize> {
+    let (path, operation) = match (get_str(obj, "path"), get_str(obj, "operation")) {
+        (Some(path), Some(operation)) if STATE_OPERATIONS.contains(&operation) => (path, operation),
+        _ => return Ok(0)
+    };
+    if operation == "REMOVE" || obj.get("checksum").is_none() {
+        tx.execute("INSERT INTO files (path, label, operation, timestamp, event_id, removed)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6) ON CONFLICT (path) DO UPDATE SET label = excluded.label,
+            operation = excluded.operation, timestamp = excluded.timestamp, event_id = excluded.event_id,
+            removed = excluded.removed", params![
+            path, get_str(obj, "label"), operation, get_timestamp(obj), get_str(obj, "id").unwrap_or(""),
+            operation == "REMOVE"
+        ])
+    } else {
+        tx.execute("INSERT OR REPLACE INTO files (path, label, operation, timestamp, event_id, checksum, uid, gid,
+            mode, filesize, removed) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, 0)", params![
+            path, get_str(obj, "label"), operation, get_timestamp(obj), get_str(obj, "id").unwrap_or(""),
+            get_str(obj, "checksum"), get_str(obj, "uid"), get_str(obj, "gid"), get_str(obj, "mode"),
+            get_str(obj, "filesize")
+        ])
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Start the database task when a database is configured
+pub fn spawn(config: &config::Config) -> Option<Handle> {
+    if config.database_path.is_empty() { return None }
+    let database = match Database::open(&config.database_path) {
+        Ok(database) => database,
+        Err(e) => {
+            error!("Database {} could not be opened, Err: [{}]", config.database_path, e);
+            return None
+        }
+    };
+    let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+    tokio::spawn(run(database, rx));
+    Some(Handle { tx })
+}
+
+// ----------------------------------------------------------------------------
+
+// Commits block, they run out of the async workers
+async fn run(mut database: Database, mut rx: mpsc::Receiver<Message>) {
+    let mut interval = tokio::time::interval(Duration::from_millis(COMMIT_INTERVAL));
+    let commit = |mut database: Database| async move {
+        match tokio::task::spawn_blocking(move || { database.commit(); database }).await {
+            Ok(database) => Some(database),
+            Err(e) => { error!("Database output failed, Err: [{}]", e); None }
+        }
+    };
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(Message::Event(obj, severity)) => {
+                    database.push(obj, severity);
+                    // While failing, commits are only tried again on ticks
+                    if database.pending.len() >= BATCH_SIZE && ! database.failing {
+                        database = match commit(database).await { Some(database) => database, None => return };
+                    }
+                },
+                Some(Message::Flush(done)) => {
+                    database = match commit(database).await { Some(database) => database, None => return };
+                    let _ = done.send(());
+                },
+                None => break
+            },
+            _ = interval.tick() => {
+                database = match commit(database).await { Some(database) => database, None => return };
+            }
+        }
+    }
+    database.commit();
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+
+    fn create_test_event(id: &str, path: &str, operation: &str, timestamp: &str) -> Value {
+        let mut obj = json!({ "id": id, "path": path, "operation": operation, "timestamp": timestamp,
+            "label": "etc" });
+        if operation != "REMOVE" {
+            obj["checksum"] = json!(format!("sum-{}", id));
+            obj["uid"] = json!("0");
+            obj["mode"] = json!("100644");
+        }
+        obj
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_commit_and_select() {
+        let path = "test_database_commit/events.db";
+        let mut database = Database::open(path).unwrap();
+        database.push(create_test_event("e1", "/etc/hosts", "CREATE", "1000"), 5);
+        database.push(create_test_event("e2", "/etc/hosts", "MODIFY", "2000"), 5);
+        database.push(create_test_event("e3", "/etc/passwd", "CREATE", "3000"), 5);
+        database.push(create_test_event("e4", "/etc/hosts", "REMOVE", "4000"), 7);
+        database.push(json!({ "id": "e5", "operation": "AGENT_STOP", "timestamp": "5000" }), 6);
+        database.commit();
+        assert!(database.pending.is_empty());
+
+        let database = Database::open_read(path).unwrap();
+        let ids = |events: Vec<Value>| events.iter().map(|e| String::from(e["id"].as_str().unwrap())).collect::<Vec<_>>();
+        let none = Vec::new();
+        assert_eq!(ids(database.events("", None, None, &none, &none).unwrap()), vec!["e1", "e2", "e3", "e4", "e5"]);
+        assert_eq!(ids(database.events("/etc/hosts", Some(1500), None, &none, &none).unwrap()), vec!["e2", "e4"]);
+        assert_eq!(ids(database.events("", None, Some(2000), &none, &none).unwrap()), vec!["e1", "e2"]);
+        let operations = vec![String::from("CREATE"), String::from("REMOVE")];
+        assert_eq!(ids(database.events("", None, None, &none, &operations).unwrap()), vec!["e1", "e3", "e4"]);
+        assert_eq!(ids(database.events("", None, None, &[String::from("etc")], &none).unwrap()).len(), 4);
+
+        // Removed files keep their last known state
+        assert_eq!(database.files("", &none, &operations).unwrap().len(), 2);
+        assert!(database.files("", &[String::from("usr")], &none).unwrap().is_empty());
+        let files = database.files("/etc/", &none, &none).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0]["path"], "/etc/hosts");
+        assert_eq!(files[0]["operation"], "REMOVE");
+        assert_eq!(files[0]["id"], "e4");
+        assert_eq!(files[0]["checksum"], "sum-e2");
+        assert_eq!(files[0]["removed"], true);
+        assert_eq!(files[1]["checksum"], "sum-e3");
+        assert_eq!(files[1]["removed"], false);
+        fs::remove_dir_all("test_database_commit").unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_commit_failure() {
+        let dir = "test_database_failure";
+        let path = format!("{}/events.db", dir);
+        let mut database = Database::open(&path).unwrap();
+        database.conn.execute_batch("DROP TABLE files").unwrap();
+        database.push(create_test_event("e1", "/etc/hosts", "CREATE", "1000"), 5);
+        database.commit();
+        assert!(database.failing);
+        assert_eq!(database.pending.len(), 1);
+        // Events are kept until the database can be written again
+        database.conn.execute_batch(SCHEMA).unwrap();
+        database.commit();
+        assert!(! database.failing);
+        assert!(database.pending.is_empty());
+        assert_eq!(database.files("", &[], &[]).unwrap()[0]["id"], "e1");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn test_spawn() {
+        let mut config = create_test_config("info");
+        assert!(spawn(&config).is_none());
+        config.database_path = String::from("test_database_spawn/events.db");
+        let handle = spawn(&config).unwrap();
+        handle.insert(&create_test_event("e1", "/etc/hosts", "CREATE", "1000"), 5).await;
+        handle.flush().await;
+        let database = Database::open_read(&config.database_path).unwrap();
+        assert_eq!(database.files("", &[], &[]).unwrap()[0]["checksum"], "sum-e1");
+        fs::remove_dir_all("test_database_spawn").unwrap();
+    }
+}
@@ -0,0 +1,298 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To keep the recent activity of labels
+use std::collections::{HashMap, VecDeque};
+// To share the detector between the filter and the workers
+use std::sync::{Arc, Mutex, MutexGuard};
+// To manage date and time
+use std::time::{Duration, Instant};
+// To recognize renames
+use notify::event::{EventKind, ModifyKind, RenameMode};
+// To handle JSON objects
+use serde_json::{json, Value};
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+// To add agent metadata to alerts
+use crate::entry;
+// To generate alert ids
+use crate::utils;
+
+// Global constants definitions
+pub const OPERATION: &str = "MASS_MODIFICATION";
+// Bits per byte from which a written file counts as encrypted or compressed
+const HIGH_ENTROPY: f64 = 7.5;
+// Writes needed in the window before their entropy share is considered
+const MIN_WRITES: usize = 20;
+// Paths listed in an alert
+const MAX_PATHS: usize = 20;
+
+// ----------------------------------------------------------------------------
+
+// Detector shared by the filter, which sees renames, and the workers, which
+// measure the entropy of written files
+pub type Shared = Arc<Mutex<Detector>>;
+
+// A worker panicking while holding the detector does not stop detection
+pub fn lock(detector: &Shared) -> MutexGuard<'_, Detector> {
+    detector.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Whether a watcher event is the source side of a rename, inotify follows it
+// with a Both event of the same rename that is not counted again
+pub fn is_rename(kind: &EventKind) -> bool {
+    matches!(kind, EventKind::Modify(ModifyKind::Name(RenameMode::From | RenameMode::Any)))
+}
+
+// ----------------------------------------------------------------------------
+
+// Changes of a label within the window
+#[derive(Default)]
+struct Activity {
+    modifications: VecDeque<(Instant, String)>,
+    renames: VecDeque<(Instant, String)>,
+    // Written files and whether their contents have a high entropy
+    writes: VecDeque<(Instant, String, bool)>,
+    // Set once an alert is raised, until the activity falls below the thresholds
+    alerted: bool
+}
+
+impl Activity {
+    fn expire(&mut self, oldest: Instant) {
+        while self.modifications.front().is_some_and(|(time, _)| *time < oldest) { self.modifications.pop_front(); }
+        while self.renames.front().is_some_and(|(time, _)| *time < oldest) { self.renames.pop_front(); }
+        while self.writes.front().is_some_and(|(time, _, _)| *time < oldest) { self.writes.pop_front(); }
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn high_entropy(&self) -> usize {
+        self.writes.iter().filter(|(_, _, high)| *high).count()
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn is_empty(&self) -> bool {
+        self.modifications.is_empty() && self.renames.is_empty() && self.writes.is_empty()
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Sliding window of the changes of each label, thresholds set to 0 are disabled
+#[derive(Default)]
+pub struct Detector {
+    window: Duration,
+    modifications: usize,
+    renames: usize,
+    entropy: f64,
+    labels: HashMap<String, Activity>,
+    // Alerts waiting to be sent by the main loop
+    alerts: Vec<Value>
+}
+
+impl Detector {
+    pub fn new(config: &config::Config) -> Self {
+        Detector {
+            window: Duration::from_secs(config.detection_window.max(1)),
+            modifications: config.detection_modifications,
+            renames: config.detection_renames,
+            entropy: config.detection_entropy,
+            ..Detector::default()
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    pub fn shared(config: &config::Config) -> Shared {
+        Arc::new(Mutex::new(Detector::new(config)))
+    }
+
+    // ------------------------------------------------------------------------
+
+    pub fn is_enabled(&self) -> bool {
+        self.modifications > 0 || self.renames > 0 || self.entropy > 0.0
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Whether the workers have to measure the entropy of written files
+    pub fn needs_entropy(&self) -> bool {
+        self.entropy > 0.0
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Record a file renamed away by a watcher event
+    pub fn rename(&mut self, label: &str, path: &str, now: Instant) {
+        if ! self.is_enabled() { return }
+        self.labels.entry(String::from(label)).or_default().renames.push_back((now, String::from(path)));
+        self.check(label, now);
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Record a processed change, with the entropy of the file when measured
+    pub fn write(&mut self, label: &str, path: &str, operation: &str, entropy: Option<f64>, now: Instant) {
+        if ! self.is_enabled() || (operation != "CREATE" && operation != "MODIFY") { return }
+        let activity = self.labels.entry(String::from(label)).or_default();
+        if operation == "MODIFY" { activity.modifications.push_back((now, String::from(path))); }
+        if let Some(entropy) = entropy { activity.writes.push_back((now, String::from(path), entropy >= HIGH_ENTROPY)); }
+        self.check(label, now);
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Raise a single alert when the activity of a label crosses a threshold
+    fn check(&mut self, label: &str, now: Instant) {
+        let oldest = now.checked_sub(self.window).unwrap_or(now);
+        let activity = match self.labels.get_mut(label) {
+            Some(activity) => activity,
+            None => return
+        };
+        activity.expire(oldest);
+        let high_entropy = activity.high_entropy();
+        let share = if activity.writes.is_empty() { 0.0 }
+            else { high_entropy as f64 / activity.writes.len() as f64 };
+        let mut reasons = Vec::new();
+        if self.modifications > 0 && activity.modifications.len() >= self.modifications { reasons.push("modifications"); }
+        if self.renames > 0 && activity.renames.len() >= self.renames { reasons.push("renames"); }
+        if self.entropy > 0.0 && activity.writes.len() >= MIN_WRITES && share >= self.entropy { reasons.push("entropy"); }
+
+        if reasons.is_empty() {
+            activity.alerted = false;
+            return
+        }
+        if activity.alerted { return }
+        activity.alerted = true;
+        // Latest paths first, high entropy writes then modifications and renames
+        let written = activity.writes.iter().filter(|(_, _, high)| *high).map(|(_, path, _)| path);
+        let mut paths: Vec<&String> = activity.renames.iter().chain(activity.modifications.iter())
+            .map(|(_, path)| path).chain(written).rev().collect();
+        paths.dedup();
+        paths.truncate(MAX_PATHS);
+        warn!("Mass modification of label '{}' detected: {}", label, reasons.join(", "));
+        self.alerts.push(json!({
+            "id": utils::get_uuid(),
+            "operation": OPERATION,
+            "timestamp": entry::get_timestamp(),
+            "label": label,
+            "reasons": reasons,
+            "window": self.window.as_secs(),
+            "modifications": activity.modifications.len(),
+            "renames": activity.renames.len(),
+            "writes": activity.writes.len(),
+            "high_entropy": high_entropy,
+            "paths": paths
+        }));
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Alerts raised since the last call, with the agent metadata
+    pub fn alerts(&mut self, config: &config::Config) -> Vec<Value> {
+        // Labels without recent activity are forgotten
+        if let Some(oldest) = Instant::now().checked_sub(self.window) {
+            self.labels.retain(|_, activity| { activity.expire(oldest); ! activity.is_empty() });
+        }
+        let mut alerts = std::mem::take(&mut self.alerts);
+        for alert in alerts.iter_mut() { entry::add_metadata(alert, config); }
+        alerts
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+
+    fn create_test_detector(modifications: usize, renames: usize, entropy: f64) -> Detector {
+        let mut config = create_test_config("info");
+        config.detection_window = 10;
+        config.detection_modifications = modifications;
+        config.detection_renames = renames;
+        config.detection_entropy = entropy;
+        Detector::new(&config)
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_is_rename() {
+        assert!(is_rename(&EventKind::Modify(ModifyKind::Name(RenameMode::From))));
+        assert!(! is_rename(&EventKind::Modify(ModifyKind::Name(RenameMode::Both))));
+        assert!(! is_rename(&EventKind::Modify(ModifyKind::Name(RenameMode::To))));
+        assert!(! is_rename(&EventKind::Modify(ModifyKind::Any)));
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_disabled() {
+        let mut detector = create_test_detector(0, 0, 0.0);
+        assert!(! detector.is_enabled());
+        detector.write("etc", "/etc/passwd", "MODIFY", Some(8.0), Instant::now());
+        assert!(detector.labels.is_empty());
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_modifications() {
+        let config = create_test_config("info");
+        let mut detector = create_test_detector(3, 0, 0.0);
+        let now = Instant::now();
+        detector.write("etc", "/etc/a", "MODIFY", None, now);
+        detector.write("etc", "/etc/b", "CREATE", None, now);
+        detector.write("bin", "/bin/c", "MODIFY", None, now);
+        detector.write("etc", "/etc/d", "MODIFY", None, now);
+        assert!(detector.alerts(&config).is_empty());
+
+        // A single alert while the rate stays over the threshold
+        for path in ["/etc/e", "/etc/f", "/etc/g"] { detector.write("etc", path, "MODIFY", None, now); }
+        let alerts = detector.alerts(&config);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0]["operation"], OPERATION);
+        assert_eq!(alerts[0]["label"], "etc");
+        assert_eq!(alerts[0]["reasons"], json!(["modifications"]));
+        assert_eq!(alerts[0]["modifications"], 3);
+        assert_eq!(alerts[0]["paths"], json!(["/etc/e", "/etc/d", "/etc/a"]));
+        assert_eq!(alerts[0]["host"]["name"], "test");
+
+        // Old changes leave the window and the detector rearms
+        let later = now + Duration::from_secs(11);
+        detector.write("etc", "/etc/h", "MODIFY", None, later);
+        assert!(! detector.labels["etc"].alerted);
+        for path in ["/etc/i", "/etc/j"] { detector.write("etc", path, "MODIFY", None, later); }
+        assert_eq!(detector.alerts(&config).len(), 1);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_renames_and_entropy() {
+        let config = create_test_config("info");
+        let mut detector = create_test_detector(0, 2, 0.0);
+        let now = Instant::now();
+        detector.rename("data", "/data/a", now);
+        detector.rename("data", "/data/b", now);
+        assert_eq!(detector.alerts(&config)[0]["reasons"], json!(["renames"]));
+
+        let mut detector = create_test_detector(0, 0, 0.5);
+        assert!(detector.needs_entropy());
+        for index in 0..MIN_WRITES {
+            let entropy = if index % 2 == 0 { 7.9 } else { 4.0 };
+            detector.write("data", &format!("/data/{}", index), "CREATE", Some(entropy), now);
+        }
+        let alerts = detector.alerts(&config);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0]["reasons"], json!(["entropy"]));
+        assert_eq!(alerts[0]["writes"], MIN_WRITES);
+        assert_eq!(alerts[0]["high_entropy"], MIN_WRITES / 2);
+        assert_eq!(alerts[0]["paths"][0], format!("/data/{}", MIN_WRITES - 2));
+    }
+}
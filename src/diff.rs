@@ -0,0 +1,243 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To handle files
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+// To manage paths
+use std::path::{Path, PathBuf};
+// To keep compressed copies
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+// To name copies after the path of their file
+use sha3::{Digest, Sha3_256};
+// To build unified diffs
+use similar::TextDiff;
+// To redact secret lines
+use regex::Regex;
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+// To recognize text files
+use crate::content;
+
+// Global constants definitions
+const REDACTED: &str = "[REDACTED]";
+const CONTEXT_LINES: usize = 3;
+
+// ----------------------------------------------------------------------------
+
+// Unified diff of a modified file and whether it was cut at the size limit
+#[derive(Debug, PartialEq, Eq)]
+pub struct Diff {
+    pub text: String,
+    pub truncated: bool
+}
+
+// ----------------------------------------------------------------------------
+
+// Compressed copies of the small text files of monitor entries with diff
+// enabled, kept to diff them on their next modification
+#[derive(Clone)]
+pub struct Store {
+    dir: PathBuf,
+    max_file_size: u64,
+    max_size: usize,
+    redact: Vec<Regex>
+}
+
+impl Store {
+    pub fn new(config: &config::Config) -> Self {
+        let redact = config.diff_redact.iter().filter_map(|pattern| match Regex::new(pattern) {
+            Ok(regex) => Some(regex),
+            Err(e) => { error!("Diff redaction pattern ignored, Err: [{}]", e); None }
+        }).collect();
+        Store {
+            dir: PathBuf::from(&config.diff_dir),
+            max_file_size: config.diff_max_file_size,
+            max_size: config.diff_max_size,
+            redact
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn get_copy(&self, path: &str) -> PathBuf {
+        self.dir.join(format!("{}.gz", hex::encode(Sha3_256::digest(path.as_bytes()))))
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Contents of a file if it is a small text file
+    fn read_text(&self, path: &str) -> Option<String> {
+        if fs::metadata(path).ok()?.len() > self.max_file_size { return None }
+        let data = fs::read(path).ok()?;
+        if ! content::is_text(&data) { return None }
+        String::from_utf8(data).ok()
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn read_copy(&self, path: &str) -> Option<String> {
+        let mut text = String::new();
+        GzDecoder::new(fs::File::open(self.get_copy(path)).ok()?).read_to_string(&mut text).ok()?;
+        Some(text)
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Replace the copy of a file, only the agent user can read it as it may
+    // hold secrets
+    fn write_copy(&self, path: &str, text: &str) {
+        let result = (|| {
+            fs::create_dir_all(&self.dir)?;
+            fs::set_permissions(&self.dir, fs::Permissions::from_mode(0o700))?;
+            let copy = self.get_copy(path);
+            let tmp = copy.with_extension("tmp");
+            let file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp)?;
+            let mut encoder = GzEncoder::new(file, Compression::default());
+            encoder.write_all(text.as_bytes())?;
+            encoder.finish()?;
+            fs::rename(tmp, copy)
+        })();
+        if let Err(e) = result { error!("Copy of {} could not be saved, Err: [{}]", path, e); }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Forget the copy of a removed file, or of one no longer small or text
+    pub fn remove(&self, path: &str) {
+        let _ = fs::remove_file(self.get_copy(path));
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Keep a copy of a file seen when the agent starts, unless it has one
+    // already so changes made while it was stopped are diffed
+    pub fn baseline(&self, path: &Path) {
+        let path = path.display().to_string();
+        if self.get_copy(&path).exists() { return }
+        if let Some(text) = self.read_text(&path) { self.write_copy(&path, &text); }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Update the copy of a changed file, returns the diff with its last copy
+    // for modifications
+    pub fn update(&self, path: &str, operation: &str) -> Option<Diff> {
+        let text = match self.read_text(path) {
+            Some(text) => text,
+            None => { self.remove(path); return None }
+        };
+        let last = self.read_copy(path);
+        if last.as_deref() == Some(&text) { return None }
+        self.write_copy(path, &text);
+        if operation != "MODIFY" { return None }
+        Some(self.get_diff(path, &last?, &text))
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn get_diff(&self, path: &str, last: &str, text: &str) -> Diff {
+        let diff = TextDiff::from_lines(last, text).unified_diff().context_radius(CONTEXT_LINES)
+            .header(path, path).to_string();
+        let mut text = String::new();
+        let mut truncated = false;
+        for line in diff.split_inclusive('\n') {
+            let line = self.redact_line(line);
+            if text.len() + line.len() > self.max_size {
+                truncated = true;
+                break
+            }
+            text.push_str(&line);
+        }
+        Diff { text, truncated }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Hide the contents of changed or context lines matching a redaction pattern
+    fn redact_line(&self, line: &str) -> String {
+        if line.starts_with("---") || line.starts_with("+++") || line.starts_with("@@") { return String::from(line) }
+        let (prefix, contents) = line.split_at(line.len().min(1));
+        if self.redact.iter().any(|regex| regex.is_match(contents)) {
+            let end = if contents.ends_with('\n') { "\n" } else { "" };
+            format!("{}{}{}", prefix, REDACTED, end)
+        } else { String::from(line) }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+
+    fn create_test_store(dir: &str) -> Store {
+        let mut config = create_test_config("info");
+        config.diff_dir = format!("{}/diffs", dir);
+        config.diff_max_file_size = 1024;
+        config.diff_max_size = 512;
+        Store::new(&config)
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_update() {
+        let dir = "test_diff_update";
+        fs::create_dir_all(dir).unwrap();
+        let store = create_test_store(dir);
+        let path = format!("{}/app.conf", dir);
+        fs::write(&path, "port = 80\nhost = a\npassword = old\n").unwrap();
+        assert_eq!(store.update(&path, "CREATE"), None);
+        let copy = store.get_copy(&path);
+        assert_eq!(fs::metadata(&copy).unwrap().permissions().mode() & 0o777, 0o600);
+
+        fs::write(&path, "port = 8080\nhost = a\npassword = new\n").unwrap();
+        let diff = store.update(&path, "MODIFY").unwrap();
+        assert!(! diff.truncated);
+        assert_eq!(diff.text, format!("--- {}\n+++ {}\n@@ -1,3 +1,3 @@\n-port = 80\n+port = 8080\n host = a\n\
+            -[REDACTED]\n+[REDACTED]\n", path, path));
+        // Unchanged contents give no diff
+        assert_eq!(store.update(&path, "MODIFY"), None);
+
+        // Binary files are not kept
+        fs::write(&path, b"\x7fELF\x00\x01").unwrap();
+        assert_eq!(store.update(&path, "MODIFY"), None);
+        assert!(! copy.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_baseline_and_truncate() {
+        let dir = "test_diff_baseline";
+        fs::create_dir_all(dir).unwrap();
+        let store = create_test_store(dir);
+        let path = format!("{}/hosts", dir);
+        let lines: Vec<String> = (0..60).map(|i| format!("10.0.0.{} host{}\n", i, i)).collect();
+        fs::write(&path, lines.concat()).unwrap();
+        store.baseline(Path::new(&path));
+        fs::write(&path, lines.concat().replace("10.0.0", "10.1.0")).unwrap();
+        let diff = store.update(&path, "MODIFY").unwrap();
+        assert!(diff.truncated);
+        assert!(diff.text.len() <= 512 && diff.text.ends_with('\n'));
+        // An existing copy is kept
+        fs::write(&path, "changed\n").unwrap();
+        store.baseline(Path::new(&path));
+        assert!(store.read_copy(&path).unwrap().starts_with("10.1.0.0"));
+
+        // Files over the size limit are not kept
+        fs::write(&path, "a".repeat(2048)).unwrap();
+        assert_eq!(store.update(&path, "MODIFY"), None);
+        assert!(! store.get_copy(&path).exists());
+        store.remove(&path);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
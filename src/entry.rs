@@ -3,21 +3,35 @@
 
 // To implement Debug and fmt method
 use std::fmt;
-// To handle files
-use std::fs::OpenOptions;
-use std::io::Write;
-//use std::io::{Write, Error, ErrorKind};
-// Event handling
-// To log the program procedure
-use log::*;
-// To handle JSON objects
-use serde_json::{json, to_string};
 // To manage paths
+use std::path::Path;
+// To manage date and time
+use std::time::{SystemTime, UNIX_EPOCH};
+// To update metrics counters
+use std::sync::atomic::Ordering;
+// To handle JSON objects
+use serde_json::{json, Value};
 
 use notify::event::EventKind;
 
 // To get configuration constants
 use crate::config;
+// To render events in SIEM formats
+use crate::format;
+// To write events to the events file
+use crate::writer;
+// To get file attributes
+use crate::utils;
+// To get file checksums
+use crate::hash;
+// To count produced events
+use crate::metrics;
+// To apply severity and action rules
+use crate::rules;
+// To recognize file types
+use crate::content;
+// To recognize events of agent files
+use crate::selfwatch;
 
 pub struct Entry {
     pub id: String,
@@ -32,13 +46,63 @@ pub struct Entry {
     pub operation: String,
     pub timestamp: String,
     pub checksum: String,
-    pub label: String
+    pub label: String,
+    // Operations merged into this event when coalescing is enabled
+    pub operations: Vec<String>,
+    // Change made during a maintenance window
+    pub expected: bool,
+    // Content analysis, set when enabled for the monitor entry
+    pub entropy: Option<f64>,
+    pub file_type: Option<String>,
+    pub type_changed: Option<bool>,
+    // Unified diff of a modified text file, set when enabled for the monitor entry
+    pub diff: Option<String>,
+    pub diff_truncated: bool,
+    // Rules matching the event
+    pub verdict: rules::Verdict
 }
 
 impl Entry {
-    // Get formatted string with all required data
-    fn format_json(&self) -> String {
-        let obj = json!({
+    // Build an event from the current state of an existing file
+    pub fn new(path: &Path, operation: String, label: String) -> Self {
+        Entry {
+            id: utils::get_uuid(),
+            path: utils::get_path(path),
+            mode: utils::get_perms(path),
+            uid: utils::get_uid(path),
+            gid: utils::get_gid(path),
+            filesize: utils::get_size(path),
+            mtime: utils::get_mtime(path),
+            atime: utils::get_atime(path),
+            ctime: utils::get_ctime(path),
+            operation,
+            timestamp: get_timestamp(),
+            label,
+            checksum: hash::get_checksum(path.display().to_string()),
+            operations: Vec::new(),
+            expected: false,
+            entropy: None,
+            file_type: None,
+            type_changed: None,
+            diff: None,
+            diff_truncated: false,
+            verdict: rules::Verdict::default()
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Measure the entropy and recognize the type of the file contents
+    pub fn analyze(&mut self) {
+        self.entropy = hash::get_entropy(&self.path);
+        self.file_type = content::get_file_type(&self.path).map(String::from);
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Get event object with all required data
+    fn to_json(&self) -> Value {
+        let mut obj = json!({
             "id": self.id.clone(),
             "path": self.path.clone(),
             "mode": self.mode.clone(),
@@ -53,31 +117,50 @@ impl Entry {
             "checksum": self.checksum.clone(),
             "label": self.label.clone()
         });
-        to_string(&obj).unwrap()
+        if ! self.operations.is_empty() { obj["operations"] = json!(self.operations.clone()); }
+        if self.expected { obj["expected"] = json!(true); }
+        if let Some(entropy) = self.entropy { obj["entropy"] = json!((entropy * 1000.0).round() / 1000.0); }
+        if let Some(file_type) = &self.file_type { obj["file_type"] = json!(file_type); }
+        if let Some(changed) = self.type_changed { obj["type_changed"] = json!(changed); }
+        if let Some(diff) = &self.diff { obj["diff"] = json!(diff); }
+        if self.diff_truncated { obj["diff_truncated"] = json!(true); }
+        self.verdict.apply(&mut obj);
+        obj
     }
 
     // ------------------------------------------------------------------------
 
-    // Function to write the received events to file
-    pub fn log(&self, file: String){
-        let mut events_file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(file)
-            .expect("(log) Unable to open events log file.");
+    // Severity set by rules or configured for the label
+    fn get_severity(&self, config: &config::Config) -> u8 {
+        self.verdict.severity.unwrap_or_else(|| config.get_severity(&self.label, &self.operation))
+    }
+
+    // ------------------------------------------------------------------------
 
-             match writeln!(events_file, "{}", self.format_json() ) {
-                    Ok(_d) => debug!("Event log written"),
-                    Err(e) => error!("Event could not be written, Err: [{}]", e)
-                };
+    // Get formatted string in the given output format
+    fn format(&self, format: &str, config: &config::Config) -> String {
+        let severity = self.get_severity(config);
+        let mut obj = self.to_json();
+        add_metadata(&mut obj, config);
+        format::render(format, &obj, severity, &config.version)
     }
 
     // ------------------------------------------------------------------------
 
     // Function to manage event destination
-    pub async fn process(&self, config: config::Config){
-         self.log(config.events_file);
+    pub async fn process(&self, config: config::Config, events: &writer::Handle){
+        metrics::count_event(&self.operation, &self.label);
+        if self.checksum == "UNKNOWN" { metrics::HASH_FAILURES.fetch_add(1, Ordering::Relaxed); }
+        let severity = self.get_severity(&config);
+        // Rule actions never drop agent events
+        let agent = self.label == selfwatch::LABEL;
+        if ! config.syslog_address.is_empty() && (agent || self.verdict.has_action(rules::ACTION_FORWARD)) {
+            events.syslog(&self.format(&config.syslog_format, &config), severity).await;
+        }
+        let mut obj = self.to_json();
+        add_metadata(&mut obj, &config);
+        if ! self.verdict.exec.is_empty() { events.exec(&self.verdict.exec, &obj, severity).await; }
+        if agent || self.verdict.has_action(rules::ACTION_LOG) { events.write(obj, severity).await; }
     }
 }
 
@@ -86,48 +169,103 @@ pub struct Rentry {
     pub path: String,
     pub operation: String,
     pub timestamp: String,
-    pub label: String
+    pub label: String,
+    pub operations: Vec<String>,
+    pub expected: bool,
+    pub verdict: rules::Verdict
 }
 
 impl Rentry {
-    // Get formatted string with all required data
-    fn format_json(&self) -> String {
-        let obj = json!({
+    // Build an event of a file that no longer exists
+    pub fn new(path: &Path, operation: String, label: String) -> Self {
+        Rentry {
+            id: utils::get_uuid(),
+            path: utils::get_path(path),
+            operation,
+            timestamp: get_timestamp(),
+            label,
+            operations: Vec::new(),
+            expected: false,
+            verdict: rules::Verdict::default()
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Get event object with all required data
+    fn to_json(&self) -> Value {
+        let mut obj = json!({
             "id": self.id.clone(),
             "path": self.path.clone(),
             "operation": self.operation.clone(),
             "timestamp": self.timestamp.clone(),
             "label": self.label.clone()
         });
-        to_string(&obj).unwrap()
+        if ! self.operations.is_empty() { obj["operations"] = json!(self.operations.clone()); }
+        if self.expected { obj["expected"] = json!(true); }
+        self.verdict.apply(&mut obj);
+        obj
     }
 
     // ------------------------------------------------------------------------
 
-    // Function to write the received events to file
-    pub fn log(&self, file: String){
-        let mut events_file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(file)
-            .expect("(log) Unable to open events log file.");
+    // Severity set by rules or configured for the label
+    fn get_severity(&self, config: &config::Config) -> u8 {
+        self.verdict.severity.unwrap_or_else(|| config.get_severity(&self.label, &self.operation))
+    }
+
+    // ------------------------------------------------------------------------
 
-             match writeln!(events_file, "{}", self.format_json() ) {
-                    Ok(_d) => debug!("Event log written"),
-                    Err(e) => error!("Event could not be written, Err: [{}]", e)
-                };
+    // Get formatted string in the given output format
+    fn format(&self, format: &str, config: &config::Config) -> String {
+        let severity = self.get_severity(config);
+        let mut obj = self.to_json();
+        add_metadata(&mut obj, config);
+        format::render(format, &obj, severity, &config.version)
     }
 
     // ------------------------------------------------------------------------
 
     // Function to manage event destination
-    pub async fn process(&self, config: config::Config){
-         self.log(config.events_file);
+    pub async fn process(&self, config: config::Config, events: &writer::Handle){
+        metrics::count_event(&self.operation, &self.label);
+        let severity = self.get_severity(&config);
+        // Rule actions never drop agent events
+        let agent = self.label == selfwatch::LABEL;
+        if ! config.syslog_address.is_empty() && (agent || self.verdict.has_action(rules::ACTION_FORWARD)) {
+            events.syslog(&self.format(&config.syslog_format, &config), severity).await;
+        }
+        let mut obj = self.to_json();
+        add_metadata(&mut obj, &config);
+        if ! self.verdict.exec.is_empty() { events.exec(&self.verdict.exec, &obj, severity).await; }
+        if agent || self.verdict.has_action(rules::ACTION_LOG) { events.write(obj, severity).await; }
     }
 }
 
 
+// ----------------------------------------------------------------------------
+
+// Current time in milliseconds as used in event timestamps
+pub fn get_timestamp() -> String {
+    format!("{:?}", SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis())
+}
+
+// ----------------------------------------------------------------------------
+
+// Add host identity, agent metadata and static tags to an event object
+pub fn add_metadata(obj: &mut Value, config: &config::Config){
+    obj["host"] = json!({ "name": config.hostname.clone() });
+    obj["agent"] = json!({ "id": config.agent_id.clone(), "version": config.version.clone() });
+    // Static tags come first, followed by the ones set by rules
+    if ! config.tags.is_empty() {
+        let mut tags = config.tags.clone();
+        for tag in obj["tags"].as_array().into_iter().flatten().filter_map(|t| t.as_str()) {
+            if ! tags.iter().any(|t| t == tag) { tags.push(String::from(tag)); }
+        }
+        obj["tags"] = json!(tags);
+    }
+}
+
 // ----------------------------------------------------------------------------
 
 impl fmt::Debug for Entry {
@@ -141,8 +279,9 @@ impl fmt::Debug for Entry {
 }
 
 // ----------------------------------------------------------------------------
-pub async fn parse_event(event: notify::event::Event) -> String {
-    match event.kind {
+// Operation name of a watcher event kind
+pub fn get_operation(kind: &EventKind) -> String {
+    match kind {
         EventKind::Create(_) =>  { String::from("CREATE") },
         EventKind::Remove(_) =>  { String::from("REMOVE") },
         EventKind::Modify(_) =>  { String::from("MODIFY") },
@@ -157,6 +296,10 @@ pub async fn parse_event(event: notify::event::Event) -> String {
 #[cfg(test)]
 mod tests {
     use crate::entry::Entry;
+    use crate::rules;
+    use crate::selfwatch;
+    use crate::config::create_test_config;
+    use crate::writer;
     use std::fs;
 
     // ------------------------------------------------------------------------
@@ -179,7 +322,15 @@ mod tests {
             operation: "TEST".to_string(),
             timestamp: "Timestamp".to_string(),
             checksum: "UNKNOWN".to_string(),
-            label: "test".to_string()
+            label: "test".to_string(),
+            operations: Vec::new(),
+            expected: false,
+            entropy: None,
+            file_type: None,
+            type_changed: None,
+            diff: None,
+            diff_truncated: false,
+            verdict: rules::Verdict::default()
         }
     }
 
@@ -219,24 +370,111 @@ mod tests {
         let expected = "{\"id\":\"Test_id\",\"path\":\"/home/user\",\"mode\":\"100644\",\
             \"uid\":\"100\",\"gid\":\"100\",\"filesize\":\"100\",\
             \"mtime\":\"Timestamp\",\"atime\":\"Timestamp\",\"ctime\":\"Timestamp\",\
-            \"operation\":\"TEST\",\"timestamp\":\"Timestamp\",\"checksum\":\"UNKNOWN\",\"label\":\"test\"}";
-        assert_eq!(create_test_entry().format_json(), expected);
+            \"operation\":\"TEST\",\"timestamp\":\"Timestamp\",\"checksum\":\"UNKNOWN\",\"label\":\"test\",\
+            \"host\":{\"name\":\"test\"},\"agent\":{\"id\":\"test_agent\",\"version\":\"0.1\"}}";
+        let config = create_test_config("info");
+        assert_eq!(create_test_entry().format("json", &config), expected);
     }
 
     // ------------------------------------------------------------------------
 
     #[test]
-    fn test_log() {
+    fn test_format_cef() {
+        let config = create_test_config("info");
+        let expected = "CEF:0|illumos|ifim|0.1|TEST|File changed|3|externalId=Test_id \
+            filePath=/home/user filePermission=100644 suid=100 cs4=100 fsize=100 \
+            fileModificationTime=Timestamp illumosIfimAtime=Timestamp illumosIfimCtime=Timestamp \
+            act=TEST rt=Timestamp cs3=UNKNOWN cs1=test dvchost=test deviceExternalId=test_agent \
+            illumosIfimAgentVersion=0.1 cs1Label=label cs3Label=checksum cs4Label=gid fname=user";
+        assert_eq!(create_test_entry().format("cef", &config), expected);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_format_operations() {
+        let config = create_test_config("info");
+        let mut evt = create_test_entry();
+        evt.operations = vec!["CREATE".to_string(), "MODIFY".to_string()];
+        let out = evt.format("json", &config);
+        assert!(out.contains("\"label\":\"test\",\"operations\":[\"CREATE\",\"MODIFY\"]"));
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_format_rules() {
+        let mut config = create_test_config("info");
+        config.tags = vec![String::from("production"), String::from("auth")];
+        let mut evt = create_test_entry();
+        evt.verdict = rules::Verdict { rules: vec![String::from("shadow")], severity: Some(9),
+            tags: vec![String::from("auth"), String::from("critical")], ..rules::Verdict::default() };
+        let out = evt.format("json", &config);
+        assert!(out.contains("\"rules\":[\"shadow\"],\"severity\":9"));
+        assert!(out.contains("\"tags\":[\"production\",\"auth\",\"critical\"]"));
+        assert!(evt.format("cef", &config).contains("|TEST|File changed|9|"));
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_format_analysis() {
+        let config = create_test_config("info");
+        let mut evt = create_test_entry();
+        assert!(! evt.format("json", &config).contains("file_type"));
+        evt.entropy = Some(4.12345);
+        evt.file_type = Some(String::from("elf"));
+        evt.type_changed = Some(true);
+        evt.diff = Some(String::from("-a\n+b\n"));
+        evt.diff_truncated = true;
+        let out = evt.format("json", &config);
+        assert!(out.contains("\"entropy\":4.123,\"file_type\":\"elf\",\"type_changed\":true,\
+            \"diff\":\"-a\\n+b\\n\",\"diff_truncated\":true"));
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn test_process() {
         let filename = String::from("test_entry.json");
         let evt = create_test_entry();
+        let mut config = create_test_config("info");
+        config.events_file = filename.clone();
 
-        evt.log(filename.clone());
+        let events = writer::spawn(config.clone());
+        evt.process(config, &events).await;
+        events.flush().await;
         let contents = fs::read_to_string(filename.clone());
         let expected = "{\"id\":\"Test_id\",\"path\":\"/home/user\",\"mode\":\"100644\",\
             \"uid\":\"100\",\"gid\":\"100\",\"filesize\":\"100\",\
             \"mtime\":\"Timestamp\",\"atime\":\"Timestamp\",\"ctime\":\"Timestamp\",\
-            \"operation\":\"TEST\",\"timestamp\":\"Timestamp\",\"checksum\":\"UNKNOWN\",\"label\":\"test\"}\n";
+            \"operation\":\"TEST\",\"timestamp\":\"Timestamp\",\"checksum\":\"UNKNOWN\",\"label\":\"test\",\
+            \"host\":{\"name\":\"test\"},\"agent\":{\"id\":\"test_agent\",\"version\":\"0.1\"}}\n";
         assert_eq!(contents.unwrap(), expected);
         remove_test_file(filename.clone());
     }
+
+    // ------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn test_process_agent_event() {
+        let filename = String::from("test_entry_agent.json");
+        let mut config = create_test_config("info");
+        config.events_file = filename.clone();
+        let verdict = rules::Verdict { actions: vec![String::from(rules::ACTION_FORWARD)], ..rules::Verdict::default() };
+        let mut evt = create_test_entry();
+        evt.verdict = verdict.clone();
+        let mut agent = create_test_entry();
+        agent.label = String::from(selfwatch::LABEL);
+        agent.verdict = verdict;
+
+        let events = writer::spawn(config.clone());
+        evt.process(config.clone(), &events).await;
+        agent.process(config, &events).await;
+        events.flush().await;
+        let contents = fs::read_to_string(filename.clone()).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+        assert!(contents.contains("\"label\":\"ifim\""));
+        remove_test_file(filename);
+    }
 }
@@ -0,0 +1,387 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To handle JSON objects
+use serde_json::{Value, to_string};
+// To render and parse LEEF dates
+use time::{Date, Month, OffsetDateTime, Time};
+
+// Global constants definitions
+const VENDOR: &str = "illumos";
+const PRODUCT: &str = "ifim";
+// Prefix of CEF vendor keys for fields without a dictionary key
+const CEF_PREFIX: &str = "illumosIfim";
+// CEF custom string fields and the event keys they carry as csNLabel
+const CEF_CUSTOM: [(&str, &str); 6] = [("label", "cs1"), ("tags", "cs2"),
+    ("checksum", "cs3"), ("gid", "cs4"), ("rules", "cs5"), ("operations", "cs6")];
+// LEEF devTime format, Java SimpleDateFormat syntax announced in devTimeFormat
+const LEEF_TIME_FORMAT: &str = "MMM dd yyyy HH:mm:ss.SSS z";
+// Month names of LEEF dates, dates are always rendered in UTC
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+    "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+// ----------------------------------------------------------------------------
+
+// Render an event object in the format configured for a sink
+pub fn render(format: &str, obj: &Value, severity: u8, version: &str) -> String {
+    match format {
+        "cef" | "CEF" => cef(obj, severity, version),
+        "leef" | "LEEF" => leef(obj, severity, version),
+        _ => to_string(obj).unwrap()
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Default severity (0-10) of an operation when the monitor entry sets none
+pub fn get_severity(operation: &str) -> u8 {
+    match operation {
+        "SELF_TAMPER" | "MASS_MODIFICATION" => 10,
+        "EVENTS_LOST" => 8,
+        "AGENT_STOP" => 6,
+        "REMOVE" => 7,
+        "CREATE" | "MODIFY" => 5,
+        "ACCESS" => 2,
+        _ => 3
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Human readable name of an operation, used as CEF event name
+fn get_name(operation: &str) -> String {
+    match operation {
+        "CREATE" => String::from("File created"),
+        "REMOVE" => String::from("File removed"),
+        "MODIFY" => String::from("File modified"),
+        "ACCESS" => String::from("File accessed"),
+        "SELF_TAMPER" => String::from("Agent file tampered"),
+        "EVENTS_LOST" => String::from("File events lost"),
+        "AGENT_START" => String::from("Agent started"),
+        "AGENT_STOP" => String::from("Agent stopped"),
+        "MAINTENANCE_SUMMARY" => String::from("Maintenance window changes"),
+        "MASS_MODIFICATION" => String::from("Mass file modification detected"),
+        _ => String::from("File changed")
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Flatten an event object into key/value pairs, nested keys joined by '.'
+fn flatten(prefix: &str, obj: &Value, pairs: &mut Vec<(String, String)>) {
+    match obj {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let name = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                flatten(&name, value, pairs);
+            }
+        },
+        Value::Array(values) => {
+            let joined: Vec<String> = values.iter().map(|v| match v {
+                Value::String(s) => s.clone(),
+                _ => v.to_string()
+            }).collect();
+            pairs.push((String::from(prefix), joined.join(",")));
+        },
+        Value::String(s) => pairs.push((String::from(prefix), s.clone())),
+        Value::Null => {},
+        _ => pairs.push((String::from(prefix), obj.to_string()))
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+fn get_str<'a>(obj: &'a Value, key: &str) -> &'a str {
+    obj[key].as_str().unwrap_or("")
+}
+
+// ----------------------------------------------------------------------------
+
+// Get a field of an event line in any format, nested keys joined by '.'.
+// Keys are the event keys, they are mapped to the CEF or LEEF key of the
+// line. CEF values are read up to the next blank so only simple values like
+// hashes and numbers can be extracted from them, LEEF timestamps are given
+// back in milliseconds.
+pub fn get_field(line: &str, key: &str) -> Option<String> {
+    if let Ok(obj) = serde_json::from_str::<Value>(line) {
+        let pointer = format!("/{}", key.replace('.', "/"));
+        return match obj.pointer(&pointer)? {
+            Value::String(s) => Some(s.clone()),
+            Value::Null => None,
+            value => Some(value.to_string())
+        }
+    }
+    let leef = line.starts_with("LEEF:");
+    let name = if leef { leef_key(key) } else { cef_key(key) };
+    let pattern = format!("{}=", name);
+    let start = line.match_indices(&pattern)
+        .find(|(pos, _)| *pos > 0 && matches!(line.as_bytes()[pos - 1], b' ' | b'\t' | b'|'))
+        .map(|(pos, _)| pos + pattern.len())?;
+    if leef {
+        let value = line[start..].split('\t').next().unwrap_or("");
+        if name == "devTime" { return parse_leef_time(value) }
+        Some(value.to_string())
+    }else{
+        Some(line[start..].split(char::is_whitespace).next().unwrap_or("").to_string())
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Format a milliseconds timestamp as a LEEF devTime, other values are kept
+fn format_leef_time(value: &str) -> String {
+    match value.parse::<i128>().ok()
+        .and_then(|millis| OffsetDateTime::from_unix_timestamp_nanos(millis * 1_000_000).ok()) {
+        Some(date) => format!("{} {:02} {} {:02}:{:02}:{:02}.{:03} UTC",
+            MONTHS[date.month() as usize - 1], date.day(), date.year(),
+            date.hour(), date.minute(), date.second(), date.millisecond()),
+        None => String::from(value)
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Parse a LEEF devTime back to a milliseconds timestamp
+fn parse_leef_time(value: &str) -> Option<String> {
+    let fields: Vec<&str> = value.split([' ', ':', '.']).collect();
+    let parse = || -> Option<String> {
+        if fields.len() != 8 || fields[7] != "UTC" { return None }
+        let number = |index: usize| fields[index].parse::<u16>().ok();
+        let month = MONTHS.iter().position(|m| *m == fields[0])?;
+        let date = Date::from_calendar_date(fields[2].parse().ok()?,
+            Month::try_from(month as u8 + 1).ok()?, number(1)? as u8).ok()?;
+        let time = Time::from_hms_milli(number(3)? as u8, number(4)? as u8,
+            number(5)? as u8, number(6)?).ok()?;
+        Some((date.with_time(time).assume_utc().unix_timestamp_nanos() / 1_000_000).to_string())
+    };
+    parse().or_else(|| Some(String::from(value)))
+}
+
+// ----------------------------------------------------------------------------
+
+// Escape CEF and LEEF header fields
+pub fn escape_header(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|")
+        .replace(['\n', '\r'], " ")
+}
+
+// ----------------------------------------------------------------------------
+
+// Escape CEF extension values
+pub fn escape_cef(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('=', "\\=")
+        .replace('\n', "\\n").replace('\r', "\\r")
+}
+
+// ----------------------------------------------------------------------------
+
+// Escape LEEF attribute values, tab is the attribute delimiter
+pub fn escape_leef(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('=', "\\=")
+        .replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+// ----------------------------------------------------------------------------
+
+// Map event keys to CEF dictionary keys or custom string fields, other keys
+// become vendor keys like illumosIfimPrevHash
+fn cef_key(key: &str) -> String {
+    if let Some((_, field)) = CEF_CUSTOM.iter().find(|(k, _)| *k == key) {
+        return String::from(*field)
+    }
+    String::from(match key {
+        "id" => "externalId",
+        "path" => "filePath",
+        "filesize" => "fsize",
+        "mode" => "filePermission",
+        "uid" => "suid",
+        "mtime" => "fileModificationTime",
+        "operation" => "act",
+        "timestamp" => "rt",
+        "host.name" => "dvchost",
+        "agent.id" => "deviceExternalId",
+        _ => {
+            let words: String = key.split(['.', '_']).map(|word| {
+                let mut chars = word.chars();
+                match chars.next() {
+                    Some(first) => first.to_uppercase().chain(chars).collect(),
+                    None => String::new()
+                }
+            }).collect();
+            return format!("{}{}", CEF_PREFIX, words)
+        }
+    })
+}
+
+// ----------------------------------------------------------------------------
+
+// Get the CEF value of an event key, file times go from seconds to milliseconds
+fn cef_value(key: &str, value: &str) -> String {
+    match (key, value.parse::<u64>()) {
+        ("mtime", Ok(secs)) => (secs * 1000).to_string(),
+        _ => String::from(value)
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Map event keys to LEEF predefined attributes, unknown keys keep their name
+fn leef_key(key: &str) -> String {
+    String::from(match key {
+        "id" => "externalId",
+        "operation" => "cat",
+        "timestamp" => "devTime",
+        "host.name" => "identHostName",
+        _ => key
+    })
+}
+
+// ----------------------------------------------------------------------------
+
+// Get file name of the event path for the fname key
+fn get_fname(obj: &Value) -> String {
+    let path = get_str(obj, "path");
+    String::from(path.rsplit('/').next().unwrap_or(path))
+}
+
+// ----------------------------------------------------------------------------
+
+// Render an event object as an ArcSight Common Event Format line
+pub fn cef(obj: &Value, severity: u8, version: &str) -> String {
+    let operation = get_str(obj, "operation");
+    let mut pairs = Vec::new();
+    flatten("", obj, &mut pairs);
+
+    let mut ext: Vec<String> = pairs.iter().map(|(k, v)|
+        format!("{}={}", cef_key(k), escape_cef(&cef_value(k, v)))).collect();
+    for (key, field) in CEF_CUSTOM {
+        if pairs.iter().any(|(k, _)| k == key) { ext.push(format!("{}Label={}", field, key)); }
+    }
+    if obj.get("path").is_some() { ext.push(format!("fname={}", escape_cef(&get_fname(obj)))); }
+
+    format!("CEF:0|{}|{}|{}|{}|{}|{}|{}",
+        escape_header(VENDOR), escape_header(PRODUCT), escape_header(version),
+        escape_header(operation), escape_header(&get_name(operation)),
+        severity.min(10), ext.join(" "))
+}
+
+// ----------------------------------------------------------------------------
+
+// Render an event object as an IBM QRadar Log Event Extended Format line
+pub fn leef(obj: &Value, severity: u8, version: &str) -> String {
+    let operation = get_str(obj, "operation");
+    let mut pairs = Vec::new();
+    flatten("", obj, &mut pairs);
+
+    let mut attrs: Vec<String> = pairs.iter().map(|(k, v)| match k.as_str() {
+        "timestamp" => format!("devTime={}", escape_leef(&format_leef_time(v))),
+        _ => format!("{}={}", leef_key(k), escape_leef(v))
+    }).collect();
+    if obj.get("timestamp").is_some() { attrs.push(format!("devTimeFormat={}", LEEF_TIME_FORMAT)); }
+    attrs.push(format!("sev={}", severity.clamp(1, 10)));
+
+    format!("LEEF:1.0|{}|{}|{}|{}|{}",
+        escape_header(VENDOR), escape_header(PRODUCT), escape_header(version),
+        escape_header(operation), attrs.join("\t"))
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn create_test_obj() -> Value {
+        json!({
+            "id": "Test_id",
+            "path": "/etc/a=b|c.conf",
+            "operation": "MODIFY",
+            "timestamp": "1671211286106",
+            "label": "etc"
+        })
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_escape_header() {
+        assert_eq!(escape_header("a|b\\c"), "a\\|b\\\\c");
+        assert_eq!(escape_header("a\nb"), "a b");
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_escape_cef() {
+        assert_eq!(escape_cef("a=b\\c|d"), "a\\=b\\\\c|d");
+        assert_eq!(escape_cef("a\nb\rc"), "a\\nb\\rc");
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_escape_leef() {
+        assert_eq!(escape_leef("a=b\tc"), "a\\=b\\tc");
+        assert_eq!(escape_leef("a\\b"), "a\\\\b");
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_get_severity() {
+        assert_eq!(get_severity("SELF_TAMPER"), 10);
+        assert_eq!(get_severity("EVENTS_LOST"), 8);
+        assert_eq!(get_severity("AGENT_STOP"), 6);
+        assert_eq!(get_severity("REMOVE"), 7);
+        assert_eq!(get_severity("MODIFY"), 5);
+        assert_eq!(get_severity("ACCESS"), 2);
+        assert_eq!(get_severity("OTHER"), 3);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_cef() {
+        let expected = "CEF:0|illumos|ifim|0.1|MODIFY|File modified|5|externalId=Test_id \
+            filePath=/etc/a\\=b|c.conf act=MODIFY rt=1671211286106 cs1=etc cs1Label=label \
+            fname=a\\=b|c.conf";
+        assert_eq!(cef(&create_test_obj(), 5, "0.1"), expected);
+        let obj = json!({"checksum": "ab", "mtime": "2", "prev_hash": "cd"});
+        let expected = "CEF:0|illumos|ifim|0.1||File changed|5|cs3=ab fileModificationTime=2000 \
+            illumosIfimPrevHash=cd cs3Label=checksum";
+        assert_eq!(cef(&obj, 5, "0.1"), expected);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_leef() {
+        let expected = "LEEF:1.0|illumos|ifim|0.1|MODIFY|externalId=Test_id\t\
+            path=/etc/a\\=b|c.conf\tcat=MODIFY\tdevTime=Dec 16 2022 17:21:26.106 UTC\tlabel=etc\t\
+            devTimeFormat=MMM dd yyyy HH:mm:ss.SSS z\tsev=5";
+        assert_eq!(leef(&create_test_obj(), 5, "0.1"), expected);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_get_field() {
+        assert_eq!(get_field("{\"a\":{\"b\":\"c\"},\"n\":1}", "a.b"), Some(String::from("c")));
+        assert_eq!(get_field("{\"a\":{\"b\":\"c\"},\"n\":1}", "n"), Some(String::from("1")));
+        assert_eq!(get_field("{\"a\":\"b\"}", "x"), None);
+        assert_eq!(get_field("CEF:0|a|b|c|d|e|5|xa=1 cs3=2", "checksum"), Some(String::from("2")));
+        assert_eq!(get_field("LEEF:1.0|a|b|c|d|a=1\tb=2", "b"), Some(String::from("2")));
+        let obj = create_test_obj();
+        assert_eq!(get_field(&cef(&obj, 5, "0.1"), "timestamp"), Some(String::from("1671211286106")));
+        assert_eq!(get_field(&leef(&obj, 5, "0.1"), "timestamp"), Some(String::from("1671211286106")));
+        assert_eq!(get_field(&leef(&obj, 5, "0.1"), "label"), Some(String::from("etc")));
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_render_json() {
+        let out = render("json", &create_test_obj(), 5, "0.1");
+        assert!(out.starts_with("{\"id\":\"Test_id\""));
+    }
+}
@@ -5,18 +5,16 @@
 use hex::{encode};
 use sha3::{Sha3_512, Digest};
 use std::io::ErrorKind;
+// To sample file contents
+use std::fs::File;
+use std::io::Read;
 // To log the program process
 use log::*;
 
 // To calculate file content hash in sha512 format (SHA3 implementation)
 pub fn get_checksum(file: String) -> String {
-    let mut hasher = Sha3_512::new();
-    match std::fs::read_to_string(file) {
-        Ok(data) => {
-            hasher.update(&data);
-            let result = hasher.finalize().to_vec();
-            encode(result)
-        },
+    match std::fs::read(file) {
+        Ok(data) => get_data_checksum(&data),
         Err(e) => {
             match e.kind() {
                 ErrorKind::NotFound => {
@@ -34,6 +32,34 @@ pub fn get_checksum(file: String) -> String {
 
 // ----------------------------------------------------------------------------
 
+// Checksum of contents already read, in the format of file checksums
+pub fn get_data_checksum(data: &[u8]) -> String {
+    let mut hasher = Sha3_512::new();
+    hasher.update(data);
+    encode(hasher.finalize())
+}
+
+// ----------------------------------------------------------------------------
+
+// Bytes read from the start of a file to measure its entropy
+const ENTROPY_SAMPLE: u64 = 65536;
+
+// Shannon entropy in bits per byte (0-8) of the start of a file, encrypted
+// and compressed contents are close to 8. None for unreadable or empty files
+pub fn get_entropy(file: &str) -> Option<f64> {
+    let mut data = Vec::new();
+    File::open(file).ok()?.take(ENTROPY_SAMPLE).read_to_end(&mut data).ok()?;
+    if data.is_empty() { return None }
+    let mut counts = [0usize; 256];
+    for byte in &data { counts[*byte as usize] += 1; }
+    let len = data.len() as f64;
+    Some(counts.iter().filter(|c| **c > 0).map(|c| {
+        let p = *c as f64 / len;
+        -p * p.log2()
+    }).sum())
+}
+
+// ----------------------------------------------------------------------------
 
 #[cfg(test)]
 mod tests {
@@ -70,6 +96,17 @@ mod tests {
 
     // ------------------------------------------------------------------------
 
+    #[test]
+    fn test_get_checksum_binary() {
+        let filename = String::from("test_get_checksum_binary");
+        File::create(filename.clone()).unwrap().write_all(b"\x7fELF\xff\xfe").unwrap();
+        assert_eq!(get_checksum(filename.clone()), get_data_checksum(b"\x7fELF\xff\xfe"));
+        assert_ne!(get_checksum(filename.clone()), String::from("UNKNOWN"));
+        remove_test_file(filename);
+    }
+
+    // ------------------------------------------------------------------------
+
     #[test]
     fn test_get_checksum_bad() {
         let filename = String::from("test_get_checksum_bad");
@@ -77,4 +114,20 @@ mod tests {
         assert_ne!(get_checksum(filename.clone()), String::from("This is a test"));
         remove_test_file(filename.clone());
     }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_get_entropy() {
+        let filename = String::from("test_get_entropy");
+        File::create(&filename).unwrap().write_all(&[b'a'; 100]).unwrap();
+        assert_eq!(get_entropy(&filename), Some(0.0));
+        let data: Vec<u8> = (0..=255).collect();
+        File::create(&filename).unwrap().write_all(&data.repeat(4)).unwrap();
+        assert_eq!(get_entropy(&filename), Some(8.0));
+        File::create(&filename).unwrap();
+        assert_eq!(get_entropy(&filename), None);
+        remove_test_file(filename);
+        assert_eq!(get_entropy("not_exists"), None);
+    }
 }
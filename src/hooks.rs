@@ -0,0 +1,179 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To run hook commands
+use std::process::{ExitStatus, Stdio};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+// To limit the hooks running at once
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+// To manage date and time
+use std::time::{Duration, Instant};
+// To handle JSON objects
+use serde_json::Value;
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+
+// Global constants definitions
+// Event fields passed as IFIM_<FIELD> environment variables, along IFIM_SEVERITY
+const ENV_FIELDS: [&str; 9] = ["id", "path", "operation", "label", "timestamp", "checksum", "uid", "gid",
+    "mode"];
+
+// ----------------------------------------------------------------------------
+
+// Environment variables of the key fields of an event and its severity,
+// which is only in the event when a rule sets it
+fn get_env(obj: &Value, severity: u8) -> Vec<(String, String)> {
+    let mut env: Vec<(String, String)> = ENV_FIELDS.iter().filter_map(|field| {
+        let value = match &obj[*field] {
+            Value::String(value) => value.clone(),
+            Value::Number(value) => value.to_string(),
+            _ => return None
+        };
+        Some((format!("IFIM_{}", field.to_uppercase()), value))
+    }).collect();
+    env.push((String::from("IFIM_SEVERITY"), severity.to_string()));
+    env
+}
+
+// ----------------------------------------------------------------------------
+
+// Run a command with the event on stdin, killed once the timeout expires
+async fn execute(command: &str, input: &str, env: Vec<(String, String)>, timeout: Duration)
+    -> Result<ExitStatus, String> {
+    let mut child = Command::new("sh").arg("-c").arg(command).envs(env)
+        .stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::null())
+        .kill_on_drop(true).spawn().map_err(|e| e.to_string())?;
+    let stdin = child.stdin.take();
+    // Writing the input counts in the timeout, hooks may never read it
+    let status = tokio::time::timeout(timeout, async {
+        if let Some(mut stdin) = stdin {
+            // Hooks may exit without reading their input
+            let _ = stdin.write_all(input.as_bytes()).await;
+        }
+        child.wait().await
+    }).await;
+    match status {
+        Ok(status) => status.map_err(|e| e.to_string()),
+        Err(_) => {
+            let _ = child.kill().await;
+            Err(format!("killed after {} seconds", timeout.as_secs()))
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Runner of hook commands shared by every worker
+#[derive(Clone)]
+pub struct Handle {
+    slots: Arc<Semaphore>,
+    concurrency: u32,
+    timeout: Duration
+}
+
+impl Handle {
+    pub fn new(config: &config::Config) -> Self {
+        let concurrency = config.hooks_concurrency.max(1) as u32;
+        Handle {
+            slots: Arc::new(Semaphore::new(concurrency as usize)),
+            concurrency,
+            timeout: Duration::from_secs(config.hooks_timeout)
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Start the hook commands of an event, waits while every slot is busy
+    pub async fn run(&self, commands: &[String], obj: &Value, severity: u8) {
+        let input = format!("{}\n", obj);
+        for command in commands {
+            let slot = match self.slots.clone().acquire_owned().await {
+                Ok(slot) => slot,
+                Err(_) => return
+            };
+            let (command, input, env, timeout) = (command.clone(), input.clone(), get_env(obj, severity), self.timeout);
+            tokio::spawn(async move {
+                let start = Instant::now();
+                match execute(&command, &input, env, timeout).await {
+                    Ok(status) if status.success() => info!("Hook {} exited with {} in {} ms", command, status,
+                        start.elapsed().as_millis()),
+                    Ok(status) => warn!("Hook {} failed, exited with {}", command, status),
+                    Err(e) => error!("Hook {} failed, Err: [{}]", command, e)
+                }
+                drop(slot);
+            });
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Wait until the running hooks end
+    pub async fn wait(&self) {
+        if let Ok(slots) = self.slots.acquire_many(self.concurrency).await { drop(slots); }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+    use serde_json::json;
+    use std::fs;
+
+    #[test]
+    fn test_get_env() {
+        let env = get_env(&json!({ "path": "/etc/shadow", "operation": "MODIFY", "severity": 9, "tags": ["a"] }), 9);
+        assert_eq!(env, vec![
+            (String::from("IFIM_PATH"), String::from("/etc/shadow")),
+            (String::from("IFIM_OPERATION"), String::from("MODIFY")),
+            (String::from("IFIM_SEVERITY"), String::from("9"))
+        ]);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn test_execute() {
+        let env = vec![(String::from("IFIM_PATH"), String::from("/etc/shadow"))];
+        let status = execute("read line && test \"$line\" = event && test \"$IFIM_PATH\" = /etc/shadow",
+            "event\n", env, Duration::from_secs(5)).await;
+        assert!(status.unwrap().success());
+        assert!(! execute("exit 3", "", Vec::new(), Duration::from_secs(5)).await.unwrap().success());
+        let start = Instant::now();
+        assert!(execute("sleep 5", "", Vec::new(), Duration::from_millis(100)).await.is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn test_execute_unread_input() {
+        // Larger than the pipe buffer, the write blocks until the hook reads
+        let input = "x".repeat(1024 * 1024);
+        let start = Instant::now();
+        assert!(execute("sleep 5", &input, Vec::new(), Duration::from_millis(100)).await.is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(execute("exit 0", &input, Vec::new(), Duration::from_secs(5)).await.unwrap().success());
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn test_run() {
+        let filename = "test_hooks.out";
+        let mut config = create_test_config("info");
+        config.hooks_concurrency = 1;
+        let hooks = Handle::new(&config);
+        let command = format!("cat >> {} && echo $IFIM_SEVERITY >> {}", filename, filename);
+        hooks.run(&[command.clone(), command], &json!({ "path": "/etc/shadow" }), 5).await;
+        hooks.wait().await;
+        assert_eq!(fs::read_to_string(filename).unwrap(), "{\"path\":\"/etc/shadow\"}\n5\n".repeat(2));
+        fs::remove_file(filename).unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To handle JSON objects
+use serde_json::{json, Value};
+// To wait for termination signals
+use tokio::signal::unix::{signal, SignalKind};
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+// To add agent metadata to records
+use crate::entry;
+// To render records for syslog
+use crate::format;
+// To write records to the events file
+use crate::writer;
+// To generate record ids
+use crate::utils;
+
+// Global constants definitions
+pub const OPERATION_START: &str = "AGENT_START";
+pub const OPERATION_STOP: &str = "AGENT_STOP";
+
+// ----------------------------------------------------------------------------
+
+// Agent start or stop record, so gaps in monitoring are visible in the events
+pub fn get_record(config: &config::Config, operation: &str, reason: &str) -> Value {
+    let mut obj = json!({
+        "id": utils::get_uuid(),
+        "operation": operation,
+        "timestamp": entry::get_timestamp(),
+        "pid": std::process::id(),
+        "reason": reason
+    });
+    entry::add_metadata(&mut obj, config);
+    obj
+}
+
+// ----------------------------------------------------------------------------
+
+// Send an agent record to every configured output
+pub async fn record(config: &config::Config, events: &writer::Handle, operation: &str, reason: &str) {
+    send(config, events, get_record(config, operation, reason)).await;
+}
+
+// ----------------------------------------------------------------------------
+
+// Send a record built by the agent itself, its severity is the default one
+// of its operation
+pub async fn send(config: &config::Config, events: &writer::Handle, obj: Value) {
+    let severity = format::get_severity(obj["operation"].as_str().unwrap_or(""));
+    if ! config.syslog_address.is_empty() {
+        events.syslog(&format::render(&config.syslog_format, &obj, severity, &config.version), severity).await;
+    }
+    events.write(obj, severity).await;
+}
+
+// ----------------------------------------------------------------------------
+
+// Wait until the agent is asked to stop, returns the signal name
+pub async fn wait_signal() -> &'static str {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Could not install SIGTERM handler, Err: [{}]", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT"
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT"
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+    use std::fs;
+
+    #[test]
+    fn test_get_record() {
+        let config = create_test_config("info");
+        let obj = get_record(&config, OPERATION_STOP, "SIGTERM");
+        assert_eq!(obj["operation"], OPERATION_STOP);
+        assert_eq!(obj["reason"], "SIGTERM");
+        assert_eq!(obj["agent"]["id"], "test_agent");
+        assert_eq!(obj["pid"], std::process::id());
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn test_record() {
+        let filename = "test_lifecycle.json";
+        let mut config = create_test_config("info");
+        config.events_file = String::from(filename);
+        let events = writer::spawn(config.clone());
+        record(&config, &events, OPERATION_START, "started").await;
+        record(&config, &events, OPERATION_STOP, "SIGINT").await;
+        events.flush().await;
+        let contents = fs::read_to_string(filename).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("\"operation\":\"AGENT_START\""));
+        assert!(lines[1].contains("\"operation\":\"AGENT_STOP\""));
+        fs::remove_file(filename).unwrap();
+    }
+}
@@ -6,16 +6,19 @@
 
 // To read and write directories and files
 use std::fs;
-use notify::{RecommendedWatcher, RecursiveMode, Watcher, Config};
+use notify::{Event, PollWatcher, RecommendedWatcher, Watcher, Config};
+// To share pipeline metrics
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+// To manage date and time
+use std::time::{Duration, Instant};
+// To queue watcher events
+use tokio::sync::mpsc;
 // To log the program process
-use log::{info, error, debug};
+use log::{info, error};
 use simplelog::{WriteLogger}; //, Config as SimpleConfig};
 // To manage paths
 use std::path::Path;
-use std::fs::metadata;
-use std::os::unix::fs::FileTypeExt;
-// To manage date and time
-use std::time::{SystemTime, UNIX_EPOCH};
 // To use intersperse()
 use itertools::Itertools;
 // Colorize
@@ -28,6 +31,62 @@ mod hash;
 mod config;
 // Single event data management
 mod entry;
+// Event output formats
+mod format;
+// Syslog event destination
+mod syslog;
+// Events file rotation
+mod rotation;
+// Events file writer task
+mod writer;
+// Events hash chain
+mod chain;
+// Events signed checkpoints
+mod signing;
+// Agent files integrity monitoring
+mod selfwatch;
+// Events bursts coalescing
+mod coalesce;
+// Disk queue of undelivered events
+mod spool;
+// Events processing stages and workers
+mod pipeline;
+// Monitored files state and rescans
+mod scan;
+// Monitored directories watches
+mod watch;
+// Agent status file and command
+mod status;
+// Agent start and stop
+mod lifecycle;
+// Prometheus metrics endpoint
+mod metrics;
+// Agent control socket and command
+mod control;
+// Maintenance windows of labels
+mod maintenance;
+// Event severity and actions rules
+mod rules;
+// External hook commands
+mod hooks;
+// Mass modification detection
+mod detector;
+// File contents analysis
+mod content;
+// Textual diffs of monitored files
+mod diff;
+// Versions of monitored files
+mod vault;
+// Search of stored events
+mod query;
+// SQLite event store
+mod database;
+
+// Global constants definitions
+// Milliseconds between checks of settled bursts of events
+const COALESCE_TICK: u64 = 100;
+// Seconds between pipeline metrics log lines
+const METRICS_INTERVAL: u64 = 60;
 
 // ----------------------------------------------------------------------------
 
@@ -46,7 +105,6 @@ fn setup_logger(config: config::Config){
         config.get_level_filter(),
         log_config,
         fs::OpenOptions::new()
-            .write(true)
             .create(true)
             .append(true)
             .open(config.log_file)
@@ -56,10 +114,54 @@ fn setup_logger(config: config::Config){
 
 // ----------------------------------------------------------------------------
 
-fn setup_events(config: config::Config){
+fn setup_events(config: &mut config::Config){
     // Perform actions depending on destination
     info!("Events file: {}", config.events_file);
-    fs::create_dir_all(Path::new(&config.events_file).parent().unwrap().to_str().unwrap()).unwrap()
+    let events_dir = Path::new(&config.events_file).parent().unwrap();
+    fs::create_dir_all(events_dir.to_str().unwrap()).unwrap();
+    config.agent_id = utils::get_agent_id(events_dir);
+    info!("Agent id: {}, host: {}", config.agent_id, config.hostname);
+}
+
+// ----------------------------------------------------------------------------
+
+// Filter stage, matched events are queued to the hashing workers
+async fn handle_event(event: notify::Result<Event>, config: &config::Config, filter: &mut pipeline::Filter,
+    pool: &pipeline::Pool, metrics: &pipeline::Metrics) {
+    metrics.event_dequeued();
+    match event {
+        Ok(event) => for job in filter.process(event, config) {
+            pool.submit(job).await;
+        },
+        Err(e) => error!("watch error: {:?}", e)
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Watch the agent files and every monitor entry with its backend
+fn setup_watches(config: &config::Config, tx: &mpsc::Sender<notify::Result<Event>>,
+    metrics: &Arc<pipeline::Metrics>, selfwatch: &selfwatch::SelfWatch) -> watch::Watches {
+    let mut watcher = RecommendedWatcher::new(pipeline::get_handler(tx.clone(), metrics.clone()),
+        Config::default()).unwrap();
+    // Agent files are always watched, whatever monitor and ignore say
+    selfwatch.watch(&mut watcher);
+
+    let mut watches = watch::Watches::new(watcher);
+    for index in 0..config.monitor.len() {
+        if config.get_backend(index) == "poll" {
+            let interval = Duration::from_secs(config.get_poll_interval(index));
+            let poll_config = Config::default().with_poll_interval(interval).with_compare_contents(true);
+            match PollWatcher::new(pipeline::get_handler(tx.clone(), metrics.clone()), poll_config) {
+                Ok(poller) => watches.add_poll(config, index, poller),
+                Err(e) => error!("Could not create poll watcher, Err: [{}]", e)
+            }
+        } else {
+            watches.add(config, index);
+        }
+    }
+    metrics::WATCHED_DIRS.store(watches.watched_dirs() as u64, Ordering::Relaxed);
+    watches
 }
 
 // ----------------------------------------------------------------------------
@@ -68,21 +170,54 @@ fn setup_events(config: config::Config){
 #[tokio::main]
 async fn main() {
     println!("{}", "Reading config...".green());
-    let config = config::Config::new(&utils::get_os());
+    let mut config = config::Config::new(&utils::get_os());
+
+    // Run command line subcommands instead of the monitor
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 1 {
+        let code = match args[1].as_str() {
+            "verify-log" => chain::verify_command(&config, &args[2..]),
+            "verify-signature" => signing::verify_command(&config, &args[2..]),
+            "keygen" => signing::keygen_command(&args[2..]),
+            "status" => status::status_command(&config),
+            "ctl" => control::ctl_command(&config, &args[2..]),
+            "restore" => vault::restore_command(&config, &args[2..]),
+            "query" => query::query_command(&config, &args[2..]),
+            command => {
+                eprintln!("{}: {}", "Unknown command".red(), command);
+                eprintln!("Usage: ifim [verify-log [file] [--chain sidecar]]");
+                eprintln!("       ifim [verify-signature <public key> [file]]");
+                eprintln!("       ifim [keygen <private key file>]");
+                eprintln!("       ifim [status]");
+                eprintln!("       ifim [ctl <command> [argument]]");
+                eprintln!("       ifim [restore <path> --at <timestamp|event-id> [--to <file>]]");
+                eprintln!("       ifim [query [--path <glob>] [--label <label>] [--operation <operation>] \
+                    [--state] [--since <time>] [--until <time>] [--uid <uid>] [--checksum <checksum>] [--format table|json|csv]]");
+                2
+            }
+        };
+        std::process::exit(code);
+    }
     println!("{}: {}", "Log file".green(), config.log_file);
     println!("{}: {}", "Log level".green(), config.log_level);
 
     setup_logger(config.clone());
-    setup_events(config.clone());
+    setup_events(&mut config);
 
     info!("illumos File Integrity Monitor started");
-    let (tx, rx) = std::sync::mpsc::channel();
-    let mut watcher: RecommendedWatcher = Watcher::new(tx, Config::default()).unwrap();
+    let events = writer::spawn(config.clone());
+    let metrics = Arc::new(pipeline::Metrics::default());
+    let detector = detector::Detector::shared(&config);
+    let vault = vault::Vault::shared(&config);
+    let engine = rules::Engine::new(&config);
+    let mut pool = pipeline::Pool::spawn(&config, &events, metrics.clone(), &detector, &vault, &engine);
+    lifecycle::record(&config, &events, lifecycle::OPERATION_START, "started").await;
+
+    let (tx, mut rx) = mpsc::channel(config.queue_size);
 
     if ! config.monitor.is_empty() {
         for element in config.monitor.clone() {
             let path = element["path"].as_str().unwrap();
-            info!("Monitoring path: {}", path);
             match element["ignore"].as_vec() {
                 Some(ig) => {
                     let ignore_list_vec  = ig.iter().map(|e| { e.as_str().unwrap() });
@@ -91,70 +226,192 @@ async fn main() {
                 },
                 None => info!("Ignore for '{}' not set", path)
             };
-            match watcher.watch(path.as_ref(), RecursiveMode::Recursive) {
-                Ok(_d) => debug!("Monitoring path: {}", path),
-                Err(e) => println!("{} {}", "Could not monitor given path".red(), e)
-            };
         }
     }
-    for res in rx {
-        match res {
-            Ok(event) => {
-                debug!("Event received: {:?}", event);
-                let plain_path = event.paths[0].display().to_string();
-                let event_path = Path::new(&plain_path);
-                let event_filename = event_path.file_name().unwrap().to_str().unwrap();
-
-                let path = event.paths[0].display().to_string();
-                let current_timestamp = format!("{:?}", SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis());
-             
-                if event_path.exists() {
-                    let ftype = metadata(event_path).unwrap().file_type();
-                    if ftype.is_fifo() || ftype.is_block_device() || ftype.is_char_device() || ftype.is_socket() { continue; } 
-                    if ftype.is_dir() { continue; }
-                }
+    let selfwatch = selfwatch::SelfWatch::new(&config);
+    // The sender is kept to watch again on reloads
+    let mut watches = setup_watches(&config, &tx, &metrics, &selfwatch);
+    for monitor in watches.status().as_array().unwrap_or(&Vec::new()) {
+        if let Some(e) = monitor["error"].as_str() {
+            println!("{} {}: {}", "Could not monitor given path".red(), monitor["path"].as_str().unwrap_or(""), e);
+        }
+    }
 
-                let index = config.get_index(event_path.to_str().unwrap(), "", config.monitor.clone().to_vec());
-                if index != usize::MAX {
-                    let label = config.get_label(index);
-                    if ! config.match_ignore(index,event_filename, config.monitor.clone()) {
-                        if ! event.kind.is_remove() {
-                            let entry = entry::Entry {
-                                id: utils::get_uuid(),
-                                path: utils::get_path(event_path),
-                                mode: utils::get_perms(event_path),
-                                uid: utils::get_uid(event_path),
-                                gid: utils::get_gid(event_path),
-                                filesize: utils::get_size(event_path), 
-                                mtime: utils::get_mtime(event_path),
-                                atime: utils::get_atime(event_path),
-                                ctime: utils::get_ctime(event_path),
-                                operation: entry::parse_event(event.clone()).await,
-                                timestamp: current_timestamp,
-                                label,
-                                checksum: hash::get_checksum(path.to_string())
-                            };
-                            entry.process(config.clone()).await;  
-                            info!("Changes found: {} {}", plain_path, entry.operation);  
-                        } else {
-                            let entry = entry::Rentry {
-                                id: utils::get_uuid(),
-                                path: utils::get_path(event_path),
-                                operation: entry::parse_event(event.clone()).await,
-                                timestamp: current_timestamp,
-                                label
-                            };
-                            entry.process(config.clone()).await;  
-                            info!("Changes found: {} {}", plain_path, entry.operation);  
+    let mut filter = pipeline::Filter::new(selfwatch, metrics.clone());
+    filter.preserve(vault.clone());
+    filter.seed(engine);
+    // Walks of monitored files block, they run out of the loop and come back through this channel
+    let (scan_tx, mut scan_rx) = mpsc::unbounded_channel();
+    filter.scanner().baseline(&config, &scan_tx);
+    filter.schedule(&config);
+    filter.detect(detector.clone());
+    status::write(&config, &status::get_status(&config, watches.status(), &metrics));
+    metrics::spawn(&config, metrics.clone()).await;
+    let mut control = control::spawn(&config);
+    let started = Instant::now();
+    let mut coalesce_tick = tokio::time::interval(Duration::from_millis(COALESCE_TICK));
+    let period = Duration::from_secs(METRICS_INTERVAL);
+    let mut metrics_tick = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    let period = Duration::from_secs(config.scan_interval);
+    let mut scan_tick = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    let stop = lifecycle::wait_signal();
+    tokio::pin!(stop);
+    let reason = loop {
+        tokio::select! {
+            event = rx.recv() => match event {
+                Some(event) => {
+                    // Files of new directories may be created before they are watched
+                    if let Ok(event) = &event {
+                        for (index, dir) in watches.update(event) {
+                            filter.scanner().scan(&config, index, &dir, None, &scan_tx);
                         }
-                        debug!("Event processed: {:?}", event);
                     }
+                    handle_event(event, &config, &mut filter, &pool, &metrics).await;
+                    for (index, root) in filter.rescans(&config) {
+                        filter.scanner().scan(&config, index, &root, None, &scan_tx);
+                    }
+                },
+                None => break "watcher stopped"
+            },
+            _ = coalesce_tick.tick() => {
+                for job in filter.settled() {
+                    pool.submit(job).await;
+                }
+                for summary in filter.maintenance(&config, maintenance::now()) {
+                    lifecycle::send(&config, &events, summary).await;
+                }
+                for alert in filter.alerts(&config) {
+                    lifecycle::send(&config, &events, alert).await;
                 }
             },
-            Err(e) => {
-                error!("watch error: {:?}", e);
-            }
-        };
+            Some(scanned) = scan_rx.recv() => match scanned {
+                pipeline::Scanned::Baseline(generation, snapshots) => {
+                    filter.record(generation, snapshots);
+                    // Events lost during the baseline are looked for once it is recorded
+                    for (index, root) in filter.rescans(&config) {
+                        filter.scanner().scan(&config, index, &root, None, &scan_tx);
+                    }
+                },
+                pipeline::Scanned::Scan(generation, snapshot, request) => {
+                    let root = snapshot.root.clone();
+                    let jobs = filter.compare(&config, generation, snapshot);
+                    let answer = match &jobs {
+                        Some(jobs) => control::ok(format!("Rescan of {} found {} changes", root.display(), jobs.len())),
+                        None => control::error(format!("Configuration reloaded during the rescan of {}", root.display()))
+                    };
+                    for job in jobs.unwrap_or_default() {
+                        pool.submit(job).await;
+                    }
+                    if let Some(request) = request { request.reply(answer); }
+                }
+            },
+            // Directories over the watches limit are scanned instead, once
+            // there is a baseline to compare with
+            _ = scan_tick.tick(), if filter.ready() => {
+                for (index, root) in watches.retry() {
+                    filter.scanner().scan(&config, index, &root, None, &scan_tx);
+                }
+            },
+            _ = metrics_tick.tick() => {
+                info!("Events pipeline: {}", metrics.summary());
+                metrics::WATCHED_DIRS.store(watches.watched_dirs() as u64, Ordering::Relaxed);
+                status::write(&config, &status::get_status(&config, watches.status(), &metrics));
+            },
+            Some(request) = control.recv() => {
+                // Rescans answer once the walk is done
+                let mut rescan = None;
+                let answer = match &request.command {
+                    control::Command::Status => {
+                        let mut status = status::get_status(&config, watches.status(), &metrics);
+                        status["ok"] = serde_json::json!(true);
+                        status["uptime"] = serde_json::json!(started.elapsed().as_secs());
+                        status["config"] = serde_json::json!(config.path.clone());
+                        status["paused"] = serde_json::json!(filter.paused());
+                        status
+                    },
+                    control::Command::Pause(label) | control::Command::Resume(label)
+                        if ! (0..config.monitor.len()).any(|index| config.get_label(index) == *label) =>
+                        control::error(format!("No monitor entry with label {}", label)),
+                    control::Command::Pause(label) => {
+                        filter.pause(label);
+                        info!("Events of label {} paused", label);
+                        control::ok(format!("Events of label {} paused", label))
+                    },
+                    control::Command::Resume(label) => {
+                        filter.resume(label);
+                        info!("Events of label {} resumed", label);
+                        control::ok(format!("Events of label {} resumed", label))
+                    },
+                    control::Command::Rescan(path) => {
+                        match config.get_index(&path.display().to_string(), "", config.monitor.clone()) {
+                            usize::MAX => control::error(format!("{} is not monitored", path.display())),
+                            _ if ! filter.ready() => control::error(String::from("Baseline of monitored files in progress")),
+                            index => {
+                                rescan = Some((index, path.clone()));
+                                serde_json::Value::Null
+                            }
+                        }
+                    },
+                    // Monitor entries and workers are set up again, outputs keep their settings
+                    control::Command::Reload => match config::Config::load(&config.path, &config.system) {
+                        Ok(mut reloaded) => {
+                            reloaded.agent_id = config.agent_id.clone();
+                            for job in filter.drain() {
+                                pool.submit(job).await;
+                            }
+                            pool.close().await;
+                            for summary in filter.end_maintenance(&config, maintenance::now()) {
+                                lifecycle::send(&config, &events, summary).await;
+                            }
+                            drop(watches);
+                            config = reloaded;
+                            watches = setup_watches(&config, &tx, &metrics, filter.selfwatch());
+                            filter.reload(&config);
+                            let engine = rules::Engine::new(&config);
+                            filter.seed(engine.clone());
+                            filter.scanner().baseline(&config, &scan_tx);
+                            pool = pipeline::Pool::spawn(&config, &events, metrics.clone(), &detector, &vault, &engine);
+                            status::write(&config, &status::get_status(&config, watches.status(), &metrics));
+                            info!("Configuration reloaded from {}", config.path);
+                            control::ok(format!("Configuration reloaded, {} monitor entries", config.monitor.len()))
+                        },
+                        Err(e) => control::error(format!("Configuration not reloaded: {}", e))
+                    }
+                };
+                match rescan {
+                    Some((index, path)) => filter.scanner().scan(&config, index, &path, Some(request), &scan_tx),
+                    None => request.reply(answer)
+                }
+            },
+            reason = &mut stop => break reason
+        }
+    };
+
+    // Stop watching and drain the events already queued, a second signal
+    // stops right away
+    info!("Stopping, reason: {}, draining {} queued events", reason, rx.len());
+    tokio::spawn(async {
+        let reason = lifecycle::wait_signal().await;
+        error!("{} received while stopping, queued events lost", reason);
+        std::process::exit(1);
+    });
+    rx.close();
+    drop(watches);
+    control::remove(&config);
+    while let Some(event) = rx.recv().await {
+        handle_event(event, &config, &mut filter, &pool, &metrics).await;
+    }
+    for job in filter.drain() {
+        pool.submit(job).await;
     }
+    pool.close().await;
+    for summary in filter.end_maintenance(&config, maintenance::now()) {
+        lifecycle::send(&config, &events, summary).await;
+    }
+    for alert in filter.alerts(&config) {
+        lifecycle::send(&config, &events, alert).await;
+    }
+    lifecycle::record(&config, &events, lifecycle::OPERATION_STOP, reason).await;
+    events.flush().await;
+    info!("illumos File Integrity Monitor stopped");
 }
-
@@ -0,0 +1,413 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To keep the changes of a window by path
+use std::collections::BTreeMap;
+// To manage paths
+use std::path::Path;
+// To manage date and time
+use std::time::{SystemTime, UNIX_EPOCH};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+// To parse windows from the configuration
+use yaml_rust::Yaml;
+// To handle JSON objects
+use serde_json::{json, Value};
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+// To add agent metadata to summaries
+use crate::entry;
+// To generate summary ids
+use crate::utils;
+
+// Global constants definitions
+pub const OPERATION: &str = "MAINTENANCE_SUMMARY";
+// Paths listed in a summary, further changes are only counted
+const MAX_CHANGES: usize = 10000;
+
+// ----------------------------------------------------------------------------
+
+// Current time in seconds
+pub fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+// ----------------------------------------------------------------------------
+
+// Values allowed by a cron field as a bit set, supports '*', lists, ranges
+// and steps such as "*/15" or "1-5"
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| format!("invalid step '{}'", part))?),
+            None => (part, 1)
+        };
+        let (first, last) = if range == "*" { (min, max) } else {
+            match range.split_once('-') {
+                Some((first, last)) => (first.parse::<u32>().map_err(|_| format!("invalid range '{}'", part))?,
+                    last.parse::<u32>().map_err(|_| format!("invalid range '{}'", part))?),
+                None => {
+                    let value = range.parse::<u32>().map_err(|_| format!("invalid value '{}'", part))?;
+                    (value, if step > 1 { max } else { value })
+                }
+            }
+        };
+        if step == 0 || first < min || last > max || first > last {
+            return Err(format!("'{}' out of range {}-{}", part, min, max))
+        }
+        for value in (first..=last).step_by(step as usize) { bits |= 1 << value; }
+    }
+    Ok(bits)
+}
+
+// ----------------------------------------------------------------------------
+
+// Cron style schedule, minute hour day-of-month month day-of-week in UTC
+#[derive(Debug, PartialEq, Eq)]
+struct Schedule {
+    minute: u64,
+    hour: u64,
+    day: u64,
+    month: u64,
+    weekday: u64,
+    // Unrestricted day fields, as in cron a day matches if either restricted field does
+    any_day: bool,
+    any_weekday: bool
+}
+
+impl Schedule {
+    fn parse(schedule: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = schedule.split_whitespace().collect();
+        if fields.len() != 5 { return Err(format!("schedule '{}' needs 5 fields", schedule)) }
+        let mut weekday = parse_field(fields[4], 0, 7)?;
+        // Sunday is 0 or 7
+        if weekday & (1 << 7) != 0 { weekday |= 1; }
+        Ok(Schedule {
+            minute: parse_field(fields[0], 0, 59)?,
+            hour: parse_field(fields[1], 0, 23)?,
+            day: parse_field(fields[2], 1, 31)?,
+            month: parse_field(fields[3], 1, 12)?,
+            weekday,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*"
+        })
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn matches(&self, time: i64) -> bool {
+        let date = match OffsetDateTime::from_unix_timestamp(time) {
+            Ok(date) => date,
+            Err(_) => return false
+        };
+        let day = self.day & (1 << date.day()) != 0;
+        let weekday = self.weekday & (1 << date.weekday().number_days_from_sunday()) != 0;
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday
+        };
+        self.minute & (1 << date.minute()) != 0 && self.hour & (1 << date.hour()) != 0 &&
+            self.month & (1 << date.month() as u8) != 0 && day
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Times a window is active, repeated by a schedule or a single date range
+#[derive(Debug, PartialEq, Eq)]
+enum When {
+    Cron(Schedule, i64),
+    Range(i64, i64)
+}
+
+// ----------------------------------------------------------------------------
+
+// A maintenance window of some labels, their events are suppressed or
+// tagged as expected while it is active
+pub struct Window {
+    name: String,
+    labels: Vec<String>,
+    when: When,
+    suppress: bool,
+    // Start and end of the current activation in seconds
+    active: Option<(i64, i64)>,
+    changes: BTreeMap<String, Vec<String>>,
+    count: u64,
+    truncated: u64
+}
+
+impl Window {
+    fn new(entry: &Yaml, index: usize) -> Result<Self, String> {
+        let name = match entry["name"].as_str() {
+            Some(name) => String::from(name),
+            None => format!("maintenance-{}", index + 1)
+        };
+        let labels: Vec<String> = match (entry["labels"].as_vec(), entry["label"].as_str()) {
+            (Some(labels), _) => labels.iter().filter_map(|l| l.as_str().map(String::from)).collect(),
+            (None, Some(label)) => vec![String::from(label)],
+            _ => Vec::new()
+        };
+        if labels.is_empty() { return Err(format!("window {} has no labels", name)) }
+        let date = |key: &str| match entry[key].as_str() {
+            Some(value) => OffsetDateTime::parse(value, &Rfc3339).map(|d| d.unix_timestamp())
+                .map_err(|e| format!("window {} has an invalid {} '{}': {}", name, key, value, e)),
+            None => Err(format!("window {} has no {}", name, key))
+        };
+        let when = match entry["schedule"].as_str() {
+            Some(schedule) => {
+                let duration = entry["duration"].as_i64().unwrap_or(0);
+                if duration <= 0 { return Err(format!("window {} has no duration", name)) }
+                When::Cron(Schedule::parse(schedule).map_err(|e| format!("window {}: {}", name, e))?, duration * 60)
+            },
+            None => When::Range(date("start")?, date("end")?)
+        };
+        let suppress = match entry["action"].as_str() {
+            Some("suppress") => true,
+            Some("tag") | None => false,
+            Some(action) => return Err(format!("window {} has an unknown action '{}'", name, action))
+        };
+        Ok(Window { name, labels, when, suppress, active: None, changes: BTreeMap::new(), count: 0, truncated: 0 })
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Start and end of the activation covering a time, scheduled windows
+    // start on the minutes matching their schedule
+    fn get_active(&self, now: i64) -> Option<(i64, i64)> {
+        match &self.when {
+            When::Range(start, end) => if *start <= now && now < *end { Some((*start, *end)) } else { None },
+            When::Cron(schedule, duration) => {
+                let mut start = now - now.rem_euclid(60);
+                while start > now - duration {
+                    if schedule.matches(start) { return Some((start, start + duration)) }
+                    start -= 60;
+                }
+                None
+            }
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn record(&mut self, path: &Path, operation: &str) {
+        self.count += 1;
+        let path = path.display().to_string();
+        if self.changes.len() >= MAX_CHANGES && ! self.changes.contains_key(&path) {
+            self.truncated += 1;
+            return
+        }
+        let operations = self.changes.entry(path).or_default();
+        if operations.last().map(|o| o.as_str()) != Some(operation) { operations.push(String::from(operation)); }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Summary event of the changes made while the window was active
+    fn summary(&mut self, config: &config::Config, end: i64) -> Value {
+        let start = self.active.map(|(start, _)| start).unwrap_or(end);
+        let changes: Vec<Value> = std::mem::take(&mut self.changes).into_iter()
+            .map(|(path, operations)| json!({ "path": path, "operations": operations })).collect();
+        let mut obj = json!({
+            "id": utils::get_uuid(),
+            "operation": OPERATION,
+            "timestamp": entry::get_timestamp(),
+            "window": self.name.clone(),
+            "labels": self.labels.clone(),
+            "action": if self.suppress { "suppress" } else { "tag" },
+            "start": format!("{}", start * 1000),
+            "end": format!("{}", end * 1000),
+            "count": self.count,
+            "changes": changes
+        });
+        if self.truncated > 0 { obj["truncated"] = json!(self.truncated); }
+        entry::add_metadata(&mut obj, config);
+        info!("Maintenance window {} ended, {} changes", self.name, self.count);
+        self.count = 0;
+        self.truncated = 0;
+        self.active = None;
+        obj
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Maintenance windows of the configuration
+#[derive(Default)]
+pub struct Windows {
+    windows: Vec<Window>,
+    // Last minute checked, scheduled windows only start on new minutes
+    minute: i64
+}
+
+impl Windows {
+    pub fn new(config: &config::Config) -> Self {
+        let mut windows = Vec::new();
+        for (index, entry) in config.maintenance.iter().enumerate() {
+            match Window::new(entry, index) {
+                Ok(window) => windows.push(window),
+                Err(e) => error!("Maintenance window ignored, Err: [{}]", e)
+            }
+        }
+        Windows { windows, minute: -1 }
+    }
+
+    // ------------------------------------------------------------------------
+
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Start and end windows, returns the summaries of the ones that ended
+    pub fn update(&mut self, config: &config::Config, now: i64) -> Vec<Value> {
+        let new_minute = now.div_euclid(60) != self.minute;
+        self.minute = now.div_euclid(60);
+        let mut summaries = Vec::new();
+        for window in self.windows.iter_mut() {
+            let current = match window.when {
+                When::Cron(..) if ! new_minute => window.active.filter(|(_, end)| now < *end),
+                _ => window.get_active(now)
+            };
+            match (window.active, current) {
+                (None, Some(active)) => {
+                    info!("Maintenance window {} started, events of {} are {}", window.name,
+                        window.labels.join(", "), if window.suppress { "suppressed" } else { "tagged as expected" });
+                    window.active = Some(active);
+                },
+                // A new scheduled start before the end extends the window
+                (Some((start, end)), Some((_, next))) => window.active = Some((start, end.max(next))),
+                (Some(_), None) => summaries.push(window.summary(config, now)),
+                (None, None) => {}
+            }
+        }
+        summaries
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Summaries of the active windows, used when the agent stops or reloads
+    pub fn close(&mut self, config: &config::Config, now: i64) -> Vec<Value> {
+        self.windows.iter_mut().filter(|w| w.active.is_some()).map(|w| w.summary(config, now)).collect()
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Record a change of a label in its active window, returns whether the
+    // event is suppressed, None when no window covers the label
+    pub fn check(&mut self, label: &str, path: &Path, operation: &str) -> Option<bool> {
+        let window = self.windows.iter_mut()
+            .find(|w| w.active.is_some() && w.labels.iter().any(|l| l == label))?;
+        window.record(path, operation);
+        Some(window.suppress)
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+    use yaml_rust::YamlLoader;
+
+    fn create_test_windows(yaml: &str) -> (Windows, config::Config) {
+        let mut config = create_test_config("info");
+        config.maintenance = YamlLoader::load_from_str(yaml).unwrap()[0].as_vec().unwrap().to_vec();
+        (Windows::new(&config), config)
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_parse_field() {
+        assert_eq!(parse_field("*", 0, 3), Ok(0b1111));
+        assert_eq!(parse_field("1,3", 0, 5), Ok(0b1010));
+        assert_eq!(parse_field("1-3", 0, 5), Ok(0b1110));
+        assert_eq!(parse_field("*/2", 0, 5), Ok(0b10101));
+        assert_eq!(parse_field("2/2", 0, 5), Ok(0b10100));
+        assert!(parse_field("6", 0, 5).is_err());
+        assert!(parse_field("a", 0, 5).is_err());
+        assert!(parse_field("*/0", 0, 5).is_err());
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_schedule() {
+        // Saturday 2022-06-04 02:00:00 UTC
+        let saturday = 1654308000;
+        assert!(Schedule::parse("0 2 * * 6").unwrap().matches(saturday));
+        assert!(! Schedule::parse("0 2 * * 0").unwrap().matches(saturday));
+        assert!(Schedule::parse("*/30 1-3 4 6 *").unwrap().matches(saturday));
+        // Either day field matches when both are restricted
+        assert!(Schedule::parse("0 2 1 * 6").unwrap().matches(saturday));
+        assert!(! Schedule::parse("0 2 1 * 0").unwrap().matches(saturday));
+        assert!(Schedule::parse("0 2 * *").is_err());
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_new() {
+        let (windows, _) = create_test_windows("- label: bin\n  schedule: \"0 2 * * 6\"\n  duration: 60\n\
+            - labels: [bin]\n  start: 2022-06-04T02:00:00Z\n  end: 2022-06-04T03:00:00Z\n  action: suppress\n\
+            - label: bin\n  schedule: \"0 2 * * 6\"\n\
+            - schedule: \"0 2 * * 6\"\n  duration: 60\n\
+            - label: bin\n  start: yesterday\n  end: 2022-06-04T03:00:00Z\n");
+        assert_eq!(windows.windows.len(), 2);
+        assert_eq!(windows.windows[0].name, "maintenance-1");
+        assert_eq!(windows.windows[1].when, When::Range(1654308000, 1654311600));
+        assert!(windows.windows[1].suppress);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_update() {
+        let (mut windows, config) = create_test_windows("- name: patching\n  labels: [bin]\n  \
+            schedule: \"0 2 * * 6\"\n  duration: 60\n");
+        let start = 1654308000;
+        let path = Path::new("/bin/ls");
+        assert!(windows.update(&config, start - 1).is_empty());
+        assert_eq!(windows.check("bin", path, "MODIFY"), None);
+
+        // Started late, the window still covers the rest of its duration
+        assert!(windows.update(&config, start + 600).is_empty());
+        assert_eq!(windows.check("bin", path, "MODIFY"), Some(false));
+        assert_eq!(windows.check("bin", path, "MODIFY"), Some(false));
+        assert_eq!(windows.check("bin", Path::new("/bin/cp"), "REMOVE"), Some(false));
+        assert_eq!(windows.check("etc", path, "MODIFY"), None);
+
+        assert!(windows.update(&config, start + 3599).is_empty());
+        let summaries = windows.update(&config, start + 3600);
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0]["operation"], OPERATION);
+        assert_eq!(summaries[0]["window"], "patching");
+        assert_eq!(summaries[0]["start"], "1654308000000");
+        assert_eq!(summaries[0]["count"], 3);
+        assert_eq!(summaries[0]["changes"], json!([
+            { "path": "/bin/cp", "operations": ["REMOVE"] },
+            { "path": "/bin/ls", "operations": ["MODIFY"] }
+        ]));
+        assert_eq!(windows.check("bin", path, "MODIFY"), None);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_close() {
+        let (mut windows, config) = create_test_windows("- label: bin\n  start: 2022-06-04T02:00:00Z\n  \
+            end: 2022-06-04T03:00:00Z\n  action: suppress\n");
+        windows.update(&config, 1654308000);
+        assert_eq!(windows.check("bin", Path::new("/bin/ls"), "CREATE"), Some(true));
+        let summaries = windows.close(&config, 1654308060);
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0]["action"], "suppress");
+        assert_eq!(summaries[0]["end"], "1654308060000");
+        assert!(windows.close(&config, 1654308060).is_empty());
+    }
+}
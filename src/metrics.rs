@@ -0,0 +1,180 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To keep counters shared by every task
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+// To manage date and time
+use std::time::{SystemTime, UNIX_EPOCH};
+// To serve the metrics endpoint
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+// To get pipeline counters and queue depths
+use crate::pipeline;
+
+// Global constants definitions
+// Events produced, by operation and label
+static EVENTS: Mutex<BTreeMap<(String, String), u64>> = Mutex::new(BTreeMap::new());
+// Files whose checksum could not be calculated
+pub static HASH_FAILURES: AtomicU64 = AtomicU64::new(0);
+// Write errors of the events file and the database and delivery errors of syslog
+pub static FILE_ERRORS: AtomicU64 = AtomicU64::new(0);
+pub static SYSLOG_ERRORS: AtomicU64 = AtomicU64::new(0);
+pub static DATABASE_ERRORS: AtomicU64 = AtomicU64::new(0);
+// Directories watched or polled, updated by the main loop
+pub static WATCHED_DIRS: AtomicU64 = AtomicU64::new(0);
+// Time of the last event in milliseconds
+static LAST_EVENT: AtomicU64 = AtomicU64::new(0);
+
+// ----------------------------------------------------------------------------
+
+// Count an event produced
+pub fn count_event(operation: &str, label: &str) {
+    match EVENTS.lock() {
+        Ok(mut events) => *events.entry((String::from(operation), String::from(label))).or_insert(0) += 1,
+        Err(e) => error!("Events metrics could not be updated, Err: [{}]", e)
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+    LAST_EVENT.store(now, Ordering::Relaxed);
+}
+
+// ----------------------------------------------------------------------------
+
+// Escape a Prometheus label value
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// ----------------------------------------------------------------------------
+
+// Render every metric in the Prometheus text format
+pub fn render(pipeline: &pipeline::Metrics) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, values: Vec<(String, u64)>| {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+        for (labels, value) in values {
+            out.push_str(&format!("{}{} {}\n", name, labels, value));
+        }
+    };
+
+    let events = match EVENTS.lock() {
+        Ok(events) => events.iter().map(|((operation, label), count)| (format!("{{operation=\"{}\",label=\"{}\"}}",
+            escape(operation), escape(label)), *count)).collect(),
+        Err(_) => Vec::new()
+    };
+    metric("ifim_events_total", "counter", "Events produced by operation and label.", events);
+    metric("ifim_events_ignored_total", "counter", "Watcher events discarded by filters.",
+        vec![(String::new(), pipeline.events_filtered.load(Ordering::Relaxed))]);
+    metric("ifim_events_lost_total", "counter", "Watcher queue overflows.",
+        vec![(String::new(), pipeline.events_lost.load(Ordering::Relaxed))]);
+    metric("ifim_hash_failures_total", "counter", "Files whose checksum could not be calculated.",
+        vec![(String::new(), HASH_FAILURES.load(Ordering::Relaxed))]);
+    metric("ifim_output_errors_total", "counter", "Errors writing or sending events by output.", vec![
+        (String::from("{output=\"file\"}"), FILE_ERRORS.load(Ordering::Relaxed)),
+        (String::from("{output=\"syslog\"}"), SYSLOG_ERRORS.load(Ordering::Relaxed)),
+        (String::from("{output=\"database\"}"), DATABASE_ERRORS.load(Ordering::Relaxed))
+    ]);
+    metric("ifim_queue_depth", "gauge", "Items waiting in the processing queues.", vec![
+        (String::from("{queue=\"events\"}"), pipeline.events_queued.load(Ordering::Relaxed) as u64),
+        (String::from("{queue=\"jobs\"}"), pipeline.jobs_queued.load(Ordering::Relaxed) as u64)
+    ]);
+    metric("ifim_watched_directories", "gauge", "Directories watched or polled.",
+        vec![(String::new(), WATCHED_DIRS.load(Ordering::Relaxed))]);
+    metric("ifim_last_event_timestamp_seconds", "gauge", "Time of the last event produced.",
+        vec![(String::new(), LAST_EVENT.load(Ordering::Relaxed) / 1000)]);
+    out
+}
+
+// ----------------------------------------------------------------------------
+
+// Start the metrics endpoint if an address is configured
+pub async fn spawn(config: &config::Config, pipeline: Arc<pipeline::Metrics>) {
+    if config.metrics_address.is_empty() { return }
+    match TcpListener::bind(&config.metrics_address).await {
+        Ok(listener) => {
+            info!("Metrics available at http://{}/metrics", config.metrics_address);
+            tokio::spawn(serve(listener, pipeline));
+        },
+        Err(e) => error!("Metrics endpoint could not listen on {}, Err: [{}]", config.metrics_address, e)
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+async fn serve(listener: TcpListener, pipeline: Arc<pipeline::Metrics>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let pipeline = pipeline.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = respond(stream, &pipeline).await {
+                        debug!("Metrics request failed, Err: [{}]", e);
+                    }
+                });
+            },
+            Err(e) => error!("Metrics connection could not be accepted, Err: [{}]", e)
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Answer a single HTTP request, only GET /metrics is served
+async fn respond(mut stream: TcpStream, pipeline: &pipeline::Metrics) -> std::io::Result<()> {
+    let mut buffer = [0; 1024];
+    let read = stream.read(&mut buffer).await?;
+    let request = String::from_utf8_lossy(&buffer[..read]);
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(pipeline)),
+        _ => ("404 Not Found", String::from("Not found\n"))
+    };
+    let response = format!("HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+        Content-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+
+    #[test]
+    fn test_render() {
+        count_event("MODIFY", "test_render \"quoted\"");
+        let pipeline = pipeline::Metrics::default();
+        pipeline.events_filtered.fetch_add(3, Ordering::Relaxed);
+        let out = render(&pipeline);
+        assert!(out.contains("# TYPE ifim_events_total counter\n"));
+        assert!(out.contains("ifim_events_total{operation=\"MODIFY\",label=\"test_render \\\"quoted\\\"\"} 1\n"));
+        assert!(out.contains("ifim_events_ignored_total 3\n"));
+        assert!(out.contains("ifim_queue_depth{queue=\"jobs\"} 0\n"));
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn test_endpoint() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = create_test_config("info");
+        config.metrics_address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        spawn(&config, Arc::new(pipeline::Metrics::default())).await;
+
+        for (path, status) in [("/metrics", "200 OK"), ("/other", "404 Not Found")] {
+            let mut stream = TcpStream::connect(&config.metrics_address).await.unwrap();
+            stream.write_all(format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with(&format!("HTTP/1.1 {}", status)));
+        }
+    }
+}
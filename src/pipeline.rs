@@ -0,0 +1,866 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To manage paths
+use std::path::{Path, PathBuf};
+use std::fs::metadata;
+use std::os::unix::fs::FileTypeExt;
+// To pick the worker of a path and keep paused labels
+use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+// To share metrics between stages
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+// To manage date and time
+use std::time::{Duration, Instant};
+// To communicate between stages
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use notify::Event;
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+// To build events
+use crate::entry;
+// To write events to the events file
+use crate::writer;
+// To check agent files integrity
+use crate::selfwatch;
+// To merge bursts of events
+use crate::coalesce;
+// To find changes missed by the watcher
+use crate::scan;
+// To suppress or tag changes of maintenance windows
+use crate::maintenance;
+// To apply severity and action rules
+use crate::rules;
+// To detect mass modifications
+use crate::detector;
+// To measure the entropy of written files
+use crate::hash;
+// To diff modified text files
+use crate::diff;
+// To store versions of changed files
+use crate::vault;
+// To answer rescans requested through the control socket
+use crate::control;
+
+// ----------------------------------------------------------------------------
+
+// Default number of hashing workers, bounded by the available CPUs
+pub fn get_default_workers() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1).min(4)
+}
+
+// ----------------------------------------------------------------------------
+
+// Counters and queue depths of the pipeline stages
+#[derive(Default)]
+pub struct Metrics {
+    pub events_received: AtomicU64,
+    pub events_filtered: AtomicU64,
+    pub events_coalesced: AtomicU64,
+    pub jobs_processed: AtomicU64,
+    // Watcher queue overflows, each one triggers a rescan
+    pub events_lost: AtomicU64,
+    // Times a stage had to wait for a full queue
+    pub backpressure: AtomicU64,
+    // Watcher events waiting to be filtered and highest depth seen
+    pub events_queued: AtomicUsize,
+    pub events_queued_max: AtomicUsize,
+    // Jobs waiting for a worker and highest depth seen
+    pub jobs_queued: AtomicUsize,
+    pub jobs_queued_max: AtomicUsize
+}
+
+impl Metrics {
+    // Called by the watcher thread before queueing an event
+    pub fn event_queued(&self) {
+        self.events_received.fetch_add(1, Ordering::Relaxed);
+        let depth = self.events_queued.fetch_add(1, Ordering::Relaxed) + 1;
+        self.events_queued_max.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    // ------------------------------------------------------------------------
+
+    pub fn event_dequeued(&self) {
+        self.events_queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn job_queued(&self) {
+        let depth = self.jobs_queued.fetch_add(1, Ordering::Relaxed) + 1;
+        self.jobs_queued_max.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn job_done(&self) {
+        self.jobs_queued.fetch_sub(1, Ordering::Relaxed);
+        self.jobs_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // ------------------------------------------------------------------------
+
+    // One line summary for the log
+    pub fn summary(&self) -> String {
+        format!("received: {}, filtered: {}, coalesced: {}, processed: {}, lost: {}, backpressure: {}, \
+            events queue: {} (max {}), jobs queue: {} (max {})",
+            self.events_received.load(Ordering::Relaxed),
+            self.events_filtered.load(Ordering::Relaxed),
+            self.events_coalesced.load(Ordering::Relaxed),
+            self.jobs_processed.load(Ordering::Relaxed),
+            self.events_lost.load(Ordering::Relaxed),
+            self.backpressure.load(Ordering::Relaxed),
+            self.events_queued.load(Ordering::Relaxed),
+            self.events_queued_max.load(Ordering::Relaxed),
+            self.jobs_queued.load(Ordering::Relaxed),
+            self.jobs_queued_max.load(Ordering::Relaxed))
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Receive stage, watcher callback queueing its events, the watcher thread
+// blocks while the events queue is full
+pub fn get_handler(tx: mpsc::Sender<notify::Result<Event>>, metrics: Arc<Metrics>)
+    -> impl FnMut(notify::Result<Event>) + Send + 'static {
+    move |event| {
+        metrics.event_queued();
+        if tx.blocking_send(event).is_err() { metrics.event_dequeued(); }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// A filtered change waiting to be enriched and hashed
+pub struct Job {
+    pub path: PathBuf,
+    pub operation: String,
+    pub label: String,
+    // Operations merged into the event when it comes from a coalesced burst
+    pub operations: Vec<String>,
+    // Made during a maintenance window of its label
+    pub expected: bool
+}
+
+impl Job {
+    pub fn new(path: &Path, operation: String, label: String) -> Self {
+        Job { path: path.to_path_buf(), operation, label, operations: Vec::new(), expected: false }
+    }
+}
+
+impl From<coalesce::Burst> for Job {
+    fn from(burst: coalesce::Burst) -> Self {
+        Job { operation: burst.get_operation(), path: burst.path, label: burst.label, operations: burst.operations,
+            expected: false }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Walks of monitored files done out of the event loop, they are sent back to
+// it tagged with the filter generation they were started in
+pub enum Scanned {
+    // Snapshots of every monitor entry, recorded as the known state
+    Baseline(u64, Vec<scan::Snapshot>),
+    // Snapshot of a path to compare with the known state, and the control
+    // request that asked for it
+    Scan(u64, scan::Snapshot, Option<control::Request>)
+}
+
+// ----------------------------------------------------------------------------
+
+// Walks monitored files on the blocking threads
+#[derive(Clone)]
+pub struct Scanner {
+    selfwatch: selfwatch::SelfWatch,
+    vault: vault::Shared,
+    engine: rules::Engine,
+    generation: u64
+}
+
+impl Scanner {
+    // Record the state of monitored files, copies to diff the first
+    // modification of each file against, vault versions and checksums
+    // needed by rules are stored
+    pub fn walk(&self, config: &config::Config) -> Vec<scan::Snapshot> {
+        let store = diff::Store::new(config);
+        (0..config.monitor.len()).map(|index| {
+            let snapshot = scan::Snapshot::entry(config, index, &self.selfwatch);
+            let label = config.get_label(index);
+            for path in snapshot.paths() { self.engine.seed(&path.to_string_lossy(), &label); }
+            if config.get_diff(index) {
+                for path in snapshot.paths() { store.baseline(path); }
+            }
+            if config.get_vault(index) {
+                for path in snapshot.paths() { vault::baseline(&self.vault, path); }
+            }
+            snapshot
+        }).collect()
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Start the baseline of every monitor entry
+    pub fn baseline(self, config: &config::Config, tx: &mpsc::UnboundedSender<Scanned>) {
+        let (config, tx) = (config.clone(), tx.clone());
+        tokio::task::spawn_blocking(move || {
+            let _ = tx.send(Scanned::Baseline(self.generation, self.walk(&config)));
+        });
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Start the scan of a path not covered by the watcher
+    pub fn scan(self, config: &config::Config, index: usize, root: &Path, request: Option<control::Request>,
+        tx: &mpsc::UnboundedSender<Scanned>) {
+        let (config, root, tx) = (config.clone(), root.to_path_buf(), tx.clone());
+        tokio::task::spawn_blocking(move || {
+            let snapshot = scan::Snapshot::new(&config, index, &root, &self.selfwatch);
+            let _ = tx.send(Scanned::Scan(self.generation, snapshot, request));
+        });
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Filter stage, turns watcher events into jobs for the workers
+pub struct Filter {
+    selfwatch: selfwatch::SelfWatch,
+    coalescer: coalesce::Coalescer,
+    state: scan::State,
+    // Bumped on reloads so walks of the previous configuration are discarded
+    generation: u64,
+    // Whether the baseline of the current generation is recorded
+    ready: bool,
+    // Monitor entries that lost watcher events, rescanned once ready
+    lost: Vec<usize>,
+    // Labels whose changes are discarded, set through the control socket
+    paused: HashSet<String>,
+    windows: maintenance::Windows,
+    detector: detector::Shared,
+    vault: vault::Shared,
+    engine: rules::Engine,
+    metrics: Arc<Metrics>
+}
+
+impl Filter {
+    pub fn new(selfwatch: selfwatch::SelfWatch, metrics: Arc<Metrics>) -> Self {
+        Filter { selfwatch, coalescer: coalesce::Coalescer::default(), state: scan::State::default(),
+            generation: 0, ready: false, lost: Vec::new(), paused: HashSet::new(), windows: maintenance::Windows::default(), detector: detector::Shared::default(),
+            vault: vault::Shared::default(), engine: rules::Engine::default(), metrics }
+    }
+
+    // ------------------------------------------------------------------------
+
+    pub fn selfwatch(&self) -> &selfwatch::SelfWatch {
+        &self.selfwatch
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Stop reporting the changes of a label, they are still recorded in the
+    // known state so they are not reported on resume either
+    pub fn pause(&mut self, label: &str) -> bool {
+        self.paused.insert(String::from(label))
+    }
+
+    // ------------------------------------------------------------------------
+
+    pub fn resume(&mut self, label: &str) -> bool {
+        self.paused.remove(label)
+    }
+
+    // ------------------------------------------------------------------------
+
+    pub fn paused(&self) -> Vec<String> {
+        let mut paused: Vec<String> = self.paused.iter().cloned().collect();
+        paused.sort();
+        paused
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Set the maintenance windows of the configuration
+    pub fn schedule(&mut self, config: &config::Config) {
+        self.windows = maintenance::Windows::new(config);
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Start and end maintenance windows, returns the summaries of the ended ones
+    pub fn maintenance(&mut self, config: &config::Config, now: i64) -> Vec<serde_json::Value> {
+        self.windows.update(config, now)
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Summaries of the active maintenance windows, ended early
+    pub fn end_maintenance(&mut self, config: &config::Config, now: i64) -> Vec<serde_json::Value> {
+        self.windows.close(config, now)
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Share the mass modification detector of the workers, renames are only
+    // seen by the filter
+    pub fn detect(&mut self, detector: detector::Shared) {
+        self.detector = detector;
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Share the vault of the workers, files seen on baselines are stored
+    pub fn preserve(&mut self, vault: vault::Shared) {
+        self.vault = vault;
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Share the rules engine of the workers, checksums rules need are
+    // recorded on baselines
+    pub fn seed(&mut self, engine: rules::Engine) {
+        self.engine = engine;
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Mass modification alerts raised since the last call
+    pub fn alerts(&self, config: &config::Config) -> Vec<serde_json::Value> {
+        detector::lock(&self.detector).alerts(config)
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Discard the jobs of paused labels and apply maintenance windows, their
+    // jobs are discarded or tagged as expected
+    fn release(&mut self, jobs: Vec<Job>) -> Vec<Job> {
+        if self.paused.is_empty() && self.windows.is_empty() { return jobs }
+        let count = jobs.len();
+        let mut released = Vec::new();
+        for mut job in jobs {
+            if self.paused.contains(&job.label) { continue }
+            match self.windows.check(&job.label, &job.path, &job.operation) {
+                Some(true) => continue,
+                Some(false) => job.expected = true,
+                None => {}
+            }
+            released.push(job);
+        }
+        self.metrics.events_filtered.fetch_add((count - released.len()) as u64, Ordering::Relaxed);
+        released
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Walker of monitored files for the current generation
+    pub fn scanner(&self) -> Scanner {
+        Scanner { selfwatch: self.selfwatch.clone(), vault: self.vault.clone(), engine: self.engine.clone(),
+            generation: self.generation }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Whether rescans can be compared with a recorded baseline
+    pub fn ready(&self) -> bool {
+        self.ready
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Record the state of monitored files, to be compared on rescans
+    pub fn record(&mut self, generation: u64, snapshots: Vec<scan::Snapshot>) {
+        if generation != self.generation { return }
+        for snapshot in snapshots { self.state.record(snapshot); }
+        self.ready = true;
+        info!("Known state of {} monitored files recorded", self.state.len());
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Returns the jobs of a watcher event, none when it is discarded or
+    // merged into a coalesced burst
+    pub fn process(&mut self, event: Event, config: &config::Config) -> Vec<Job> {
+        if event.need_rescan() {
+            let jobs = self.lose(&event, config);
+            return self.release(jobs)
+        }
+        if let Some((path, reason)) = selfwatch::check_event(&mut self.selfwatch, &event) {
+            warn!("Agent file tampered: {} {}", path.display(), reason);
+            return vec![Job::new(&path, String::from(selfwatch::OPERATION), String::from(selfwatch::LABEL))]
+        }
+        let renamed = detector::is_rename(&event.kind);
+        match match_event(event, config, &self.selfwatch) {
+            Some((job, _)) if self.paused.contains(&job.label) => {
+                self.state.update(&job.path);
+                self.metrics.events_filtered.fetch_add(1, Ordering::Relaxed);
+                Vec::new()
+            },
+            Some((job, index)) => {
+                self.state.update(&job.path);
+                if renamed {
+                    detector::lock(&self.detector).rename(&job.label, &job.path.to_string_lossy(), Instant::now());
+                }
+                let window = config.get_coalesce(index);
+                if window > 0 {
+                    // Hashed once the burst of events on this file settles
+                    self.coalescer.push(job.path, job.label, job.operation, Duration::from_millis(window));
+                    self.metrics.events_coalesced.fetch_add(1, Ordering::Relaxed);
+                    Vec::new()
+                } else { self.release(vec![job]) }
+            },
+            None => {
+                self.metrics.events_filtered.fetch_add(1, Ordering::Relaxed);
+                Vec::new()
+            }
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // The watcher lost events, report it and queue the affected monitor
+    // entries to be rescanned, all of them if no path is given
+    fn lose(&mut self, event: &Event, config: &config::Config) -> Vec<Job> {
+        self.metrics.events_lost.fetch_add(1, Ordering::Relaxed);
+        let mut indexes: Vec<usize> = event.paths.iter()
+            .map(|p| config.get_index(&p.display().to_string(), "", config.monitor.clone()))
+            .filter(|index| *index != usize::MAX).collect();
+        if indexes.is_empty() { indexes = (0..config.monitor.len()).collect(); }
+        indexes.sort_unstable();
+        indexes.dedup();
+
+        let mut jobs = Vec::new();
+        for index in indexes {
+            let path = config.monitor[index]["path"].as_str().unwrap_or("");
+            warn!("Watcher events lost on {}, rescanning", path);
+            jobs.push(Job::new(Path::new(path), String::from(scan::OPERATION_LOST), config.get_label(index)));
+            if ! self.lost.contains(&index) { self.lost.push(index); }
+        }
+        jobs
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Monitor entries and paths to rescan after lost watcher events, kept
+    // until there is a baseline to compare them with
+    pub fn rescans(&mut self, config: &config::Config) -> Vec<(usize, PathBuf)> {
+        if ! self.ready { return Vec::new() }
+        self.lost.drain(..)
+            .map(|index| (index, PathBuf::from(config.monitor[index]["path"].as_str().unwrap_or(""))))
+            .collect()
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Compare a path not covered by the watcher with its last known state,
+    // None if it was walked before a reload
+    pub fn compare(&mut self, config: &config::Config, generation: u64, snapshot: scan::Snapshot) -> Option<Vec<Job>> {
+        if generation != self.generation { return None }
+        let jobs = self.state.compare(config, snapshot);
+        Some(self.release(jobs))
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Forget the known state with a reloaded configuration, pending bursts have
+    // to be drained before and a new baseline recorded after
+    pub fn reload(&mut self, config: &config::Config) {
+        self.state = scan::State::default();
+        self.generation += 1;
+        self.ready = false;
+        self.lost.clear();
+        *vault::lock(&self.vault) = vault::Vault::new(config);
+        self.schedule(config);
+        *detector::lock(&self.detector) = detector::Detector::new(config);
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Jobs of the bursts of events that settled
+    pub fn settled(&mut self) -> Vec<Job> {
+        let jobs = self.coalescer.settled(Instant::now()).into_iter().map(Job::from).collect();
+        self.release(jobs)
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Jobs of every pending burst, used when the agent stops
+    pub fn drain(&mut self) -> Vec<Job> {
+        let jobs = self.coalescer.drain().into_iter().map(Job::from).collect();
+        self.release(jobs)
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Match a watcher event against monitor entries, file types and ignore lists
+fn match_event(event: Event, config: &config::Config, selfwatch: &selfwatch::SelfWatch) -> Option<(Job, usize)> {
+    // Own writes to agent files are not reported as changes
+    if event.paths.iter().any(|p| selfwatch.is_watched(p)) { return None }
+    debug!("Event received: {:?}", event);
+
+    let event_path = event.paths.first()?;
+    let event_filename = event_path.file_name()?.to_str()?;
+
+    if event_path.exists() {
+        let ftype = metadata(event_path).ok()?.file_type();
+        if ftype.is_fifo() || ftype.is_block_device() || ftype.is_char_device() || ftype.is_socket() { return None }
+        if ftype.is_dir() { return None }
+    }
+
+    let index = config.get_index(event_path.to_str()?, "", config.monitor.clone().to_vec());
+    if index == usize::MAX || config.match_ignore(index, event_filename, config.monitor.clone()) { return None }
+    let operation = entry::get_operation(&event.kind);
+    Some((Job::new(event_path, operation, config.get_label(index)), index))
+}
+
+// ----------------------------------------------------------------------------
+
+// Apply the rules to an event, they can not suppress or lower tamper events
+// of agent files
+fn evaluate(engine: &rules::Engine, facts: &rules::Facts) -> rules::Verdict {
+    if facts.label == selfwatch::LABEL {
+        rules::Verdict::default()
+    } else {
+        engine.evaluate(facts)
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Enrich stage, hash the file, apply rules and send its event to the output stage
+pub async fn process(job: Job, config: &config::Config, events: &writer::Handle, engine: &mut rules::Engine,
+    detector: &detector::Shared, store: &diff::Store, vault: &vault::Shared) {
+    let operations = if job.operations.is_empty() { String::new() }
+        else { format!(" ({})", job.operations.join(",")) };
+    let exists = job.path.exists();
+    // Monitor entry of the path, its hook command runs after those of the rules
+    let index = config.get_index(&job.path.to_string_lossy(), "", config.monitor.clone());
+    if exists && job.operation != "REMOVE" && job.operation != scan::OPERATION_LOST {
+        let Job { path, operation, label, operations: merged, expected } = job;
+        let analyze = config.get_analyze(index);
+        let store = if config.get_diff(index) { Some(store.clone()) } else { None };
+        let vault = if config.get_vault(index) { Some(vault.clone()) } else { None };
+        let needs_entropy = detector::lock(detector).needs_entropy();
+        // Reading and hashing the file blocks, keep it off the async workers
+        let entry = tokio::task::spawn_blocking(move || {
+            let mut entry = entry::Entry::new(&path, operation, label);
+            entry.operations = merged;
+            entry.expected = expected;
+            if analyze { entry.analyze(); }
+            if let Some(diff) = store.and_then(|store| store.update(&entry.path, &entry.operation)) {
+                entry.diff = Some(diff.text);
+                entry.diff_truncated = diff.truncated;
+            }
+            if let Some(vault) = vault {
+                vault::store(&vault, &entry.path, Some(&entry.checksum), &entry.id, &entry.timestamp, &entry.mode);
+            }
+            let entropy = if analyze { entry.entropy }
+                else if needs_entropy { hash::get_entropy(&entry.path) } else { None };
+            (entry, entropy)
+        }).await;
+        match entry {
+            Ok((mut entry, entropy)) => {
+                detector::lock(detector).write(&entry.label, &entry.path, &entry.operation, entropy, Instant::now());
+                let checksum_changed = engine.checksum_changed(&entry.path, Some(&entry.checksum));
+                entry.type_changed = engine.type_changed(&entry.path, entry.file_type.as_deref());
+                entry.verdict = evaluate(engine, &rules::Facts {
+                    path: &entry.path,
+                    operation: &entry.operation,
+                    label: &entry.label,
+                    uid: entry.uid.parse().ok(),
+                    mode: u32::from_str_radix(&entry.mode, 8).ok(),
+                    checksum_changed,
+                    file_type: entry.file_type.as_deref(),
+                    type_changed: entry.type_changed
+                });
+                entry.verdict.exec.extend(config.get_exec(index));
+                entry.process(config.clone(), events).await;
+                info!("Changes found: {} {}{}", entry.path, entry.operation, operations);
+            },
+            Err(e) => error!("Event could not be processed, Err: [{}]", e)
+        }
+    } else {
+        // Bursts that end with the file gone are reported as a removal
+        let operation = if job.operations.is_empty() { job.operation } else { String::from("REMOVE") };
+        let mut entry = entry::Rentry::new(&job.path, operation, job.label);
+        entry.operations = job.operations;
+        entry.expected = job.expected;
+        engine.checksum_changed(&entry.path, None);
+        engine.type_changed(&entry.path, None);
+        if config.get_diff(index) { store.remove(&entry.path); }
+        if config.get_vault(index) { vault::lock(vault).remove(&entry.path, &entry.id, &entry.timestamp); }
+        entry.verdict = evaluate(engine, &rules::Facts {
+            path: &entry.path,
+            operation: &entry.operation,
+            label: &entry.label,
+            ..rules::Facts::default()
+        });
+        entry.verdict.exec.extend(config.get_exec(index));
+        entry.process(config.clone(), events).await;
+        info!("Changes found: {} {}{}", entry.path, entry.operation, operations);
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Pool of enrich workers, jobs of a path always go to the same worker so
+// its events keep their order
+pub struct Pool {
+    workers: Vec<mpsc::Sender<Job>>,
+    handles: Vec<JoinHandle<()>>,
+    metrics: Arc<Metrics>
+}
+
+impl Pool {
+    pub fn spawn(config: &config::Config, events: &writer::Handle, metrics: Arc<Metrics>,
+        detector: &detector::Shared, vault: &vault::Shared, engine: &rules::Engine) -> Self {
+        let count = config.workers.max(1);
+        let size = (config.queue_size / count).max(1);
+        let mut workers = Vec::new();
+        let mut handles = Vec::new();
+        let store = diff::Store::new(config);
+        for _ in 0..count {
+            let (tx, mut rx) = mpsc::channel::<Job>(size);
+            let config = config.clone();
+            let events = events.clone();
+            let metrics = metrics.clone();
+            let mut engine = engine.clone();
+            let detector = detector.clone();
+            let store = store.clone();
+            let vault = vault.clone();
+            handles.push(tokio::spawn(async move {
+                while let Some(job) = rx.recv().await {
+                    process(job, &config, &events, &mut engine, &detector, &store, &vault).await;
+                    metrics.job_done();
+                }
+            }));
+            workers.push(tx);
+        }
+        info!("Events pipeline started with {} workers, queue size {}", count, config.queue_size);
+        Pool { workers, handles, metrics }
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn get_worker(&self, path: &Path) -> &mpsc::Sender<Job> {
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        &self.workers[hasher.finish() as usize % self.workers.len()]
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Queue a job, waits while the queue of its worker is full
+    pub async fn submit(&self, job: Job) {
+        let worker = self.get_worker(&job.path);
+        self.metrics.job_queued();
+        let job = match worker.try_send(job) {
+            Ok(_) => return,
+            Err(mpsc::error::TrySendError::Full(job)) => job,
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                self.metrics.jobs_queued.fetch_sub(1, Ordering::Relaxed);
+                error!("Events worker is not running, event dropped");
+                return
+            }
+        };
+        self.metrics.backpressure.fetch_add(1, Ordering::Relaxed);
+        debug!("Events workers busy, waiting for a free slot");
+        if worker.send(job).await.is_err() {
+            self.metrics.jobs_queued.fetch_sub(1, Ordering::Relaxed);
+            error!("Events worker is not running, event dropped");
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Stop accepting jobs and wait until the queued ones are processed
+    pub async fn close(self) {
+        drop(self.workers);
+        for handle in self.handles {
+            if let Err(e) = handle.await { error!("Events worker failed, Err: [{}]", e); }
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+    use std::fs;
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::default();
+        metrics.event_queued();
+        metrics.event_queued();
+        metrics.event_dequeued();
+        metrics.job_queued();
+        metrics.job_done();
+        assert_eq!(metrics.events_received.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.events_queued.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.events_queued_max.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.jobs_queued.load(Ordering::Relaxed), 0);
+        assert_eq!(metrics.jobs_processed.load(Ordering::Relaxed), 1);
+        assert!(metrics.summary().contains("events queue: 1 (max 2)"));
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn test_pool() {
+        let dir = "test_pipeline_pool";
+        fs::create_dir_all(dir).unwrap();
+        let mut config = create_test_config("info");
+        config.events_file = format!("{}/events.json", dir);
+        config.workers = 2;
+        config.queue_size = 2;
+        let file = format!("{}/file.txt", dir);
+        fs::write(&file, "test").unwrap();
+
+        let metrics = Arc::new(Metrics::default());
+        let events = writer::spawn(config.clone());
+        let pool = Pool::spawn(&config, &events, metrics.clone(), &detector::Shared::default(),
+            &vault::Shared::default(), &rules::Engine::new(&config));
+        for operation in ["CREATE", "MODIFY", "REMOVE"] {
+            pool.submit(Job::new(Path::new(&file), String::from(operation), String::from("test"))).await;
+        }
+        pool.close().await;
+        events.flush().await;
+
+        let contents = fs::read_to_string(&config.events_file).unwrap();
+        let operations: Vec<String> = contents.lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap()["operation"].as_str().unwrap().to_string())
+            .collect();
+        // Events of the same path keep their order
+        assert_eq!(operations, vec!["CREATE", "MODIFY", "REMOVE"]);
+        assert!(!contents.lines().nth(2).unwrap().contains("checksum"));
+        assert_eq!(metrics.jobs_processed.load(Ordering::Relaxed), 3);
+        assert_eq!(metrics.jobs_queued.load(Ordering::Relaxed), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_filter_rescan() {
+        let dir = "test_pipeline_rescan";
+        fs::create_dir_all(dir).unwrap();
+        fs::write(format!("{}/a.txt", dir), "a").unwrap();
+        let mut config = create_test_config("info");
+        let yaml = format!("- path: {}\n  label: test\n", dir);
+        config.monitor = yaml_rust::YamlLoader::load_from_str(&yaml).unwrap()[0].as_vec().unwrap().to_vec();
+
+        let metrics = Arc::new(Metrics::default());
+        let mut filter = Filter::new(selfwatch::SelfWatch::new(&config), metrics.clone());
+        // Queue overflows carry no path, every monitor entry is rescanned
+        let overflow = Event::new(notify::EventKind::Other).set_flag(notify::event::Flag::Rescan);
+        let jobs: Vec<(String, String)> = filter.process(overflow, &config).into_iter()
+            .map(|j| (j.path.display().to_string(), j.operation)).collect();
+        assert_eq!(jobs, vec![(String::from(dir), String::from(scan::OPERATION_LOST))]);
+        assert_eq!(metrics.events_lost.load(Ordering::Relaxed), 1);
+
+        // Rescans wait for the baseline to compare with
+        assert!(filter.rescans(&config).is_empty());
+        let snapshots = filter.scanner().walk(&config);
+        filter.record(0, snapshots);
+        fs::write(format!("{}/b.txt", dir), "b").unwrap();
+        let rescans = filter.rescans(&config);
+        assert_eq!(rescans, vec![(0, PathBuf::from(dir))]);
+        assert!(filter.rescans(&config).is_empty());
+        let snapshot = scan::Snapshot::new(&config, 0, &rescans[0].1, filter.selfwatch());
+        let jobs: Vec<(String, String)> = filter.compare(&config, 0, snapshot).unwrap().into_iter()
+            .map(|j| (j.path.display().to_string(), j.operation)).collect();
+        assert_eq!(jobs, vec![(format!("{}/b.txt", dir), String::from("CREATE"))]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_filter_pause() {
+        let dir = "test_pipeline_pause";
+        fs::create_dir_all(dir).unwrap();
+        let mut config = create_test_config("info");
+        let yaml = format!("- path: {}\n  label: test\n", dir);
+        config.monitor = yaml_rust::YamlLoader::load_from_str(&yaml).unwrap()[0].as_vec().unwrap().to_vec();
+        let mut filter = Filter::new(selfwatch::SelfWatch::new(&config), Arc::new(Metrics::default()));
+        let snapshots = filter.scanner().walk(&config);
+        filter.record(0, snapshots);
+        assert!(filter.ready());
+
+        assert!(filter.pause("test"));
+        assert!(! filter.pause("test"));
+        assert_eq!(filter.paused(), vec![String::from("test")]);
+        let file = PathBuf::from(format!("{}/a.txt", dir));
+        fs::write(&file, "a").unwrap();
+        let create = Event::new(notify::EventKind::Create(notify::event::CreateKind::File)).add_path(file.clone());
+        assert!(filter.process(create.clone(), &config).is_empty());
+        let scan = |filter: &Filter| scan::Snapshot::new(&config, 0, Path::new(dir), filter.selfwatch());
+        assert_eq!(filter.compare(&config, 0, scan(&filter)).unwrap().len(), 0);
+
+        // Changes made while paused are not reported on resume
+        assert!(filter.resume("test"));
+        assert_eq!(filter.compare(&config, 0, scan(&filter)).unwrap().len(), 0);
+        // Walks started before a reload are discarded
+        filter.reload(&config);
+        assert!(! filter.ready());
+        assert!(filter.compare(&config, 0, scan(&filter)).is_none());
+        filter.record(0, Vec::new());
+        assert!(! filter.ready());
+        assert_eq!(filter.process(create, &config).len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_filter_maintenance() {
+        let dir = "test_pipeline_maintenance";
+        fs::create_dir_all(dir).unwrap();
+        let mut config = create_test_config("info");
+        let yaml = format!("- path: {}\n  label: test\n", dir);
+        config.monitor = yaml_rust::YamlLoader::load_from_str(&yaml).unwrap()[0].as_vec().unwrap().to_vec();
+        let yaml = "- label: test\n  start: 2022-06-04T02:00:00Z\n  end: 2022-06-04T03:00:00Z\n";
+        config.maintenance = yaml_rust::YamlLoader::load_from_str(yaml).unwrap()[0].as_vec().unwrap().to_vec();
+        let mut filter = Filter::new(selfwatch::SelfWatch::new(&config), Arc::new(Metrics::default()));
+        filter.schedule(&config);
+        filter.maintenance(&config, 1654308000);
+
+        let file = PathBuf::from(format!("{}/a.txt", dir));
+        fs::write(&file, "a").unwrap();
+        let create = Event::new(notify::EventKind::Create(notify::event::CreateKind::File)).add_path(file);
+        let jobs = filter.process(create.clone(), &config);
+        assert!(jobs[0].expected);
+        let summaries = filter.maintenance(&config, 1654311600);
+        assert_eq!(summaries[0]["count"], 1);
+        assert!(! filter.process(create, &config)[0].expected);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn test_process_removed_burst() {
+        let dir = "test_pipeline_burst";
+        fs::create_dir_all(dir).unwrap();
+        let mut config = create_test_config("info");
+        config.events_file = format!("{}/events.json", dir);
+        let events = writer::spawn(config.clone());
+
+        let mut job = Job::new(Path::new("/nonexistent/file"), String::from("CREATE"), String::from("test"));
+        job.operations = vec![String::from("CREATE"), String::from("REMOVE")];
+        process(job, &config, &events, &mut rules::Engine::default(), &detector::Shared::default(),
+            &diff::Store::new(&config), &vault::Shared::default()).await;
+        events.flush().await;
+        let contents = fs::read_to_string(&config.events_file).unwrap();
+        assert!(contents.contains("\"operation\":\"REMOVE\""));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,357 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To read events files
+use std::io::BufRead;
+// To manage paths
+use std::path::PathBuf;
+// To match paths
+use glob::{MatchOptions, Pattern};
+// To parse and print times
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+// To handle JSON objects
+use serde_json::Value;
+
+// To get configuration constants
+use crate::config;
+// To read rotated events files
+use crate::rotation;
+// To get the current time as in events
+use crate::entry;
+// To query the SQLite event store
+use crate::database;
+
+// Global constants definitions
+const USAGE: &str = "Usage: ifim query [--file <events file>] [--state] [--path <glob>] [--label <label>] \
+    [--operation <operation>] [--since <time>] [--until <time>] [--uid <uid>] [--checksum <checksum>] \
+    [--format table|json|csv]";
+// Columns of table and CSV outputs
+const COLUMNS: [&str; 9] = ["timestamp", "operation", "label", "path", "uid", "gid", "mode", "checksum", "id"];
+
+// ----------------------------------------------------------------------------
+
+// Milliseconds from milliseconds, an RFC3339 date or a time ago like 7d, 12h,
+// 30m, 45s or 2w
+pub fn parse_time(value: &str) -> Option<u64> {
+    if let Ok(millis) = value.parse::<u64>() { return Some(millis) }
+    if let Ok(date) = OffsetDateTime::parse(value, &Rfc3339) {
+        return Some((date.unix_timestamp_nanos() / 1_000_000).max(0) as u64)
+    }
+    let unit = match value.chars().last()? {
+        's' => 1000,
+        'm' => 60 * 1000,
+        'h' => 3600 * 1000,
+        'd' => 86400 * 1000,
+        'w' => 7 * 86400 * 1000,
+        _ => return None
+    };
+    let count = value[..value.len() - 1].parse::<u64>().ok()?;
+    let now: u64 = entry::get_timestamp().parse().unwrap_or(0);
+    Some(now.saturating_sub(count.saturating_mul(unit)))
+}
+
+// ----------------------------------------------------------------------------
+
+// Conditions on events, unset ones match every event
+#[derive(Default)]
+pub struct Filter {
+    path: Option<Pattern>,
+    labels: Vec<String>,
+    operations: Vec<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+    uid: Option<String>,
+    // Checksums are long, a prefix is enough
+    checksum: Option<String>
+}
+
+impl Filter {
+    fn matches(&self, obj: &Value) -> bool {
+        let get = |key: &str| obj[key].as_str().unwrap_or("");
+        let timestamp = get("timestamp").parse::<u64>().ok();
+        let options = MatchOptions { require_literal_separator: true, ..MatchOptions::new() };
+        self.path.as_ref().is_none_or(|p| p.matches_with(get("path"), options)) &&
+            (self.labels.is_empty() || self.labels.iter().any(|l| l == get("label"))) &&
+            (self.operations.is_empty() || self.operations.iter().any(|o| o.eq_ignore_ascii_case(get("operation")))) &&
+            self.since.is_none_or(|since| timestamp.is_some_and(|t| t >= since)) &&
+            self.until.is_none_or(|until| timestamp.is_some_and(|t| t <= until)) &&
+            self.uid.as_ref().is_none_or(|uid| uid == get("uid")) &&
+            self.checksum.as_ref().is_none_or(|checksum| get("checksum").starts_with(checksum.as_str()))
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Events file and its rotated files, oldest first so events come in order
+fn get_files(file: &str) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = (1..).map_while(|index| rotation::get_rotated(file, index)).collect();
+    files.reverse();
+    let current = PathBuf::from(file);
+    if current.exists() { files.push(current); }
+    files
+}
+
+// ----------------------------------------------------------------------------
+
+// Events matching a filter, with the number of lines that are not JSON events
+pub fn search(file: &str, filter: &Filter) -> (Vec<Value>, usize) {
+    let mut events = Vec::new();
+    let mut skipped = 0;
+    for path in get_files(file) {
+        let reader = match rotation::open_reader(&path) {
+            Ok(reader) => reader,
+            Err(e) => { eprintln!("{} could not be read: {}", path.display(), e); continue }
+        };
+        for line in reader.lines().map_while(Result::ok) {
+            match serde_json::from_str::<Value>(&line) {
+                Ok(obj) if obj.is_object() => if filter.matches(&obj) { events.push(obj) },
+                _ => skipped += 1
+            }
+        }
+    }
+    (events, skipped)
+}
+
+// ----------------------------------------------------------------------------
+
+// Literal start of a path glob, narrows the rows read from the database
+fn get_prefix(pattern: &str) -> &str {
+    &pattern[..pattern.find(['*', '?', '[']).unwrap_or(pattern.len())]
+}
+
+// ----------------------------------------------------------------------------
+
+// Events matching a filter from the database, or the current state of the
+// files matching it
+pub fn search_database(path: &str, filter: &Filter, state: bool) -> Result<Vec<Value>, String> {
+    let database = database::Database::open_read(path)
+        .map_err(|e| format!("Database {} could not be opened: {}", path, e))?;
+    let prefix = filter.path.as_ref().map(|p| get_prefix(p.as_str())).unwrap_or("");
+    // Operations are stored in upper case, the indexed columns narrow the rows read
+    let operations: Vec<String> = filter.operations.iter().map(|o| o.to_uppercase()).collect();
+    let events = if state { database.files(prefix, &filter.labels, &operations) }
+        else { database.events(prefix, filter.since, filter.until, &filter.labels, &operations) };
+    let events = events.map_err(|e| format!("Database {} could not be read: {}", path, e))?;
+    Ok(events.into_iter().filter(|obj| filter.matches(obj)).collect())
+}
+
+// ----------------------------------------------------------------------------
+
+fn get_column(obj: &Value, column: &str) -> String {
+    match &obj[column] {
+        Value::String(s) if column == "timestamp" => s.parse::<i128>().ok()
+            .and_then(|millis| OffsetDateTime::from_unix_timestamp_nanos(millis * 1_000_000).ok())
+            .and_then(|date| date.format(&Rfc3339).ok())
+            .unwrap_or_else(|| s.clone()),
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        value => value.to_string()
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Quote CSV fields holding separators, quotes or line breaks
+fn escape_csv(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else { String::from(value) }
+}
+
+// ----------------------------------------------------------------------------
+
+// Render events as an aligned table, one JSON event per line or CSV
+pub fn render(events: &[Value], format: &str) -> String {
+    match format {
+        "json" => events.iter().map(|obj| format!("{}\n", obj)).collect(),
+        "csv" => {
+            let mut out = format!("{}\n", COLUMNS.join(","));
+            for obj in events {
+                let row: Vec<String> = COLUMNS.iter().map(|c| escape_csv(&get_column(obj, c))).collect();
+                out.push_str(&format!("{}\n", row.join(",")));
+            }
+            out
+        },
+        _ => {
+            // The table is meant to be read, checksums and ids are left out
+            let columns = &COLUMNS[..7];
+            let rows: Vec<Vec<String>> = events.iter()
+                .map(|obj| columns.iter().map(|c| get_column(obj, c)).collect()).collect();
+            let widths: Vec<usize> = columns.iter().enumerate()
+                .map(|(i, c)| rows.iter().map(|row| row[i].chars().count()).fold(c.len(), usize::max)).collect();
+            let line = |cells: Vec<String>| -> String {
+                let cells: Vec<String> = cells.iter().zip(&widths).map(|(cell, width)| format!("{:<1$}", cell, width)).collect();
+                format!("{}\n", cells.join("  ").trim_end())
+            };
+            let mut out = line(columns.iter().map(|c| c.to_uppercase()).collect());
+            for row in rows { out.push_str(&line(row)); }
+            out
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Command line entry point of `ifim query`
+pub fn query_command(config: &config::Config, args: &[String]) -> i32 {
+    let mut file = None;
+    let mut state = false;
+    let mut filter = Filter::default();
+    let mut format = String::from("table");
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--state" {
+            state = true;
+            continue
+        }
+        let value = match iter.next() {
+            Some(value) if arg.starts_with("--") => value,
+            _ => { eprintln!("{}", USAGE); return 2 }
+        };
+        match arg.as_str() {
+            "--file" => file = Some(value.clone()),
+            "--path" => match Pattern::new(value) {
+                Ok(pattern) => filter.path = Some(pattern),
+                Err(e) => { eprintln!("Invalid path glob {}: {}", value, e); return 2 }
+            },
+            "--label" => filter.labels.push(value.clone()),
+            "--operation" => filter.operations.push(value.clone()),
+            "--since" | "--until" => match parse_time(value) {
+                Some(time) if arg == "--since" => filter.since = Some(time),
+                Some(time) => filter.until = Some(time),
+                None => { eprintln!("Invalid time {}", value); return 2 }
+            },
+            "--uid" => filter.uid = Some(value.clone()),
+            "--checksum" => filter.checksum = Some(value.to_lowercase()),
+            "--format" if ["table", "json", "csv"].contains(&value.as_str()) => format = value.clone(),
+            _ => { eprintln!("{}", USAGE); return 2 }
+        }
+    }
+
+    // The database is read when configured, unless an events file is given
+    let events = match file {
+        None if ! config.database_path.is_empty() => match search_database(&config.database_path, &filter, state) {
+            Ok(events) => events,
+            Err(e) => { eprintln!("{}", e); return 2 }
+        },
+        _ if state => { eprintln!("--state needs a database, set database: path"); return 2 },
+        file => {
+            let file = file.unwrap_or_else(|| config.events_file.clone());
+            let (events, skipped) = search(&file, &filter);
+            // Only JSON events can be queried, CEF and LEEF lines are counted
+            if skipped > 0 { eprintln!("{} lines of {} are not JSON events and were skipped", skipped, file); }
+            events
+        }
+    };
+    print!("{}", render(&events, &format));
+    if events.is_empty() { 1 } else { 0 }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+    use serde_json::json;
+
+    fn create_test_event(path: &str, operation: &str, timestamp: &str, uid: &str) -> Value {
+        json!({ "id": format!("id-{}", timestamp), "path": path, "operation": operation, "timestamp": timestamp,
+            "label": "etc", "uid": uid, "gid": "0", "mode": "100644", "checksum": "ab12cd" })
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("1654041600000"), Some(1654041600000));
+        assert_eq!(parse_time("2022-06-01T00:00:00Z"), Some(1654041600000));
+        let now: u64 = entry::get_timestamp().parse().unwrap();
+        let week = parse_time("1w").unwrap();
+        assert!(week <= now - 7 * 86400 * 1000 && week > now - 7 * 86400 * 1000 - 60000);
+        assert_eq!(parse_time("7x"), None);
+        assert_eq!(parse_time("d"), None);
+        assert_eq!(parse_time("yesterday"), None);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_filter() {
+        let obj = create_test_event("/etc/ssh/sshd_config", "MODIFY", "2000", "0");
+        assert!(Filter::default().matches(&obj));
+        let filter = |f: fn(&mut Filter)| { let mut filter = Filter::default(); f(&mut filter); filter.matches(&obj) };
+        assert!(filter(|f| f.path = Some(Pattern::new("/etc/ssh/*").unwrap())));
+        assert!(! filter(|f| f.path = Some(Pattern::new("/etc/*").unwrap())));
+        assert!(filter(|f| f.path = Some(Pattern::new("/etc/**").unwrap())));
+        assert!(filter(|f| f.operations = vec![String::from("create"), String::from("modify")]));
+        assert!(! filter(|f| f.labels = vec![String::from("bin")]));
+        assert!(filter(|f| { f.since = Some(2000); f.until = Some(2000) }));
+        assert!(! filter(|f| f.since = Some(2001)));
+        assert!(! filter(|f| f.uid = Some(String::from("1000"))));
+        assert!(filter(|f| f.checksum = Some(String::from("ab1"))));
+        assert!(! filter(|f| f.checksum = Some(String::from("cd"))));
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_search() {
+        let file = "test_query_search.json";
+        let rotated = format!("{}.1.gz", file);
+        let mut encoder = flate2::write::GzEncoder::new(fs::File::create(&rotated).unwrap(),
+            flate2::Compression::default());
+        writeln!(encoder, "{}", create_test_event("/etc/hosts", "MODIFY", "1000", "0")).unwrap();
+        encoder.finish().unwrap();
+        fs::write(file, format!("{}\nCEF:0|illumos|ifim\n{}\n",
+            create_test_event("/etc/passwd", "CREATE", "2000", "0"),
+            create_test_event("/etc/shadow", "REMOVE", "3000", "1000"))).unwrap();
+
+        let filter = Filter { uid: Some(String::from("0")), ..Filter::default() };
+        let (events, skipped) = search(file, &filter);
+        assert_eq!(skipped, 1);
+        assert_eq!(events.iter().map(|e| e["path"].as_str().unwrap()).collect::<Vec<_>>(), vec!["/etc/hosts", "/etc/passwd"]);
+        fs::remove_file(file).unwrap();
+        fs::remove_file(rotated).unwrap();
+        assert_eq!(search(file, &filter).0.len(), 0);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_search_database() {
+        assert_eq!(get_prefix("/etc/ssh/*"), "/etc/ssh/");
+        assert_eq!(get_prefix("/etc/[ab]*"), "/etc/");
+        let dir = "test_query_database";
+        let path = format!("{}/events.db", dir);
+        let mut database = database::Database::open(&path).unwrap();
+        database.push(create_test_event("/etc/ssh/sshd_config", "CREATE", "1000", "0"), 5);
+        database.push(create_test_event("/etc/ssh/sshd_config", "MODIFY", "2000", "1000"), 5);
+        database.push(create_test_event("/etc/ssh2/config", "MODIFY", "3000", "0"), 5);
+        database.commit();
+
+        let filter = Filter { path: Some(Pattern::new("/etc/ssh/*").unwrap()), ..Filter::default() };
+        let events = search_database(&path, &filter, false).unwrap();
+        assert_eq!(events.iter().map(|e| e["timestamp"].as_str().unwrap()).collect::<Vec<_>>(), vec!["1000", "2000"]);
+        let filter = Filter { uid: Some(String::from("0")), ..Filter::default() };
+        let files = search_database(&path, &filter, true).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0]["path"], "/etc/ssh2/config");
+        assert!(search_database("test_query_missing.db", &filter, false).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_render() {
+        let mut obj = create_test_event("/etc/a,b", "MODIFY", "1654041600000", "0");
+        assert_eq!(render(std::slice::from_ref(&obj), "json"), format!("{}\n", obj));
+        assert_eq!(render(std::slice::from_ref(&obj), "csv"), "timestamp,operation,label,path,uid,gid,mode,checksum,id\n\
+            2022-06-01T00:00:00Z,MODIFY,etc,\"/etc/a,b\",0,0,100644,ab12cd,id-1654041600000\n");
+        obj["uid"] = Value::Null;
+        assert_eq!(render(&[obj], "table"), "TIMESTAMP             OPERATION  LABEL  PATH      UID  GID  MODE\n\
+            2022-06-01T00:00:00Z  MODIFY     etc    /etc/a,b       0    100644\n");
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To handle files
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+// To manage paths
+use std::path::{Path, PathBuf};
+// To manage date and time
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+// To share the last rotation time of each file
+use std::sync::Mutex;
+// To compress rotated files
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+// To get configuration constants
+use crate::config;
+
+// Global constants definitions
+const EXTENSIONS: [&str; 3] = ["", ".gz", ".zst"];
+// Seconds after a rotation or truncation where changes of the events file are expected
+const ROTATION_GRACE: u64 = 10;
+
+// Files rotated by the agent and time of their last rotation, in seconds since epoch
+static ROTATIONS: Mutex<Vec<(PathBuf, u64)>> = Mutex::new(Vec::new());
+// Files the agent cut back to their last flushed line after a write failure
+static TRUNCATIONS: Mutex<Vec<(PathBuf, u64)>> = Mutex::new(Vec::new());
+
+// ----------------------------------------------------------------------------
+
+fn get_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// ----------------------------------------------------------------------------
+
+// Get the absolute path of a file that may have been moved
+fn get_key(file: &Path) -> PathBuf {
+    match (file.parent(), file.file_name()) {
+        (Some(parent), Some(name)) => fs::canonicalize(if parent.as_os_str().is_empty() { Path::new(".") } else { parent })
+            .map(|parent| parent.join(name)).unwrap_or_else(|_| file.to_path_buf()),
+        _ => file.to_path_buf()
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+fn is_recent(changes: &Mutex<Vec<(PathBuf, u64)>>, file: &Path) -> bool {
+    let key = get_key(file);
+    let changes = changes.lock().unwrap_or_else(|e| e.into_inner());
+    changes.iter().any(|(path, time)| *path == key && get_now().saturating_sub(*time) <= ROTATION_GRACE)
+}
+
+// ----------------------------------------------------------------------------
+
+fn mark(changes: &Mutex<Vec<(PathBuf, u64)>>, file: &Path) {
+    let key = get_key(file);
+    let mut changes = changes.lock().unwrap_or_else(|e| e.into_inner());
+    changes.retain(|(path, _)| *path != key);
+    changes.push((key, get_now()));
+}
+
+// ----------------------------------------------------------------------------
+
+// Returns if the agent rotated a file in the last seconds
+pub fn recently_rotated(file: &Path) -> bool {
+    is_recent(&ROTATIONS, file)
+}
+
+// ----------------------------------------------------------------------------
+
+// Record a rotation of a file done by the agent
+fn mark_rotated(file: &Path) {
+    mark(&ROTATIONS, file)
+}
+
+// ----------------------------------------------------------------------------
+
+// Returns if the agent truncated a file in the last seconds
+pub fn recently_truncated(file: &Path) -> bool {
+    is_recent(&TRUNCATIONS, file)
+}
+
+// ----------------------------------------------------------------------------
+
+// Record a truncation of a file done by the agent, before doing it
+pub fn mark_truncated(file: &Path) {
+    mark(&TRUNCATIONS, file)
+}
+
+// ----------------------------------------------------------------------------
+
+// Get the extension of rotated files for a compression setting
+pub fn get_extension(compress: &str) -> &'static str {
+    match compress {
+        "gzip" | "gz" => ".gz",
+        "zstd" | "zst" => ".zst",
+        _ => ""
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Find the rotated file with the given index, whatever its compression
+pub fn get_rotated(file: &str, index: usize) -> Option<PathBuf> {
+    EXTENSIONS.iter()
+        .map(|ext| PathBuf::from(format!("{}.{}{}", file, index, ext)))
+        .find(|path| path.exists())
+}
+
+// ----------------------------------------------------------------------------
+
+// Open a plain, gzip or zstd events file for reading
+pub fn open_reader(path: &Path) -> io::Result<Box<dyn io::BufRead>> {
+    let file = File::open(path)?;
+    let name = path.to_string_lossy();
+    if name.ends_with(".gz") {
+        Ok(Box::new(BufReader::new(flate2::read::GzDecoder::new(file))))
+    } else if name.ends_with(".zst") {
+        Ok(Box::new(BufReader::new(zstd::stream::read::Decoder::new(file)?)))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Compress a rotated file and remove the uncompressed copy, it can take a
+// while on big files and is run out of the writer task
+pub fn compress(path: &Path, compress: &str) -> io::Result<PathBuf> {
+    let target = PathBuf::from(format!("{}{}", path.display(), get_extension(compress)));
+    let mut input = File::open(path)?;
+    let output = BufWriter::new(File::create(&target)?);
+    match compress {
+        "gzip" | "gz" => {
+            let mut encoder = GzEncoder::new(output, Compression::default());
+            io::copy(&mut input, &mut encoder)?;
+            encoder.finish()?;
+        },
+        _ => {
+            let mut encoder = zstd::stream::write::Encoder::new(output, 0)?;
+            io::copy(&mut input, &mut encoder)?;
+            encoder.finish()?;
+        }
+    }
+    fs::remove_file(path)?;
+    Ok(target)
+}
+
+// ----------------------------------------------------------------------------
+
+// Move the events file to the first rotated file, keeping at most `keep`
+// rotated files, returns the rotated file if there was one to move
+pub fn rotate(file: &str, keep: usize) -> io::Result<Option<PathBuf>> {
+    if ! Path::new(file).exists() { return Ok(None) }
+
+    // Drop files over the retention limit, then shift the rest up by one
+    let mut index = keep.max(1);
+    while let Some(path) = get_rotated(file, index) {
+        fs::remove_file(path)?;
+        index += 1;
+    }
+    for index in (1..keep.max(1)).rev() {
+        if let Some(path) = get_rotated(file, index) {
+            let ext = path.to_string_lossy().replacen(&format!("{}.{}", file, index), "", 1);
+            fs::rename(&path, format!("{}.{}{}", file, index + 1, ext))?;
+        }
+    }
+
+    let rotated = PathBuf::from(format!("{}.1", file));
+    mark_rotated(Path::new(file));
+    fs::rename(file, &rotated)?;
+    Ok(Some(rotated))
+}
+
+// ----------------------------------------------------------------------------
+
+// Returns if the events file reached the configured size or age
+pub fn needs_rotation(config: &config::Config, started: SystemTime) -> bool {
+    let size = match fs::metadata(&config.events_file) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return false
+    };
+    if size == 0 { return false }
+    if config.rotation_size > 0 && size >= config.rotation_size { return true }
+
+    if config.rotation_interval > 0 {
+        // Last rotation time is the one of the newest rotated file
+        let last = get_rotated(&config.events_file, 1)
+            .and_then(|path| fs::metadata(path).ok())
+            .and_then(|metadata| metadata.modified().ok())
+            .unwrap_or(started);
+        let age = SystemTime::now().duration_since(last).unwrap_or_default();
+        return age >= Duration::from_secs(config.rotation_interval)
+    }
+    false
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn create_test_file(filename: &str, contents: &str) {
+        fs::write(filename, contents).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_get_extension() {
+        assert_eq!(get_extension("gzip"), ".gz");
+        assert_eq!(get_extension("zstd"), ".zst");
+        assert_eq!(get_extension("none"), "");
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_rotate_keep() {
+        let dir = "test_rotate_keep";
+        let file = format!("{}/events.json", dir);
+        fs::create_dir_all(dir).unwrap();
+        for i in 0..4 {
+            create_test_file(&file, &format!("{}\n", i));
+            rotate(&file, 2).unwrap();
+        }
+        assert!(!Path::new(&file).exists());
+        assert_eq!(fs::read_to_string(format!("{}.1", file)).unwrap(), "3\n");
+        assert_eq!(fs::read_to_string(format!("{}.2", file)).unwrap(), "2\n");
+        assert!(!Path::new(&format!("{}.3", file)).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_rotate_compress() {
+        let dir = "test_rotate_compress";
+        let file = format!("{}/events.json", dir);
+        fs::create_dir_all(dir).unwrap();
+        create_test_file(&file, "first\n");
+        compress(&rotate(&file, 3).unwrap().unwrap(), "gzip").unwrap();
+        create_test_file(&file, "second\n");
+        compress(&rotate(&file, 3).unwrap().unwrap(), "zstd").unwrap();
+
+        let mut first = String::new();
+        let gz = File::open(format!("{}.2.gz", file)).unwrap();
+        flate2::read::GzDecoder::new(gz).read_to_string(&mut first).unwrap();
+        assert_eq!(first, "first\n");
+        let zst = File::open(format!("{}.1.zst", file)).unwrap();
+        let second = String::from_utf8(zstd::stream::decode_all(zst).unwrap()).unwrap();
+        assert_eq!(second, "second\n");
+        assert!(!Path::new(&format!("{}.1", file)).exists());
+
+        let mut data = String::new();
+        open_reader(Path::new(&format!("{}.2.gz", file))).unwrap().read_to_string(&mut data).unwrap();
+        assert_eq!(data, "first\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
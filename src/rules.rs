@@ -0,0 +1,382 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To keep the last checksum and type of files
+use std::collections::HashMap;
+// To share the checksums recorded on baselines between workers
+use std::sync::{Arc, Mutex};
+// To match paths
+use glob::{MatchOptions, Pattern};
+// To parse rules from the configuration
+use yaml_rust::Yaml;
+// To handle JSON objects
+use serde_json::{json, Value};
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+// To hash files recorded on baselines
+use crate::hash;
+
+// Global constants definitions
+pub const ACTION_LOG: &str = "log";
+pub const ACTION_FORWARD: &str = "forward";
+pub const ACTION_EXEC: &str = "exec";
+// Actions of events when no matching rule sets any, exec has to be listed
+pub const DEFAULT_ACTIONS: [&str; 2] = [ACTION_LOG, ACTION_FORWARD];
+
+// ----------------------------------------------------------------------------
+
+// Strings of a value that can be a single string or a list of them
+fn get_list(value: &Yaml) -> Vec<String> {
+    match value {
+        Yaml::String(value) => vec![value.clone()],
+        Yaml::Array(values) => values.iter().filter_map(|v| v.as_str().map(String::from)).collect(),
+        _ => Vec::new()
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Attributes of an event that rules match on, unknown ones never match
+#[derive(Default)]
+pub struct Facts<'a> {
+    pub path: &'a str,
+    pub operation: &'a str,
+    pub label: &'a str,
+    pub uid: Option<u32>,
+    pub mode: Option<u32>,
+    pub checksum_changed: Option<bool>,
+    pub file_type: Option<&'a str>,
+    pub type_changed: Option<bool>
+}
+
+// ----------------------------------------------------------------------------
+
+// Outcome of the rules matching an event
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct Verdict {
+    pub rules: Vec<String>,
+    pub severity: Option<u8>,
+    pub tags: Vec<String>,
+    // Empty when no matching rule sets actions, DEFAULT_ACTIONS then apply
+    pub actions: Vec<String>,
+    pub exec: Vec<String>
+}
+
+impl Verdict {
+    pub fn has_action(&self, action: &str) -> bool {
+        if self.actions.is_empty() { DEFAULT_ACTIONS.contains(&action) } else { self.actions.iter().any(|a| a == action) }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Add the matching rules, their severity and tags to an event object
+    pub fn apply(&self, obj: &mut Value) {
+        if self.rules.is_empty() { return }
+        obj["rules"] = json!(self.rules.clone());
+        if let Some(severity) = self.severity { obj["severity"] = json!(severity); }
+        if ! self.tags.is_empty() { obj["tags"] = json!(self.tags.clone()); }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// A rule of the configuration, every condition set has to match
+#[derive(Clone)]
+struct Rule {
+    name: String,
+    path: Option<Pattern>,
+    operations: Vec<String>,
+    labels: Vec<String>,
+    uid: Option<u32>,
+    // Permission bits that have to be set, such as 0o4000 for setuid files
+    mode: Option<u32>,
+    checksum_changed: Option<bool>,
+    file_types: Vec<String>,
+    type_changed: Option<bool>,
+    severity: Option<u8>,
+    tags: Vec<String>,
+    actions: Vec<String>,
+    exec: String
+}
+
+impl Rule {
+    fn new(entry: &Yaml, index: usize) -> Result<Self, String> {
+        let name = match entry["name"].as_str() {
+            Some(name) => String::from(name),
+            None => format!("rule-{}", index + 1)
+        };
+        let path = match entry["path"].as_str() {
+            Some(path) => Some(Pattern::new(path).map_err(|e| format!("rule {} has an invalid path: {}", name, e))?),
+            None => None
+        };
+        let mode = match &entry["mode"] {
+            Yaml::String(mode) => Some(u32::from_str_radix(mode, 8)
+                .map_err(|_| format!("rule {} has an invalid octal mode '{}'", name, mode))?),
+            Yaml::Integer(mode) => Some(u32::from_str_radix(&mode.to_string(), 8)
+                .map_err(|_| format!("rule {} has an invalid octal mode '{}'", name, mode))?),
+            _ => None
+        };
+        let actions = get_list(&entry["actions"]);
+        if let Some(action) = actions.iter().find(|a| ! [ACTION_LOG, ACTION_FORWARD, ACTION_EXEC].contains(&a.as_str())) {
+            return Err(format!("rule {} has an unknown action '{}'", name, action))
+        }
+        let exec = String::from(entry["exec"].as_str().unwrap_or(""));
+        if actions.iter().any(|a| a == ACTION_EXEC) && exec.is_empty() {
+            return Err(format!("rule {} has the exec action but no exec command", name))
+        }
+        Ok(Rule {
+            name,
+            path,
+            operations: get_list(&entry["operation"]),
+            labels: get_list(&entry["label"]),
+            uid: entry["uid"].as_i64().map(|uid| uid as u32),
+            mode,
+            checksum_changed: entry["checksum_changed"].as_bool(),
+            file_types: get_list(&entry["file_type"]),
+            type_changed: entry["type_changed"].as_bool(),
+            severity: entry["severity"].as_i64().map(|s| s.clamp(0, 10) as u8),
+            tags: get_list(&entry["tags"]),
+            actions,
+            exec
+        })
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Whether the rule can match events of a file of a label
+    fn matches_file(&self, path: &str, label: &str) -> bool {
+        let options = MatchOptions { require_literal_separator: true, ..MatchOptions::new() };
+        self.path.as_ref().is_none_or(|p| p.matches_with(path, options)) &&
+            (self.labels.is_empty() || self.labels.iter().any(|l| l == label))
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn matches(&self, facts: &Facts) -> bool {
+        self.matches_file(facts.path, facts.label) &&
+            (self.operations.is_empty() || self.operations.iter().any(|o| o == facts.operation)) &&
+            self.uid.is_none_or(|uid| facts.uid == Some(uid)) &&
+            self.mode.is_none_or(|mode| facts.mode.is_some_and(|m| m & mode == mode)) &&
+            self.checksum_changed.is_none_or(|changed| facts.checksum_changed == Some(changed)) &&
+            (self.file_types.is_empty() || facts.file_type.is_some_and(|t| self.file_types.iter().any(|f| f == t))) &&
+            self.type_changed.is_none_or(|changed| facts.type_changed == Some(changed))
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Rules of the configuration with the last checksum and type of the files
+// seen, each worker has its own as a path is always handled by the same worker
+#[derive(Default, Clone)]
+pub struct Engine {
+    rules: Vec<Rule>,
+    checksums: HashMap<String, String>,
+    // Checksums recorded on the baseline, shared by the workers and taken the
+    // first time each file is seen
+    seeds: Arc<Mutex<HashMap<String, String>>>,
+    // Only filled for files whose contents are analyzed
+    types: HashMap<String, String>,
+    // Checksums are only kept if some rule needs them
+    track: bool
+}
+
+impl Engine {
+    pub fn new(config: &config::Config) -> Self {
+        let mut rules = Vec::new();
+        for (index, entry) in config.rules.iter().enumerate() {
+            match Rule::new(entry, index) {
+                Ok(rule) => rules.push(rule),
+                Err(e) => error!("Rule ignored, Err: [{}]", e)
+            }
+        }
+        let track = rules.iter().any(|r| r.checksum_changed.is_some());
+        Engine { rules, checksums: HashMap::new(), seeds: Arc::default(), types: HashMap::new(), track }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Record the checksum of a file seen on a baseline when a rule needs it,
+    // so the first change of the file after a start is known
+    pub fn seed(&self, path: &str, label: &str) {
+        if ! self.track || ! self.rules.iter().any(|r| r.checksum_changed.is_some() && r.matches_file(path, label)) {
+            return
+        }
+        let checksum = hash::get_checksum(String::from(path));
+        if checksum != "UNKNOWN" {
+            self.seeds.lock().unwrap_or_else(|e| e.into_inner()).insert(String::from(path), checksum);
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Whether the checksum of a file differs from the last one seen or the one
+    // recorded on the baseline, unknown for files created since. None for
+    // removed files
+    pub fn checksum_changed(&mut self, path: &str, checksum: Option<&str>) -> Option<bool> {
+        if ! self.track { return None }
+        let seed = self.seeds.lock().unwrap_or_else(|e| e.into_inner()).remove(path);
+        match checksum {
+            Some(checksum) => self.checksums.insert(String::from(path), String::from(checksum))
+                .or(seed).map(|last| last != checksum),
+            None => { self.checksums.remove(path); None }
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Whether the type of a file differs from the last one seen, unknown for
+    // files seen for the first time. None for removed or not analyzed files
+    pub fn type_changed(&mut self, path: &str, file_type: Option<&str>) -> Option<bool> {
+        match file_type {
+            Some(file_type) => self.types.insert(String::from(path), String::from(file_type))
+                .map(|last| last != file_type),
+            None => { self.types.remove(path); None }
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Combine every matching rule, the highest severity wins
+    pub fn evaluate(&self, facts: &Facts) -> Verdict {
+        let mut verdict = Verdict::default();
+        for rule in self.rules.iter().filter(|r| r.matches(facts)) {
+            verdict.rules.push(rule.name.clone());
+            verdict.severity = verdict.severity.max(rule.severity);
+            for tag in &rule.tags {
+                if ! verdict.tags.contains(tag) { verdict.tags.push(tag.clone()); }
+            }
+            for action in &rule.actions {
+                if ! verdict.actions.contains(action) { verdict.actions.push(action.clone()); }
+            }
+            if rule.actions.iter().any(|a| a == ACTION_EXEC) { verdict.exec.push(rule.exec.clone()); }
+        }
+        verdict
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+    use yaml_rust::YamlLoader;
+
+    fn create_test_engine(yaml: &str) -> Engine {
+        let mut config = create_test_config("info");
+        config.rules = YamlLoader::load_from_str(yaml).unwrap()[0].as_vec().unwrap().to_vec();
+        Engine::new(&config)
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_new() {
+        let engine = create_test_engine("- path: /usr/bin/*\n  mode: \"4000\"\n\
+            - name: bad\n  path: \"[\"\n\
+            - actions: [page]\n\
+            - actions: [exec]\n\
+            - mode: 4755\n");
+        assert_eq!(engine.rules.len(), 2);
+        assert_eq!(engine.rules[0].name, "rule-1");
+        assert_eq!(engine.rules[0].mode, Some(0o4000));
+        assert_eq!(engine.rules[1].mode, Some(0o4755));
+        assert!(! engine.track);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_evaluate() {
+        let engine = create_test_engine("- name: setuid\n  path: /usr/bin/*\n  mode: \"4000\"\n  severity: 9\n  \
+            tags: [setuid]\n  actions: [log, exec]\n  exec: /bin/true\n\
+            - name: root\n  operation: [CREATE, MODIFY]\n  label: bin\n  uid: 0\n  severity: 6\n  tags: root\n");
+        let mut facts = Facts { path: "/usr/bin/su", operation: "MODIFY", label: "bin", uid: Some(0),
+            mode: Some(0o104755), ..Facts::default() };
+        let verdict = engine.evaluate(&facts);
+        assert_eq!(verdict.rules, vec!["setuid", "root"]);
+        assert_eq!(verdict.severity, Some(9));
+        assert_eq!(verdict.tags, vec!["setuid", "root"]);
+        assert!(verdict.has_action(ACTION_LOG));
+        assert!(! verdict.has_action(ACTION_FORWARD));
+        assert_eq!(verdict.exec, vec!["/bin/true"]);
+
+        // Globs do not cross directories and unknown attributes do not match
+        facts.path = "/usr/bin/sub/su";
+        facts.uid = None;
+        let verdict = engine.evaluate(&facts);
+        assert!(verdict.rules.is_empty());
+        assert!(verdict.has_action(ACTION_LOG) && verdict.has_action(ACTION_FORWARD));
+        assert!(! verdict.has_action(ACTION_EXEC));
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_checksum_changed() {
+        let mut engine = create_test_engine("- checksum_changed: true\n");
+        assert_eq!(engine.checksum_changed("/etc/passwd", Some("a")), None);
+        assert_eq!(engine.checksum_changed("/etc/passwd", Some("a")), Some(false));
+        assert_eq!(engine.checksum_changed("/etc/passwd", Some("b")), Some(true));
+        assert_eq!(engine.checksum_changed("/etc/passwd", None), None);
+        assert_eq!(engine.checksum_changed("/etc/passwd", Some("b")), None);
+        let facts = Facts { checksum_changed: Some(false), ..Facts::default() };
+        assert!(engine.evaluate(&facts).rules.is_empty());
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_checksum_seed() {
+        let dir = "test_rules_seed";
+        std::fs::create_dir_all(dir).unwrap();
+        let (tracked, other) = (format!("{}/shadow", dir), format!("{}/motd", dir));
+        std::fs::write(&tracked, "a").unwrap();
+        std::fs::write(&other, "a").unwrap();
+        let mut engine = create_test_engine(&format!("- path: {}\n  checksum_changed: true\n", tracked));
+        // Workers share the checksums recorded on the baseline
+        let mut worker = engine.clone();
+        engine.seed(&tracked, "test");
+        engine.seed(&other, "test");
+        let checksum = hash::get_checksum(tracked.clone());
+        assert_eq!(worker.checksum_changed(&tracked, Some("b")), Some(true));
+        assert_eq!(worker.checksum_changed(&other, Some(&checksum)), None);
+        assert_eq!(engine.checksum_changed(&tracked, Some(&checksum)), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_type_changed() {
+        let mut engine = create_test_engine("- name: script\n  path: /usr/bin/*\n  file_type: [script]\n\
+            - name: binary\n  file_type: elf\n  type_changed: true\n");
+        assert_eq!(engine.type_changed("/etc/app.conf", Some("text")), None);
+        assert_eq!(engine.type_changed("/etc/app.conf", Some("text")), Some(false));
+        assert_eq!(engine.type_changed("/etc/app.conf", Some("elf")), Some(true));
+        assert_eq!(engine.type_changed("/etc/app.conf", None), None);
+        assert_eq!(engine.type_changed("/etc/app.conf", Some("elf")), None);
+
+        let facts = Facts { path: "/etc/app.conf", file_type: Some("elf"), type_changed: Some(true), ..Facts::default() };
+        assert_eq!(engine.evaluate(&facts).rules, vec!["binary"]);
+        let facts = Facts { path: "/usr/bin/tool", file_type: Some("script"), ..Facts::default() };
+        assert_eq!(engine.evaluate(&facts).rules, vec!["script"]);
+        let facts = Facts { path: "/usr/bin/tool", ..Facts::default() };
+        assert!(engine.evaluate(&facts).rules.is_empty());
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_apply() {
+        let mut obj = json!({ "path": "/etc/shadow" });
+        Verdict::default().apply(&mut obj);
+        assert!(obj.get("rules").is_none());
+        let verdict = Verdict { rules: vec![String::from("shadow")], severity: Some(9),
+            tags: vec![String::from("auth")], ..Verdict::default() };
+        verdict.apply(&mut obj);
+        assert_eq!(obj, json!({ "path": "/etc/shadow", "rules": ["shadow"], "severity": 9, "tags": ["auth"] }));
+    }
+}
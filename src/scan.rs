@@ -0,0 +1,249 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To walk monitored directories
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+// To manage paths
+use std::path::{Path, PathBuf};
+// To keep the last known state of files
+use std::collections::HashMap;
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+// To skip agent files
+use crate::selfwatch;
+// To queue found changes
+use crate::pipeline;
+
+// Global constants definitions
+pub const OPERATION_LOST: &str = "EVENTS_LOST";
+
+// ----------------------------------------------------------------------------
+
+// Attributes compared to find changes missed by the watcher
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct FileState {
+    size: u64,
+    mode: u32,
+    ino: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64)
+}
+
+impl FileState {
+    fn new(metadata: &fs::Metadata) -> Self {
+        FileState {
+            size: metadata.len(),
+            mode: metadata.mode(),
+            ino: metadata.ino(),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            ctime: (metadata.ctime(), metadata.ctime_nsec())
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Walk a monitor entry and collect the state of its regular files, symlinks
+// are not followed and ignored or agent files are skipped
+fn walk(path: &Path, config: &config::Config, index: usize, selfwatch: &selfwatch::SelfWatch,
+    files: &mut HashMap<PathBuf, FileState>) {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) => { debug!("Could not scan {}, Err: [{}]", path.display(), e); return }
+    };
+    if metadata.is_dir() {
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) => { warn!("Could not scan directory {}, Err: [{}]", path.display(), e); return }
+        };
+        for entry in entries.flatten() {
+            walk(&entry.path(), config, index, selfwatch, files);
+        }
+    } else if metadata.is_file() && ! selfwatch.is_watched(path) {
+        let filename = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if ! config.match_ignore(index, filename, config.monitor.clone()) {
+            files.insert(path.to_path_buf(), FileState::new(&metadata));
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Files found below a path of a monitor entry, walked out of the event loop
+// and compared with the known state once done
+pub struct Snapshot {
+    pub index: usize,
+    pub root: PathBuf,
+    files: HashMap<PathBuf, FileState>
+}
+
+impl Snapshot {
+    // Walk a path of a monitor entry
+    pub fn new(config: &config::Config, index: usize, root: &Path, selfwatch: &selfwatch::SelfWatch) -> Self {
+        let mut files = HashMap::new();
+        walk(root, config, index, selfwatch, &mut files);
+        Snapshot { index, root: root.to_path_buf(), files }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Walk every file of a monitor entry
+    pub fn entry(config: &config::Config, index: usize, selfwatch: &selfwatch::SelfWatch) -> Self {
+        let root = config.monitor[index]["path"].as_str().unwrap_or("");
+        Snapshot::new(config, index, Path::new(root), selfwatch)
+    }
+
+    // ------------------------------------------------------------------------
+
+    pub fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.files.keys()
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Last known state of monitored files, used to find changes missed by the watcher
+#[derive(Default)]
+pub struct State {
+    files: HashMap<PathBuf, FileState>
+}
+
+impl State {
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Record the current state of a file after an event on it
+    pub fn update(&mut self, path: &Path) {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_file() => {
+                self.files.insert(path.to_path_buf(), FileState::new(&metadata));
+            },
+            Ok(_) => {},
+            Err(_) => { self.files.remove(path); }
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Record the files of a snapshot without reporting changes, states updated
+    // by events since the walk are newer and kept
+    pub fn record(&mut self, snapshot: Snapshot) {
+        for (path, state) in snapshot.files {
+            self.files.entry(path).or_insert(state);
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Compare the files of a snapshot with their last known state and return
+    // the jobs of the changes found
+    pub fn compare(&mut self, config: &config::Config, snapshot: Snapshot) -> Vec<pipeline::Job> {
+        let Snapshot { index, root, files: current } = snapshot;
+        let root = root.as_path();
+        let label = config.get_label(index);
+
+        let mut jobs = Vec::new();
+        let removed: Vec<PathBuf> = self.files.keys()
+            .filter(|p| p.starts_with(root) && ! current.contains_key(*p))
+            .cloned().collect();
+        for path in removed {
+            self.files.remove(&path);
+            jobs.push(pipeline::Job::new(&path, String::from("REMOVE"), label.clone()));
+        }
+        for (path, state) in current {
+            let operation = match self.files.insert(path.clone(), state) {
+                None => "CREATE",
+                Some(last) if last != state => "MODIFY",
+                Some(_) => continue
+            };
+            jobs.push(pipeline::Job::new(&path, String::from(operation), label.clone()));
+        }
+        jobs.sort_by(|a, b| a.path.cmp(&b.path));
+        if jobs.is_empty() { debug!("Rescan of {} found no changes", root.display()); }
+        else { info!("Rescan of {} found {} changes", root.display(), jobs.len()); }
+        jobs
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+    use yaml_rust::YamlLoader;
+
+    fn create_test_scan(dir: &str) -> config::Config {
+        fs::create_dir_all(format!("{}/sub", dir)).unwrap();
+        fs::write(format!("{}/a.txt", dir), "a").unwrap();
+        fs::write(format!("{}/sub/b.txt", dir), "b").unwrap();
+        fs::write(format!("{}/c.swp", dir), "c").unwrap();
+        let mut config = create_test_config("info");
+        let yaml = format!("- path: {}\n  label: test\n  ignore: [\".swp\"]\n", dir);
+        config.monitor = YamlLoader::load_from_str(&yaml).unwrap()[0].as_vec().unwrap().to_vec();
+        config
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_baseline() {
+        let dir = "test_scan_baseline";
+        let config = create_test_scan(dir);
+        let selfwatch = selfwatch::SelfWatch::new(&config);
+        let mut state = State::default();
+        state.record(Snapshot::entry(&config, 0, &selfwatch));
+        assert_eq!(state.len(), 2);
+        assert!(state.compare(&config, Snapshot::entry(&config, 0, &selfwatch)).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_rescan() {
+        let dir = "test_scan_rescan";
+        let config = create_test_scan(dir);
+        let selfwatch = selfwatch::SelfWatch::new(&config);
+        let mut state = State::default();
+        state.record(Snapshot::entry(&config, 0, &selfwatch));
+
+        fs::write(format!("{}/a.txt", dir), "changed").unwrap();
+        fs::remove_file(format!("{}/sub/b.txt", dir)).unwrap();
+        fs::write(format!("{}/sub/d.txt", dir), "d").unwrap();
+        fs::write(format!("{}/e.swp", dir), "e").unwrap();
+        let jobs: Vec<(String, String)> = state.compare(&config, Snapshot::entry(&config, 0, &selfwatch)).into_iter()
+            .map(|j| (j.path.display().to_string(), j.operation)).collect();
+        assert_eq!(jobs, vec![
+            (format!("{}/a.txt", dir), String::from("MODIFY")),
+            (format!("{}/sub/b.txt", dir), String::from("REMOVE")),
+            (format!("{}/sub/d.txt", dir), String::from("CREATE"))
+        ]);
+        assert_eq!(state.len(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_update() {
+        let dir = "test_scan_update";
+        let config = create_test_scan(dir);
+        let selfwatch = selfwatch::SelfWatch::new(&config);
+        let mut state = State::default();
+        let file = format!("{}/a.txt", dir);
+        state.update(Path::new(&file));
+        assert_eq!(state.len(), 1);
+        fs::remove_file(&file).unwrap();
+        state.update(Path::new(&file));
+        assert_eq!(state.len(), 0);
+        assert!(state.compare(&config, Snapshot::entry(&config, 0, &selfwatch)).iter().all(|j| j.operation == "CREATE"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
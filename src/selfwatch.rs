@@ -0,0 +1,275 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To manage paths
+use std::path::{Path, PathBuf};
+use std::fs;
+// To keep known sizes of files written by the agent
+use std::collections::HashMap;
+// To watch agent files
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use notify::event::{Event, EventKind, ModifyKind};
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+// To check agent rotations of the events file
+use crate::rotation;
+// To get chain sidecar path
+use crate::chain;
+// To get the status file path
+use crate::status;
+// To skip the control socket
+use crate::control;
+
+// Global constants definitions
+pub const OPERATION: &str = "SELF_TAMPER";
+pub const LABEL: &str = "ifim";
+
+// ----------------------------------------------------------------------------
+
+// Get an absolute path even if the file does not exist yet
+fn get_absolute(path: &Path) -> PathBuf {
+    if let Ok(path) = fs::canonicalize(path) { return path }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => match fs::canonicalize(if parent.as_os_str().is_empty() { Path::new(".") } else { parent }) {
+            Ok(parent) => parent.join(name),
+            Err(_) => path.to_path_buf()
+        },
+        _ => path.to_path_buf()
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Files of the agent itself that are always watched
+#[derive(Clone)]
+pub struct SelfWatch {
+    // Files only the administrator should change, config and executable
+    files: Vec<PathBuf>,
+    // Files the agent writes, only shrinking, moves and removals are reported
+    written: Vec<PathBuf>,
+    // Files and directories the agent changes all the time, never reported
+    ignored: Vec<PathBuf>,
+    // Written files rotated by an external tool, their moves and truncations
+    // are expected
+    rotated: Vec<PathBuf>,
+    dirs: Vec<PathBuf>,
+    sizes: HashMap<PathBuf, u64>
+}
+
+impl SelfWatch {
+    pub fn new(config: &config::Config) -> Self {
+        let mut files = vec![get_absolute(Path::new(&config.path))];
+        if let Ok(exe) = std::env::current_exe() { files.push(get_absolute(&exe)); }
+
+        let mut written = vec![get_absolute(Path::new(&config.log_file))];
+        let mut rotated = Vec::new();
+        if config.events_file != "Not_used" {
+            let events = get_absolute(Path::new(&config.events_file));
+            if config.rotation_external { rotated.push(events.clone()); }
+            written.push(events.clone());
+            written.push(PathBuf::from(chain::get_sidecar(&events.display().to_string())));
+            if let Some(parent) = events.parent() { written.push(parent.join("agent.id")); }
+        }
+
+        let status = get_absolute(&status::get_path(config));
+        let ignored = vec![get_absolute(Path::new(&config.spool_dir)), status.with_extension("json.tmp"), status,
+            get_absolute(&control::get_path(config))];
+
+        let mut dirs: Vec<PathBuf> = files.iter().chain(written.iter())
+            .filter_map(|p| p.parent().map(Path::to_path_buf)).collect();
+        dirs.sort();
+        dirs.dedup();
+
+        let sizes = written.iter()
+            .filter_map(|p| fs::metadata(p).ok().map(|m| (p.clone(), m.len()))).collect();
+        SelfWatch { files, written, ignored, rotated, dirs, sizes }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Watch the directories of agent files, files can be replaced on edition
+    pub fn watch(&self, watcher: &mut RecommendedWatcher) {
+        for dir in &self.dirs {
+            match watcher.watch(dir, RecursiveMode::NonRecursive) {
+                Ok(_) => info!("Self integrity monitoring: {}", dir.display()),
+                Err(e) => error!("Could not monitor agent directory {}, Err: [{}]", dir.display(), e)
+            }
+        }
+        for file in self.files.iter().chain(self.written.iter()) {
+            debug!("Self integrity file: {}", file.display());
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Returns if the path is one of the agent files or directories
+    pub fn is_watched(&self, path: &Path) -> bool {
+        self.files.iter().chain(self.written.iter()).chain(self.dirs.iter()).any(|p| p == path) ||
+            self.ignored.iter().any(|p| path.starts_with(p))
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Returns why an event on an agent file is a tamper, if it is
+    pub fn check(&mut self, path: &Path, kind: &EventKind) -> Option<String> {
+        if matches!(kind, EventKind::Access(_)) { return None }
+
+        if self.dirs.iter().any(|p| p == path) {
+            return match kind {
+                EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_)) => Some(String::from("directory moved or removed")),
+                EventKind::Modify(ModifyKind::Metadata(_)) => Some(String::from("directory metadata changed")),
+                _ => None
+            }
+        }
+
+        if self.files.iter().any(|p| p == path) {
+            return match kind {
+                EventKind::Create(_) => Some(String::from("file replaced")),
+                EventKind::Remove(_) => Some(String::from("file removed")),
+                EventKind::Modify(ModifyKind::Metadata(_)) => Some(String::from("file metadata changed")),
+                EventKind::Modify(ModifyKind::Name(_)) => Some(String::from("file moved")),
+                _ => Some(String::from("file modified"))
+            }
+        }
+
+        if self.written.iter().any(|p| p == path) {
+            let rotated = self.rotated.iter().any(|p| p == path);
+            return match kind {
+                EventKind::Modify(ModifyKind::Metadata(_)) => Some(String::from("file metadata changed")),
+                EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_)) => {
+                    if rotated || rotation::recently_rotated(path) { None }
+                    else { Some(String::from("file moved or removed")) }
+                },
+                EventKind::Modify(_) => {
+                    // The known size is reset, the writer reopens truncated files
+                    // and cuts its own partial writes after failures
+                    let size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+                    let last = self.sizes.insert(path.to_path_buf(), size).unwrap_or(0);
+                    if size < last && ! rotated && ! rotation::recently_truncated(path) {
+                        Some(format!("file truncated from {} to {} bytes", last, size))
+                    } else { None }
+                },
+                EventKind::Create(_) => {
+                    self.sizes.insert(path.to_path_buf(), fs::metadata(path).map(|m| m.len()).unwrap_or(0));
+                    None
+                },
+                _ => None
+            }
+        }
+        None
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Returns the first agent file of an event and why it was tampered
+pub fn check_event(selfwatch: &mut SelfWatch, event: &Event) -> Option<(PathBuf, String)> {
+    event.paths.iter()
+        .find_map(|p| selfwatch.check(p, &event.kind).map(|reason| (p.clone(), reason)))
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+    use notify::event::{AccessKind, CreateKind, DataChange, MetadataKind, RemoveKind, RenameMode};
+
+    fn create_test_selfwatch(dir: &str) -> (SelfWatch, PathBuf, PathBuf) {
+        fs::create_dir_all(dir).unwrap();
+        let mut config = create_test_config("info");
+        config.path = format!("{}/config.yml", dir);
+        config.events_file = format!("{}/events.json", dir);
+        config.log_file = format!("{}/ifim.log", dir);
+        fs::write(&config.path, "events:\n").unwrap();
+        fs::write(&config.events_file, "1234567890\n").unwrap();
+        let selfwatch = SelfWatch::new(&config);
+        (selfwatch, get_absolute(Path::new(&config.path)), get_absolute(Path::new(&config.events_file)))
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_check_config() {
+        let dir = "test_selfwatch_config";
+        let (mut selfwatch, config, _) = create_test_selfwatch(dir);
+        assert!(selfwatch.is_watched(&config));
+        assert_eq!(selfwatch.check(&config, &EventKind::Access(AccessKind::Any)), None);
+        assert!(selfwatch.check(&config, &EventKind::Modify(ModifyKind::Data(DataChange::Any))).is_some());
+        assert!(selfwatch.check(&config, &EventKind::Remove(RemoveKind::File)).is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_check_events_file() {
+        let dir = "test_selfwatch_events";
+        let (mut selfwatch, _, events) = create_test_selfwatch(dir);
+        let modify = EventKind::Modify(ModifyKind::Data(DataChange::Any));
+        fs::write(&events, "1234567890\nabc\n").unwrap();
+        assert_eq!(selfwatch.check(&events, &modify), None);
+        fs::write(&events, "1\n").unwrap();
+        assert!(selfwatch.check(&events, &modify).unwrap().contains("truncated"));
+        assert!(selfwatch.check(&events, &EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any))).is_some());
+        assert_eq!(selfwatch.check(&events, &EventKind::Create(CreateKind::File)), None);
+        // Partial writes cut by the agent after a write failure are not reported
+        fs::write(&events, "1234567890\nabc").unwrap();
+        assert_eq!(selfwatch.check(&events, &modify), None);
+        rotation::mark_truncated(&events);
+        fs::write(&events, "1234567890\n").unwrap();
+        assert_eq!(selfwatch.check(&events, &modify), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_check_external_rotation() {
+        let dir = "test_selfwatch_rotation";
+        let (mut selfwatch, _, events) = create_test_selfwatch(dir);
+        let modify = EventKind::Modify(ModifyKind::Data(DataChange::Any));
+        let moved = EventKind::Modify(ModifyKind::Name(RenameMode::From));
+        assert_eq!(selfwatch.check(&events, &modify), None);
+        // Rotations like logrotate copytruncate and move are reported unless configured
+        fs::write(&events, "").unwrap();
+        assert!(selfwatch.check(&events, &modify).unwrap().contains("truncated"));
+        fs::write(&events, "1234567890\n").unwrap();
+        fs::rename(&events, events.with_extension("json.1")).unwrap();
+        assert!(selfwatch.check(&events, &moved).is_some());
+        // Rotations done by the agent are not
+        fs::write(&events, "1234567890\n").unwrap();
+        rotation::rotate(&events.display().to_string(), 2).unwrap();
+        assert_eq!(selfwatch.check(&events, &moved), None);
+
+        let mut config = create_test_config("info");
+        config.events_file = events.display().to_string();
+        config.rotation_external = true;
+        let mut selfwatch = SelfWatch::new(&config);
+        fs::write(&events, "1234567890\n").unwrap();
+        assert_eq!(selfwatch.check(&events, &modify), None);
+        fs::write(&events, "").unwrap();
+        assert_eq!(selfwatch.check(&events, &modify), None);
+        fs::write(&events, "1\n").unwrap();
+        assert_eq!(selfwatch.check(&events, &modify), None);
+        fs::rename(&events, events.with_extension("json.1")).unwrap();
+        assert_eq!(selfwatch.check(&events, &moved), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_check_other_file() {
+        let dir = "test_selfwatch_other";
+        let (mut selfwatch, _, events) = create_test_selfwatch(dir);
+        let other = events.parent().unwrap().join("other.txt");
+        assert!(!selfwatch.is_watched(&other));
+        assert!(selfwatch.is_watched(&events.parent().unwrap().join("status.json")));
+        assert_eq!(selfwatch.check(&other, &EventKind::Remove(RemoveKind::File)), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
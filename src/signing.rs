@@ -0,0 +1,402 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To handle files
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+// To manage paths
+use std::path::Path;
+// To manage date and time
+use std::time::{SystemTime, UNIX_EPOCH};
+// To sign checkpoints
+use ed25519_dalek::{Signature, Signer as _, SigningKey, Verifier, VerifyingKey};
+// To hash event lines
+use hex::{encode, decode};
+use sha3::{Sha3_512, Digest};
+// To handle JSON objects
+use serde_json::{json, Value};
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+// To hash event lines as the chain does
+use crate::chain;
+// To read event fields
+use crate::format;
+// To read rotated events files
+use crate::rotation;
+// To generate event ids
+use crate::utils;
+
+// ----------------------------------------------------------------------------
+
+// Digest over the hashes of a sequence of event lines
+fn get_digest<'a>(hashes: impl Iterator<Item = &'a String>) -> String {
+    let mut hasher = Sha3_512::new();
+    for hash in hashes { hasher.update(hash.as_bytes()); }
+    encode(hasher.finalize())
+}
+
+// ----------------------------------------------------------------------------
+
+// Message signed by a checkpoint, it includes the signature of the previous
+// checkpoint of the file so signed segments can not be removed
+fn get_payload(count: usize, digest: &str, timestamp: &str, previous: &str) -> String {
+    format!("{}:{}:{}:{}", count, digest, timestamp, previous)
+}
+
+// ----------------------------------------------------------------------------
+
+// Get the signature of a checkpoint line, None for event lines
+fn get_signature(line: &str) -> Option<String> {
+    format::get_field(line, "checkpoint.signature")
+}
+
+// ----------------------------------------------------------------------------
+
+// Read a 32 bytes key stored in hex format
+fn read_key(path: &str) -> Result<[u8; 32], String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    parse_key(contents.trim())
+}
+
+// ----------------------------------------------------------------------------
+
+fn parse_key(value: &str) -> Result<[u8; 32], String> {
+    let bytes = decode(value).map_err(|e| format!("invalid key: {}", e))?;
+    bytes.try_into().map_err(|_| String::from("invalid key length, 32 bytes expected"))
+}
+
+// ----------------------------------------------------------------------------
+
+// Signing state of the events writer
+pub struct Signer {
+    key: Option<SigningKey>,
+    // Hashes of the lines written to the events file since the last checkpoint
+    hashes: Vec<String>,
+    // Signature of the last checkpoint of the events file
+    previous: String
+}
+
+impl Signer {
+    pub fn new(config: &config::Config) -> Self {
+        let key = if config.signing_key.is_empty() { None } else {
+            match read_key(&config.signing_key) {
+                Ok(bytes) => {
+                    info!("Events checkpoints signed with key {}", config.signing_key);
+                    Some(SigningKey::from_bytes(&bytes))
+                },
+                Err(e) => {
+                    error!("Signing key could not be loaded, checkpoints disabled, Err: [{}]", e);
+                    None
+                }
+            }
+        };
+        Signer { key, hashes: Vec::new(), previous: String::new() }
+    }
+
+    // ------------------------------------------------------------------------
+
+    pub fn enabled(&self) -> bool {
+        self.key.is_some()
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Record a line written to the events file to be covered by the next checkpoint
+    pub fn push(&mut self, line: &str) {
+        if self.enabled() && get_signature(line).is_none() { self.hashes.push(chain::get_hash(line)); }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Called when the events file is opened, the next checkpoint follows the
+    // last one of the file and covers the lines written after it
+    pub fn resume(&mut self, file: &str) {
+        if ! self.enabled() { return }
+        self.hashes.clear();
+        self.previous.clear();
+        let reader = match File::open(file) {
+            Ok(f) => BufReader::new(f),
+            Err(_) => return
+        };
+        for line in reader.lines().map_while(Result::ok) {
+            match get_signature(&line) {
+                Some(signature) => {
+                    self.hashes.clear();
+                    self.previous = signature;
+                },
+                None => self.hashes.push(chain::get_hash(&line))
+            }
+        }
+        debug!("Events signing resumed with {} unsigned events", self.hashes.len());
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Build a signed checkpoint over the events since the last one
+    pub fn checkpoint(&mut self) -> Option<Value> {
+        let key = self.key.as_ref()?;
+        if self.hashes.is_empty() { return None }
+
+        let count = self.hashes.len();
+        let digest = get_digest(self.hashes.iter());
+        let timestamp = format!("{}", SystemTime::now().duration_since(UNIX_EPOCH)
+            .expect("Time went backwards").as_millis());
+        let signature = encode(key.sign(get_payload(count, &digest, &timestamp, &self.previous).as_bytes()).to_bytes());
+        self.hashes.clear();
+        self.previous = signature.clone();
+        Some(json!({
+            "id": utils::get_uuid(),
+            "operation": "CHECKPOINT",
+            "timestamp": timestamp,
+            "checkpoint": {
+                "count": count,
+                "digest": digest,
+                "signature": signature
+            }
+        }))
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Verify the signed checkpoints of an events file, every event before a
+// checkpoint has to be covered by it and each checkpoint signs the previous
+// one, returns the number of signed events and the number of events after
+// the last checkpoint, or the line number and reason of the first failure
+pub fn verify(path: &Path, key: &VerifyingKey) -> Result<(usize, usize), (usize, String)> {
+    let reader = rotation::open_reader(path).map_err(|e| (0, format!("cannot read file: {}", e)))?;
+    let mut hashes: Vec<String> = Vec::new();
+    let mut previous = String::new();
+    let mut signed = 0;
+    for (index, line) in reader.lines().enumerate() {
+        let number = index + 1;
+        let line = line.map_err(|e| (number, format!("cannot read line: {}", e)))?;
+        let signature = match get_signature(&line) {
+            Some(value) => value,
+            None => { hashes.push(chain::get_hash(&line)); continue }
+        };
+
+        let count: usize = format::get_field(&line, "checkpoint.count")
+            .and_then(|c| c.parse().ok()).unwrap_or(0);
+        let digest = format::get_field(&line, "checkpoint.digest").unwrap_or_default();
+        let timestamp = format::get_field(&line, "timestamp").unwrap_or_default();
+        if count != hashes.len() {
+            return Err((number, format!("checkpoint covers {} events, {} found", count, hashes.len())))
+        }
+        if get_digest(hashes.iter()) != digest {
+            return Err((number, String::from("events do not match the checkpoint digest")))
+        }
+        let bytes = decode(&signature).ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or((number, String::from("invalid signature format")))?;
+        // A wrong previous signature means checkpoints were removed before this one
+        key.verify(get_payload(count, &digest, &timestamp, &previous).as_bytes(), &bytes)
+            .map_err(|_| (number, String::from("invalid checkpoint signature")))?;
+        signed += count;
+        hashes.clear();
+        previous = signature;
+    }
+    Ok((signed, hashes.len()))
+}
+
+// ----------------------------------------------------------------------------
+
+// Command line entry point of `ifim verify-signature <public key> [file]`
+pub fn verify_command(config: &config::Config, args: &[String]) -> i32 {
+    let key = match args.first() {
+        Some(value) => {
+            // Public key given in hex format or as a file path
+            let parsed = if Path::new(value).exists() { read_key(value) } else { parse_key(value) };
+            match parsed.and_then(|bytes| VerifyingKey::from_bytes(&bytes).map_err(|e| e.to_string())) {
+                Ok(key) => key,
+                Err(e) => { eprintln!("Invalid public key: {}", e); return 2 }
+            }
+        },
+        None => {
+            eprintln!("Usage: ifim verify-signature <public key> [file]");
+            return 2
+        }
+    };
+    let file = args.get(1).cloned().unwrap_or_else(|| config.events_file.clone());
+
+    match verify(Path::new(&file), &key) {
+        Ok((signed, unsigned)) => {
+            println!("{}: checkpoints verified, {} signed events, {} events after last checkpoint",
+                file, signed, unsigned);
+            0
+        },
+        Err((line, reason)) => {
+            println!("{}: checkpoint verification failed at line {}: {}", file, line, reason);
+            1
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Command line entry point of `ifim keygen <private key file>`, prints the
+// public key to be used with verify-signature
+pub fn keygen_command(args: &[String]) -> i32 {
+    let path = match args.first() {
+        Some(value) => value,
+        None => { eprintln!("Usage: ifim keygen <private key file>"); return 2 }
+    };
+    let mut seed = [0u8; 32];
+    if let Err(e) = File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut seed)) {
+        eprintln!("Cannot read random data: {}", e);
+        return 1
+    }
+    let key = SigningKey::from_bytes(&seed);
+    let written = OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+        .and_then(|mut f| writeln!(f, "{}", encode(seed)));
+    match written {
+        Ok(_) => {
+            println!("Private key written to {}", path);
+            println!("Public key: {}", encode(key.verifying_key().to_bytes()));
+            0
+        },
+        Err(e) => {
+            eprintln!("Cannot write {}: {}", path, e);
+            1
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_signer() -> (Signer, VerifyingKey) {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let public = key.verifying_key();
+        (Signer { key: Some(key), hashes: Vec::new(), previous: String::new() }, public)
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Lines of a signed segment, the events and their checkpoint
+    fn get_segment(signer: &mut Signer, lines: &[&str]) -> Vec<String> {
+        let mut all = Vec::new();
+        for line in lines {
+            signer.push(line);
+            all.push(String::from(*line));
+        }
+        all.push(signer.checkpoint().unwrap().to_string());
+        all
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn write_lines(filename: &str, lines: &[String]) {
+        fs::write(filename, lines.join("\n") + "\n").unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_checkpoint_empty() {
+        let (mut signer, _) = create_test_signer();
+        assert!(signer.checkpoint().is_none());
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_verify_ok() {
+        let filename = "test_signing_ok.json";
+        let (mut signer, public) = create_test_signer();
+        let mut lines = get_segment(&mut signer, &["{\"id\":\"1\"}", "{\"id\":\"2\"}"]);
+        lines.append(&mut get_segment(&mut signer, &["{\"id\":\"3\"}"]));
+        lines.push(String::from("{\"id\":\"4\"}"));
+        write_lines(filename, &lines);
+        assert_eq!(verify(Path::new(filename), &public), Ok((3, 1)));
+        fs::remove_file(filename).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_verify_modified() {
+        let filename = "test_signing_modified.json";
+        let (mut signer, public) = create_test_signer();
+        let mut lines = get_segment(&mut signer, &["{\"id\":\"1\"}", "{\"id\":\"2\"}"]);
+        lines[0] = String::from("{\"id\":\"3\"}");
+        write_lines(filename, &lines);
+        assert_eq!(verify(Path::new(filename), &public).unwrap_err().0, 3);
+        fs::remove_file(filename).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_verify_inserted() {
+        let filename = "test_signing_inserted.json";
+        let (mut signer, public) = create_test_signer();
+        let mut lines = get_segment(&mut signer, &["{\"id\":\"1\"}"]);
+        lines.push(String::from("{\"id\":\"forged\"}"));
+        lines.append(&mut get_segment(&mut signer, &["{\"id\":\"2\"}"]));
+        write_lines(filename, &lines);
+        assert_eq!(verify(Path::new(filename), &public),
+            Err((5, String::from("checkpoint covers 1 events, 2 found"))));
+        fs::remove_file(filename).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_verify_removed_segment() {
+        let filename = "test_signing_removed.json";
+        let (mut signer, public) = create_test_signer();
+        get_segment(&mut signer, &["{\"id\":\"1\"}"]);
+        write_lines(filename, &get_segment(&mut signer, &["{\"id\":\"2\"}"]));
+        assert_eq!(verify(Path::new(filename), &public),
+            Err((2, String::from("invalid checkpoint signature"))));
+        fs::remove_file(filename).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_verify_wrong_key() {
+        let filename = "test_signing_wrong_key.json";
+        let (mut signer, _) = create_test_signer();
+        write_lines(filename, &get_segment(&mut signer, &["{\"id\":\"1\"}"]));
+        let other = SigningKey::from_bytes(&[8u8; 32]).verifying_key();
+        assert_eq!(verify(Path::new(filename), &other),
+            Err((2, String::from("invalid checkpoint signature"))));
+        fs::remove_file(filename).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_resume() {
+        let filename = "test_signing_resume.json";
+        let (mut signer, public) = create_test_signer();
+        let mut lines = get_segment(&mut signer, &["{\"id\":\"1\"}", "{\"id\":\"2\"}"]);
+        // Written before the agent stopped without a checkpoint
+        lines.push(String::from("{\"id\":\"3\"}"));
+        write_lines(filename, &lines);
+
+        let (mut signer, _) = create_test_signer();
+        signer.resume(filename);
+        lines.append(&mut get_segment(&mut signer, &["{\"id\":\"4\"}"]));
+        write_lines(filename, &lines);
+        assert_eq!(verify(Path::new(filename), &public), Ok((4, 0)));
+        fs::remove_file(filename).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_parse_key() {
+        assert!(parse_key(&encode([1u8; 32])).is_ok());
+        assert!(parse_key("abcd").is_err());
+        assert!(parse_key("not hex").is_err());
+    }
+}
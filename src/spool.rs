@@ -0,0 +1,237 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To handle files
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, Seek, SeekFrom, Write};
+// To manage paths
+use std::path::{Path, PathBuf};
+// To log the program process
+use log::*;
+
+// Global constants definitions
+const OFFSET_EXTENSION: &str = "offset";
+// Lines delivered between saves of the replay offset
+const SAVE_EVERY: usize = 100;
+
+// ----------------------------------------------------------------------------
+
+// Disk backed queue of messages that could not be delivered, messages are
+// appended to a file and the offset of the first undelivered one is saved
+// aside, so the queue survives restarts. Delivery is at least once, a crash
+// during a replay may send again up to SAVE_EVERY messages.
+pub struct Spool {
+    path: PathBuf,
+    offset_path: PathBuf,
+    offset: u64,
+    size: u64,
+    max_size: u64,
+    // Drop the oldest or the newest messages when full
+    drop_oldest: bool,
+    pub dropped: u64
+}
+
+impl Spool {
+    pub fn new(dir: &str, name: &str, max_size: u64, policy: &str) -> Self {
+        let path = Path::new(dir).join(name);
+        let offset_path = path.with_extension(OFFSET_EXTENSION);
+        if let Err(e) = fs::create_dir_all(dir) {
+            error!("Spool directory {} could not be created, Err: [{}]", dir, e);
+        }
+        let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        let offset = fs::read_to_string(&offset_path).ok()
+            .and_then(|o| o.trim().parse().ok()).unwrap_or(0);
+        let mut spool = Spool {
+            path, offset_path, offset: offset.min(size), size, max_size,
+            drop_oldest: policy != "drop_newest", dropped: 0
+        };
+        if ! spool.is_empty() {
+            info!("Spool {} has {} bytes of undelivered events", spool.path.display(), spool.len());
+        }
+        spool.trim();
+        spool
+    }
+
+    // ------------------------------------------------------------------------
+
+    pub fn is_empty(&self) -> bool {
+        self.offset >= self.size
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Bytes of undelivered messages
+    pub fn len(&self) -> u64 {
+        self.size - self.offset
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn save_offset(&self) -> std::io::Result<()> {
+        fs::write(&self.offset_path, format!("{}\n", self.offset))
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Remove the spool files once everything was delivered, or rewrite the
+    // undelivered messages when delivered ones take too much space
+    fn trim(&mut self) {
+        if self.is_empty() {
+            if self.size > 0 {
+                let _ = fs::remove_file(&self.path);
+                let _ = fs::remove_file(&self.offset_path);
+                self.offset = 0;
+                self.size = 0;
+            }
+            return
+        }
+        if self.offset <= self.max_size { return }
+        let tmp = self.path.with_extension("tmp");
+        let compacted = File::open(&self.path).and_then(|mut file| {
+            file.seek(SeekFrom::Start(self.offset))?;
+            let mut out = File::create(&tmp)?;
+            std::io::copy(&mut file, &mut out)?;
+            fs::rename(&tmp, &self.path)
+        });
+        match compacted {
+            Ok(_) => {
+                self.size -= self.offset;
+                self.offset = 0;
+                if let Err(e) = self.save_offset() { error!("Spool offset could not be saved, Err: [{}]", e); }
+            },
+            Err(e) => error!("Spool {} could not be compacted, Err: [{}]", self.path.display(), e)
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Skip the oldest undelivered message
+    fn drop_first(&mut self) -> std::io::Result<()> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        reader.seek(SeekFrom::Start(self.offset))?;
+        let mut line = Vec::new();
+        let read = reader.read_until(b'\n', &mut line)?;
+        self.offset += read.max(1) as u64;
+        self.dropped += 1;
+        Ok(())
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Queue a message, applying the drop policy when the spool is full
+    pub fn push(&mut self, message: &str) -> std::io::Result<()> {
+        let needed = message.len() as u64 + 1;
+        if needed > self.max_size || (! self.drop_oldest && self.len() + needed > self.max_size) {
+            self.dropped += 1;
+            return Ok(())
+        }
+        let first = self.dropped;
+        while self.len() + needed > self.max_size { self.drop_first()?; }
+        if self.dropped > first {
+            self.save_offset()?;
+            self.trim();
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", message)?;
+        self.size += needed;
+        Ok(())
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Deliver queued messages in order, stops at the first failure, returns
+    // the number of messages delivered
+    pub fn replay<F: FnMut(&str) -> Result<(), Error>>(&mut self, mut send: F) -> Result<usize, Error> {
+        if self.is_empty() { return Ok(0) }
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        reader.seek(SeekFrom::Start(self.offset))?;
+        let mut delivered = 0;
+        let mut line = String::new();
+        let result = loop {
+            line.clear();
+            let read = match reader.read_line(&mut line) {
+                Ok(0) => break Ok(delivered),
+                Ok(read) => read,
+                Err(e) => break Err(e)
+            };
+            if let Err(e) = send(line.trim_end_matches('\n')) { break Err(e) }
+            self.offset += read as u64;
+            delivered += 1;
+            if delivered % SAVE_EVERY == 0 { self.save_offset()?; }
+        };
+        self.save_offset()?;
+        self.trim();
+        result
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    fn collect(spool: &mut Spool) -> Vec<String> {
+        let mut out = Vec::new();
+        spool.replay(|m| { out.push(String::from(m)); Ok(()) }).unwrap();
+        out
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_push_and_replay() {
+        let dir = "test_spool_replay";
+        let mut spool = Spool::new(dir, "test.spool", 1024, "drop_oldest");
+        assert!(spool.is_empty());
+        for i in 0..3 { spool.push(&format!("message {}", i)).unwrap(); }
+
+        // Delivery stops at the first failure and resumes from there
+        let mut sent = 0;
+        let result = spool.replay(|_| {
+            if sent == 1 { return Err(Error::new(ErrorKind::ConnectionRefused, "down")) }
+            sent += 1;
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert_eq!(collect(&mut spool), vec!["message 1", "message 2"]);
+        assert!(spool.is_empty());
+        assert!(!Path::new(dir).join("test.spool").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_restart() {
+        let dir = "test_spool_restart";
+        let mut spool = Spool::new(dir, "test.spool", 1024, "drop_oldest");
+        for i in 0..3 { spool.push(&format!("message {}", i)).unwrap(); }
+        let mut first = true;
+        let _ = spool.replay(|_| {
+            if first { first = false; Ok(()) } else { Err(Error::new(ErrorKind::ConnectionRefused, "down")) }
+        });
+
+        let mut spool = Spool::new(dir, "test.spool", 1024, "drop_oldest");
+        assert_eq!(collect(&mut spool), vec!["message 1", "message 2"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_drop_policy() {
+        let dir = "test_spool_policy";
+        // Room for two messages of 10 bytes
+        let mut spool = Spool::new(dir, "oldest.spool", 20, "drop_oldest");
+        for i in 0..4 { spool.push(&format!("message {}", i)).unwrap(); }
+        assert_eq!(spool.dropped, 2);
+        assert_eq!(collect(&mut spool), vec!["message 2", "message 3"]);
+
+        let mut spool = Spool::new(dir, "newest.spool", 20, "drop_newest");
+        for i in 0..4 { spool.push(&format!("message {}", i)).unwrap(); }
+        assert_eq!(spool.dropped, 2);
+        assert_eq!(collect(&mut spool), vec!["message 0", "message 1"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
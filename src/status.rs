@@ -0,0 +1,149 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To handle files
+use std::fs;
+// To manage paths
+use std::path::{Path, PathBuf};
+// To read pipeline metrics
+use std::sync::atomic::Ordering;
+// To handle JSON objects
+use serde_json::{json, Value};
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+// To get pipeline metrics
+use crate::pipeline;
+// To get event timestamps
+use crate::entry;
+
+// Global constants definitions
+const STATUS_FILE: &str = "status.json";
+
+// ----------------------------------------------------------------------------
+
+// Status file path, next to the events file or the log file if events are not stored
+pub fn get_path(config: &config::Config) -> PathBuf {
+    let file = if config.events_file != "Not_used" { &config.events_file } else { &config.log_file };
+    match Path::new(file).parent() {
+        Some(parent) => parent.join(STATUS_FILE),
+        None => PathBuf::from(STATUS_FILE)
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Build the agent status from the watches state and the pipeline metrics
+pub fn get_status(config: &config::Config, watches: Value, metrics: &pipeline::Metrics) -> Value {
+    json!({
+        "pid": std::process::id(),
+        "agent": { "id": config.agent_id.clone(), "version": config.version.clone() },
+        "timestamp": entry::get_timestamp(),
+        "monitor": watches,
+        "pipeline": {
+            "received": metrics.events_received.load(Ordering::Relaxed),
+            "filtered": metrics.events_filtered.load(Ordering::Relaxed),
+            "coalesced": metrics.events_coalesced.load(Ordering::Relaxed),
+            "processed": metrics.jobs_processed.load(Ordering::Relaxed),
+            "lost": metrics.events_lost.load(Ordering::Relaxed),
+            "backpressure": metrics.backpressure.load(Ordering::Relaxed),
+            "events_queued": metrics.events_queued.load(Ordering::Relaxed),
+            "jobs_queued": metrics.jobs_queued.load(Ordering::Relaxed)
+        }
+    })
+}
+
+// ----------------------------------------------------------------------------
+
+// Replace the status file, written aside and renamed so readers never see
+// a partial file
+pub fn write(config: &config::Config, status: &Value) {
+    let path = get_path(config);
+    let tmp = path.with_extension("json.tmp");
+    let written = fs::write(&tmp, format!("{:#}\n", status)).and_then(|_| fs::rename(&tmp, &path));
+    if let Err(e) = written {
+        error!("Status file {} could not be written, Err: [{}]", path.display(), e);
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Command line entry point of `ifim status`, prints the last status written
+// by the running agent
+pub fn status_command(config: &config::Config) -> i32 {
+    let path = get_path(config);
+    let status: Value = match fs::read_to_string(&path).map(|c| serde_json::from_str(&c)) {
+        Ok(Ok(status)) => status,
+        Ok(Err(e)) => { eprintln!("Invalid status file {}: {}", path.display(), e); return 1 },
+        Err(e) => { eprintln!("Cannot read status file {}, is the agent running? {}", path.display(), e); return 1 }
+    };
+    print!("{}", format_status(&status));
+    match status["monitor"].as_array() {
+        Some(monitor) if monitor.iter().any(|m| m["error"].is_string()) => 1,
+        _ => 0
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Human readable status
+fn format_status(status: &Value) -> String {
+    let mut out = format!("Agent {} (version {}), pid {}, updated at {}\n",
+        status["agent"]["id"].as_str().unwrap_or(""), status["agent"]["version"].as_str().unwrap_or(""),
+        status["pid"], status["timestamp"].as_str().unwrap_or(""));
+    for monitor in status["monitor"].as_array().unwrap_or(&Vec::new()) {
+        if monitor["backend"] == "poll" {
+            out.push_str(&format!("  {}: {} directories polled every {} seconds", monitor["path"].as_str().unwrap_or(""),
+                monitor["watched_dirs"], monitor["interval"]));
+        } else {
+            out.push_str(&format!("  {}: {} directories watched", monitor["path"].as_str().unwrap_or(""),
+                monitor["watched_dirs"]));
+        }
+        let unwatched = monitor["unwatched"].as_array().map(|u| u.len()).unwrap_or(0);
+        if unwatched > 0 { out.push_str(&format!(", {} subtrees scanned periodically", unwatched)); }
+        if let Some(error) = monitor["error"].as_str() { out.push_str(&format!(" ({})", error)); }
+        out.push('\n');
+    }
+    let pipeline = &status["pipeline"];
+    out.push_str(&format!("  events received: {}, processed: {}, lost: {}, queued: {}\n",
+        pipeline["received"], pipeline["processed"], pipeline["lost"], pipeline["events_queued"]));
+    out
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+
+    #[test]
+    fn test_get_path() {
+        let mut config = create_test_config("info");
+        config.events_file = String::from("/var/lib/ifim/events.json");
+        assert_eq!(get_path(&config), PathBuf::from("/var/lib/ifim/status.json"));
+        config.events_file = String::from("Not_used");
+        config.log_file = String::from("/var/log/ifim/ifim.log");
+        assert_eq!(get_path(&config), PathBuf::from("/var/log/ifim/status.json"));
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_write_and_format() {
+        let dir = "test_status";
+        fs::create_dir_all(dir).unwrap();
+        let mut config = create_test_config("info");
+        config.events_file = format!("{}/events.json", dir);
+        let watches = json!([{ "path": "/usr", "watched_dirs": 10, "unwatched": ["/usr/lib"],
+            "error": "OS file watch limit reached." }]);
+        write(&config, &get_status(&config, watches, &pipeline::Metrics::default()));
+
+        let status: Value = serde_json::from_str(&fs::read_to_string(get_path(&config)).unwrap()).unwrap();
+        let out = format_status(&status);
+        assert!(out.contains("/usr: 10 directories watched, 1 subtrees scanned periodically"));
+        assert_eq!(status_command(&config), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
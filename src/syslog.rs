@@ -0,0 +1,350 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To send events over the network
+use std::net::{UdpSocket, TcpStream};
+use std::io::{Write, Error, ErrorKind};
+use std::time::Duration;
+// To count delivery errors
+use std::sync::atomic::Ordering;
+// To format syslog timestamps
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+// To communicate with the syslog output task
+use tokio::sync::{mpsc, oneshot};
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+// To keep undelivered events on disk
+use crate::spool;
+// To count delivery errors
+use crate::metrics;
+
+// Global constants definitions
+const APP_NAME: &str = "ifim";
+// Facility used in syslog priority, 1 = user-level messages
+const FACILITY: u8 = 1;
+const CHANNEL_SIZE: usize = 1024;
+const SPOOL_NAME: &str = "syslog.spool";
+// Seconds between deliveries of spooled events
+const RETRY_INTERVAL: u64 = 5;
+// Seconds to connect and write to a TCP destination
+const TIMEOUT: u64 = 5;
+
+// ----------------------------------------------------------------------------
+
+// Map an event severity (0-10) to a syslog severity (0-7)
+pub fn get_syslog_severity(severity: u8) -> u8 {
+    match severity {
+        9..=u8::MAX => 2,
+        7 | 8 => 3,
+        5 | 6 => 4,
+        3 | 4 => 5,
+        _ => 6
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Build an RFC 5424 syslog message with the given event line
+pub fn format_message(line: &str, severity: u8, hostname: &str) -> String {
+    let timestamp = OffsetDateTime::now_utc().format(&Rfc3339)
+        .unwrap_or_else(|_| String::from("-"));
+    let host = if hostname.is_empty() { "-" } else { hostname };
+    format!("<{}>1 {} {} {} {} - - {}",
+        FACILITY * 8 + get_syslog_severity(severity), timestamp, host,
+        APP_NAME, std::process::id(), line)
+}
+
+// ----------------------------------------------------------------------------
+
+// Connection to the syslog destination, kept open between messages and
+// opened again after an error
+struct Connection {
+    address: String,
+    protocol: String,
+    stream: Option<TcpStream>,
+    socket: Option<UdpSocket>
+}
+
+impl Connection {
+    fn new(address: &str, protocol: &str) -> Self {
+        Connection { address: String::from(address), protocol: String::from(protocol), stream: None, socket: None }
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn connect_tcp(&self) -> Result<TcpStream, Error> {
+        let addr = self.address.parse().map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(TIMEOUT))?;
+        stream.set_write_timeout(Some(Duration::from_secs(TIMEOUT)))?;
+        Ok(stream)
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Send a message, TCP uses newline framing. UDP is best effort, the
+    // socket is connected so refusals reported by the network are errors
+    fn send(&mut self, message: &str) -> Result<(), Error> {
+        match self.protocol.as_str() {
+            "tcp" | "TCP" => {
+                let line = format!("{}\n", message);
+                if let Some(stream) = self.stream.as_mut() {
+                    if stream.write_all(line.as_bytes()).is_ok() { return Ok(()) }
+                    // The collector may have closed an idle connection, reconnect once
+                    self.stream = None;
+                }
+                let mut stream = self.connect_tcp()?;
+                stream.write_all(line.as_bytes())?;
+                self.stream = Some(stream);
+                Ok(())
+            },
+            _ => {
+                if self.socket.is_none() {
+                    let socket = UdpSocket::bind("0.0.0.0:0")?;
+                    socket.connect(&self.address)?;
+                    self.socket = Some(socket);
+                }
+                let result = self.socket.as_ref().map_or(Ok(0), |socket| socket.send(message.as_bytes()));
+                if result.is_err() { self.socket = None; }
+                result.map(|_| ())
+            }
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+enum Message {
+    // Syslog message of an event
+    Event(String),
+    // Try to deliver spooled events and answer once done
+    Flush(oneshot::Sender<()>)
+}
+
+// ----------------------------------------------------------------------------
+
+// Cloneable handle used to feed the syslog output task
+#[derive(Clone)]
+pub struct Handle {
+    tx: mpsc::Sender<Message>,
+    hostname: String
+}
+
+impl Handle {
+    // Queue a formatted event line, the message timestamp is set now
+    pub async fn log(&self, line: &str, severity: u8) {
+        let message = format_message(line, severity, &self.hostname);
+        if self.tx.send(Message::Event(message)).await.is_err() {
+            error!("Syslog output is not running, event dropped");
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Wait until spooled events had a delivery attempt
+    pub async fn flush(&self) {
+        let (tx, rx) = oneshot::channel();
+        if self.tx.send(Message::Flush(tx)).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Syslog destination, events that cannot be delivered are spooled to disk
+// and replayed in order once it is reachable again
+struct Output {
+    address: String,
+    connection: Connection,
+    spool: spool::Spool,
+    failing: bool
+}
+
+impl Output {
+    fn new(config: &config::Config) -> Self {
+        Output {
+            address: config.syslog_address.clone(),
+            connection: Connection::new(&config.syslog_address, &config.syslog_protocol),
+            spool: spool::Spool::new(&config.spool_dir, SPOOL_NAME, config.spool_max_size, &config.spool_policy),
+            failing: false
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn deliver(&mut self, message: String) {
+        // Spooled events go first to keep the order
+        if self.spool.is_empty() {
+            match self.connection.send(&message) {
+                Ok(_) => { debug!("Event sent to syslog {}", self.address); return },
+                Err(e) => self.fail(e)
+            }
+        }
+        let dropped = self.spool.dropped;
+        if let Err(e) = self.spool.push(&message) {
+            error!("Event could not be spooled, dropped, Err: [{}]", e);
+        }
+        if self.spool.dropped > dropped && self.spool.dropped % 1000 == 1 {
+            warn!("Syslog spool is full, {} events dropped", self.spool.dropped);
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn replay(&mut self) {
+        if self.spool.is_empty() { return }
+        let connection = &mut self.connection;
+        match self.spool.replay(|message| connection.send(message)) {
+            Ok(count) => {
+                info!("Syslog {} reachable again, {} spooled events delivered, {} dropped",
+                    self.address, count, self.spool.dropped);
+                self.failing = false;
+                self.spool.dropped = 0;
+            },
+            Err(e) => self.fail(e)
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn fail(&mut self, e: Error) {
+        metrics::SYSLOG_ERRORS.fetch_add(1, Ordering::Relaxed);
+        if ! self.failing {
+            error!("Events could not be sent to syslog {}, spooling them, Err: [{}]", self.address, e);
+            self.failing = true;
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Start the syslog output task if a destination is configured
+pub fn spawn(config: &config::Config) -> Option<Handle> {
+    if config.syslog_address.is_empty() { return None }
+    let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+    tokio::spawn(run(Output::new(config), rx));
+    Some(Handle { tx, hostname: config.hostname.clone() })
+}
+
+// ----------------------------------------------------------------------------
+
+// Network calls block, they run out of the async workers
+async fn run(mut output: Output, mut rx: mpsc::Receiver<Message>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(RETRY_INTERVAL));
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(Message::Event(message)) => {
+                    output = match tokio::task::spawn_blocking(move || { output.deliver(message); output }).await {
+                        Ok(output) => output,
+                        Err(e) => { error!("Syslog output failed, Err: [{}]", e); return }
+                    };
+                },
+                Some(Message::Flush(done)) => {
+                    output = match tokio::task::spawn_blocking(move || { output.replay(); output }).await {
+                        Ok(output) => output,
+                        Err(e) => { error!("Syslog output failed, Err: [{}]", e); return }
+                    };
+                    let _ = done.send(());
+                },
+                None => break
+            },
+            _ = interval.tick() => {
+                output = match tokio::task::spawn_blocking(move || { output.replay(); output }).await {
+                    Ok(output) => output,
+                    Err(e) => { error!("Syslog output failed, Err: [{}]", e); return }
+                };
+            }
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_get_syslog_severity() {
+        assert_eq!(get_syslog_severity(10), 2);
+        assert_eq!(get_syslog_severity(7), 3);
+        assert_eq!(get_syslog_severity(5), 4);
+        assert_eq!(get_syslog_severity(3), 5);
+        assert_eq!(get_syslog_severity(0), 6);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_format_message() {
+        let msg = format_message("CEF:0|test", 7, "host");
+        let tail = format!(" host ifim {} - - CEF:0|test", std::process::id());
+        assert!(msg.starts_with("<11>1 "));
+        assert!(msg.ends_with(&tail));
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn test_spool_tcp() {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+        use crate::config::create_test_config;
+
+        let dir = "test_syslog_spool";
+        // Nothing listens on the port until the listener is bound again
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let mut config = create_test_config("info");
+        config.syslog_address = address.clone();
+        config.syslog_protocol = String::from("tcp");
+        config.spool_dir = String::from(dir);
+
+        let syslog = spawn(&config).unwrap();
+        syslog.log("first", 5).await;
+        syslog.log("second", 5).await;
+        syslog.flush().await;
+        assert!(fs::metadata(format!("{}/{}", dir, SPOOL_NAME)).is_ok());
+
+        let server = TcpListener::bind(&address).unwrap();
+        // Every event goes through the same connection
+        let reader = std::thread::spawn(move || {
+            let (stream, _) = server.accept().unwrap();
+            BufReader::new(stream).lines().take(3).map(|line| line.unwrap()).collect::<Vec<String>>()
+        });
+        syslog.flush().await;
+        syslog.log("third", 5).await;
+        syslog.flush().await;
+        let lines = reader.join().unwrap();
+        assert!(lines[0].ends_with(" first"));
+        assert!(lines[1].ends_with(" second"));
+        assert!(lines[2].ends_with(" third"));
+        assert!(fs::metadata(format!("{}/{}", dir, SPOOL_NAME)).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_send_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let mut connection = Connection::new(&address, "udp");
+        connection.send("test message").unwrap();
+        let mut buf = [0; 64];
+        let (len, _) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"test message");
+
+        // Refusals of a closed port are reported on the next sends
+        drop(server);
+        let errors = (0..3).filter(|_| {
+            std::thread::sleep(Duration::from_millis(50));
+            connection.send("test message").is_err()
+        }).count();
+        assert!(errors > 0);
+    }
+}
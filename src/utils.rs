@@ -8,6 +8,13 @@ use std::env;
 use std::path::Path;
 // To manage cmp order
 use std::cmp::Ordering;
+// To store the agent identifier
+use std::fs;
+// To log the program process
+use log::*;
+
+// Global constants definitions
+const AGENT_ID_FILE: &str = "agent.id";
 
 // ----------------------------------------------------------------------------
 
@@ -30,7 +37,7 @@ use std::os::unix::fs::PermissionsExt;
 use std::fs::metadata;
 
 pub fn get_path(path: &Path) -> String {
-    format!("{}", path.display().to_string())
+    path.display().to_string()
 }
 
 pub fn get_perms(path: &Path) -> String {
@@ -68,6 +75,30 @@ pub fn get_os() -> String {
 
 // ----------------------------------------------------------------------------
 
+pub fn get_hostname() -> String {
+    gethostname::gethostname().to_string_lossy().to_string()
+}
+
+// ----------------------------------------------------------------------------
+
+// Get the persistent agent identifier stored in the given directory,
+// a new one is generated and stored on first run
+pub fn get_agent_id(dir: &Path) -> String {
+    let file = dir.join(AGENT_ID_FILE);
+    match fs::read_to_string(&file) {
+        Ok(id) if !id.trim().is_empty() => String::from(id.trim()),
+        _ => {
+            let id = get_uuid();
+            if let Err(e) = fs::write(&file, format!("{}\n", id)) {
+                error!("Could not store agent id in {}, Err: [{}]", file.display(), e);
+            }
+            id
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
 
 
 // Function to clean trailing slash of a path
@@ -141,6 +172,18 @@ mod tests {
 
     // ------------------------------------------------------------------------
 
+    #[test]
+    fn test_get_agent_id() {
+        let dir = Path::new("test_get_agent_id");
+        fs::create_dir_all(dir).unwrap();
+        let id = get_agent_id(dir);
+        assert_eq!(id.len(), 36);
+        assert_eq!(get_agent_id(dir), id);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
     #[test]
     fn test_match_path() {
         if get_os() == "illumos" {
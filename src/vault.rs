@@ -0,0 +1,552 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To handle files
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+// To manage paths
+use std::path::{Path, PathBuf};
+// To keep the versions of each path
+use std::collections::{BTreeMap, HashSet};
+// To share the vault between workers
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+// To compress stored contents
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+// To handle JSON objects
+use serde_json::{json, Value};
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+// To key contents by their checksum
+use crate::hash;
+// To get the current time as in events
+use crate::entry;
+// To parse restore times
+use crate::query;
+
+// Global constants definitions
+const INDEX_FILE: &str = "index.json";
+const OBJECTS_DIR: &str = "objects";
+// Event id of the versions stored when the agent starts
+const BASELINE_ID: &str = "baseline";
+const USAGE: &str = "Usage: ifim restore <path> --at <timestamp|event-id> [--to <file>]";
+
+// Suffix of temporary object files, contents are written out of the lock
+static TMP_COUNT: AtomicU64 = AtomicU64::new(0);
+
+// ----------------------------------------------------------------------------
+
+// A version of a path, removals are recorded without checksum
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Version {
+    id: String,
+    // Milliseconds, as event timestamps
+    timestamp: u64,
+    checksum: Option<String>,
+    mode: Option<u32>
+}
+
+impl Version {
+    fn to_json(&self, path: &str) -> Value {
+        json!({ "path": path, "id": self.id, "timestamp": self.timestamp, "checksum": self.checksum,
+            "mode": self.mode })
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Moment of a restore, a time or the event before which the file is wanted
+#[derive(Debug, PartialEq, Eq)]
+pub enum At {
+    Time(u64),
+    Event(String)
+}
+
+// A time as accepted by queries, otherwise an event id
+pub fn parse_at(value: &str) -> At {
+    match query::parse_time(value) {
+        Some(time) => At::Time(time),
+        None => At::Event(String::from(value))
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Vault shared by the workers, versions are indexed in a single file
+pub type Shared = Arc<Mutex<Vault>>;
+
+pub fn lock(vault: &Shared) -> MutexGuard<'_, Vault> {
+    vault.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// ----------------------------------------------------------------------------
+
+// Only the agent user can read the vault as it may hold secrets
+fn create_dir(dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+}
+
+// ----------------------------------------------------------------------------
+
+fn get_object(dir: &Path, checksum: &str) -> PathBuf {
+    dir.join(OBJECTS_DIR).join(&checksum[..checksum.len().min(2)]).join(format!("{}.gz", checksum))
+}
+
+// ----------------------------------------------------------------------------
+
+fn write_object(dir: &Path, checksum: &str, data: &[u8]) -> std::io::Result<()> {
+    let object = get_object(dir, checksum);
+    if object.exists() { return Ok(()) }
+    create_dir(dir)?;
+    fs::create_dir_all(object.parent().unwrap_or(dir))?;
+    // Workers may write the same contents at once
+    let tmp = object.with_extension(format!("{}.tmp", TMP_COUNT.fetch_add(1, Ordering::Relaxed)));
+    let file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp)?;
+    let mut encoder = GzEncoder::new(file, Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()?;
+    fs::rename(tmp, object)
+}
+
+// ----------------------------------------------------------------------------
+
+// Contents of a file to store, None if it is too big or unreadable
+fn read_contents(path: &str, max_file_size: u64) -> Option<Vec<u8>> {
+    let data = match fs::metadata(path) {
+        Ok(metadata) if metadata.len() <= max_file_size => fs::read(path),
+        Ok(_) => { debug!("{} is over the vault size limit", path); return None },
+        Err(e) => Err(e)
+    };
+    match data {
+        Ok(data) => Some(data),
+        Err(e) => { debug!("{} could not be stored in the vault, Err: [{}]", path, e); None }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Content-addressed store of the files of monitor entries with vault enabled,
+// contents are kept once per checksum and versions list them per path
+#[derive(Default)]
+pub struct Vault {
+    dir: PathBuf,
+    max_file_size: u64,
+    max_versions: usize,
+    // Milliseconds, 0 keeps versions forever
+    max_age: u64,
+    versions: BTreeMap<String, Vec<Version>>,
+    // Checksums being stored out of the lock, collect keeps their contents
+    writing: Vec<String>
+}
+
+impl Vault {
+    // Load the index of the vault
+    fn open(config: &config::Config) -> Self {
+        let mut vault = Vault {
+            dir: PathBuf::from(&config.vault_dir),
+            max_file_size: config.vault_max_file_size,
+            max_versions: config.vault_max_versions,
+            max_age: config.vault_max_age * 86400 * 1000,
+            versions: BTreeMap::new(),
+            writing: Vec::new()
+        };
+        vault.load();
+        vault
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Load the index of the vault, versions past the retention limits are removed
+    pub fn new(config: &config::Config) -> Self {
+        let mut vault = Vault::open(config);
+        let now = entry::get_timestamp().parse().unwrap_or(0);
+        let paths: Vec<String> = vault.versions.keys().cloned().collect();
+        let pruned: Vec<Version> = paths.iter().flat_map(|path| vault.prune(path, now)).collect();
+        if ! pruned.is_empty() {
+            vault.save();
+            vault.collect(pruned);
+        }
+        vault
+    }
+
+    // ------------------------------------------------------------------------
+
+    pub fn shared(config: &config::Config) -> Shared {
+        Arc::new(Mutex::new(Vault::new(config)))
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn load(&mut self) {
+        let file = match fs::File::open(self.dir.join(INDEX_FILE)) {
+            Ok(file) => file,
+            Err(_) => return
+        };
+        for line in BufReader::new(file).lines().map_while(Result::ok) {
+            let obj: Value = match serde_json::from_str(&line) {
+                Ok(obj) => obj,
+                Err(e) => { warn!("Vault index line ignored, Err: [{}]", e); continue }
+            };
+            let path = match obj["path"].as_str() {
+                Some(path) => String::from(path),
+                None => continue
+            };
+            self.versions.entry(path).or_default().push(Version {
+                id: String::from(obj["id"].as_str().unwrap_or("")),
+                timestamp: obj["timestamp"].as_u64().unwrap_or(0),
+                checksum: obj["checksum"].as_str().map(String::from),
+                mode: obj["mode"].as_u64().map(|m| m as u32)
+            });
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Rewrite the index once versions are removed
+    fn save(&self) {
+        let index = self.dir.join(INDEX_FILE);
+        let tmp = index.with_extension("tmp");
+        let lines: String = self.versions.iter()
+            .flat_map(|(path, versions)| versions.iter().map(move |v| format!("{}\n", v.to_json(path))))
+            .collect();
+        if let Err(e) = fs::write(&tmp, lines).and_then(|_| fs::rename(&tmp, &index)) {
+            error!("Vault index {} could not be saved, Err: [{}]", index.display(), e);
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn append(&self, path: &str, version: &Version) {
+        let result = create_dir(&self.dir)
+            .and_then(|_| fs::OpenOptions::new().create(true).append(true).mode(0o600).open(self.dir.join(INDEX_FILE)))
+            .and_then(|mut file| writeln!(file, "{}", version.to_json(path)));
+        if let Err(e) = result { error!("Vault version of {} could not be indexed, Err: [{}]", path, e); }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Contents of a checksum, checked against it
+    fn read_object(&self, checksum: &str) -> Result<Vec<u8>, String> {
+        let file = fs::File::open(get_object(&self.dir, checksum)).map_err(|e| format!("Stored contents missing: {}", e))?;
+        let mut data = Vec::new();
+        GzDecoder::new(file).read_to_end(&mut data).map_err(|e| format!("Stored contents unreadable: {}", e))?;
+        if hash::get_data_checksum(&data) != checksum { return Err(String::from("Stored contents corrupted")) }
+        Ok(data)
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Drop the versions of a path over the retention limits, the current
+    // contents of an existing file are kept whatever their age
+    fn prune(&mut self, path: &str, now: u64) -> Vec<Version> {
+        let versions = match self.versions.get_mut(path) {
+            Some(versions) => versions,
+            None => return Vec::new()
+        };
+        let mut pruned = Vec::new();
+        let excess = versions.len().saturating_sub(self.max_versions.max(1));
+        pruned.extend(versions.drain(..excess));
+        if self.max_age > 0 {
+            let cutoff = now.saturating_sub(self.max_age);
+            let keep_last = versions.last().is_some_and(|v| v.checksum.is_some());
+            let old = versions.iter().take_while(|v| v.timestamp < cutoff).count();
+            let old = if keep_last && old == versions.len() { old - 1 } else { old };
+            pruned.extend(versions.drain(..old));
+        }
+        if versions.is_empty() { self.versions.remove(path); }
+        pruned
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Remove the contents no version refers to anymore
+    fn collect(&self, pruned: Vec<Version>) {
+        let used: HashSet<&String> = self.versions.values().flatten().filter_map(|v| v.checksum.as_ref()).collect();
+        let unused: HashSet<String> = pruned.into_iter().filter_map(|v| v.checksum)
+            .filter(|c| ! used.contains(c) && ! self.writing.contains(c)).collect();
+        for checksum in unused {
+            let _ = fs::remove_file(get_object(&self.dir, &checksum));
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Protect contents being stored from collect until they are recorded
+    fn claim(&mut self, checksum: &str) {
+        self.writing.push(String::from(checksum));
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn release(&mut self, checksum: &str) {
+        if let Some(position) = self.writing.iter().position(|c| c == checksum) { self.writing.swap_remove(position); }
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn record(&mut self, path: &str, version: Version) {
+        let last = self.versions.get(path).and_then(|versions| versions.last());
+        // Unchanged contents and removals of unknown files are not recorded
+        if last.map(|v| &v.checksum) == Some(&version.checksum) { return }
+        if last.is_none() && version.checksum.is_none() { return }
+        self.append(path, &version);
+        let now = version.timestamp;
+        self.versions.entry(String::from(path)).or_default().push(version);
+        let pruned = self.prune(path, now);
+        if ! pruned.is_empty() {
+            self.save();
+            self.collect(pruned);
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Record the removal of a file, its last contents remain restorable
+    pub fn remove(&mut self, path: &str, id: &str, timestamp: &str) {
+        self.record(path, Version { id: String::from(id), timestamp: timestamp.parse().unwrap_or(0), checksum: None,
+            mode: None });
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Version a file had at a time, or right before an event on it
+    fn find(&self, path: &str, at: &At) -> Result<&Version, String> {
+        let versions = self.versions.get(path).ok_or(format!("No stored versions of {}", path))?;
+        let version = match at {
+            At::Time(time) => versions.iter().rev().find(|v| v.timestamp <= *time),
+            At::Event(id) => {
+                // Events leaving the contents unchanged, like metadata changes, add no version
+                let position = versions.iter().position(|v| v.id == *id)
+                    .ok_or(format!("Event {} did not change the stored contents of {}, restore it with --at <time>",
+                        id, path))?;
+                match versions[..position].last() {
+                    Some(version) => Some(version),
+                    None => return Err(format!("No version of {} stored before event {}", path, id))
+                }
+            }
+        };
+        match version {
+            Some(version) if version.checksum.is_some() => Ok(version),
+            Some(_) => Err(format!("{} did not exist at that time", path)),
+            None => Err(format!("No version of {} stored before that time", path))
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Write back a stored version of a file, to another file if given
+    pub fn restore(&self, path: &str, at: &At, target: &Path) -> Result<String, String> {
+        let version = self.find(path, at)?;
+        let checksum = version.checksum.as_deref().unwrap_or("");
+        let data = self.read_object(checksum)?;
+        let tmp = target.with_file_name(format!(".{}.ifim-restore",
+            target.file_name().and_then(|n| n.to_str()).unwrap_or("file")));
+        let result = (|| {
+            let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp)?;
+            file.write_all(&data)?;
+            file.sync_all()?;
+            if let Some(mode) = version.mode { fs::set_permissions(&tmp, fs::Permissions::from_mode(mode))?; }
+            fs::rename(&tmp, target)
+        })();
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp);
+            return Err(format!("{} could not be written: {}", target.display(), e))
+        }
+        Ok(format!("Restored {} ({} bytes) from version {} of {}, checksum {}", target.display(), data.len(),
+            version.id, version.timestamp, &checksum[..checksum.len().min(16)]))
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Store the current contents of a file changed by an event. The file is read
+// and compressed out of the lock, it is only held to update the index
+pub fn store(vault: &Shared, path: &str, checksum: Option<&str>, id: &str, timestamp: &str, mode: &str) {
+    let dir = lock(vault).dir.clone();
+    // Contents already stored under the event checksum are not read again
+    let stored = checksum.filter(|c| *c != "UNKNOWN").and_then(|checksum| {
+        lock(vault).claim(checksum);
+        if get_object(&dir, checksum).exists() { return Some(String::from(checksum)) }
+        lock(vault).release(checksum);
+        None
+    });
+    let checksum = match stored {
+        Some(checksum) => checksum,
+        None => {
+            let max_file_size = lock(vault).max_file_size;
+            let data = match read_contents(path, max_file_size) {
+                Some(data) => data,
+                None => return
+            };
+            // The file may have changed since the event, keep what was read
+            let checksum = hash::get_data_checksum(&data);
+            lock(vault).claim(&checksum);
+            if let Err(e) = write_object(&dir, &checksum, &data) {
+                error!("Contents of {} could not be stored in the vault, Err: [{}]", path, e);
+                lock(vault).release(&checksum);
+                return
+            }
+            checksum
+        }
+    };
+    let mut vault = lock(vault);
+    vault.release(&checksum);
+    vault.record(path, Version {
+        id: String::from(id),
+        timestamp: timestamp.parse().unwrap_or(0),
+        checksum: Some(checksum),
+        mode: u32::from_str_radix(mode, 8).ok().map(|m| m & 0o7777)
+    });
+}
+
+// ----------------------------------------------------------------------------
+
+// Store files seen when the agent starts that have no version yet
+pub fn baseline(vault: &Shared, path: &Path) {
+    let path = path.display().to_string();
+    if lock(vault).versions.contains_key(&path) { return }
+    let mode = fs::metadata(&path).map(|m| format!("{:o}", m.permissions().mode())).unwrap_or_default();
+    store(vault, &path, None, BASELINE_ID, &entry::get_timestamp(), &mode);
+}
+
+// ----------------------------------------------------------------------------
+
+// Command line entry point of `ifim restore <path> --at <timestamp|event-id> [--to <file>]`
+pub fn restore_command(config: &config::Config, args: &[String]) -> i32 {
+    let (mut path, mut at, mut target) = (None, None, None);
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--at" => match iter.next() {
+                Some(value) => at = Some(parse_at(value)),
+                None => { eprintln!("Missing value of --at"); return 2 }
+            },
+            "--to" => match iter.next() {
+                Some(value) => target = Some(PathBuf::from(value)),
+                None => { eprintln!("Missing value of --to"); return 2 }
+            },
+            value => path = Some(value)
+        }
+    }
+    let (path, at) = match (path, at) {
+        (Some(path), Some(at)) => (path, at),
+        _ => { eprintln!("{}", USAGE); return 2 }
+    };
+    // Versions are stored under absolute paths, the file may not exist anymore
+    let path = std::path::absolute(path).unwrap_or_else(|_| PathBuf::from(path));
+    let target = target.unwrap_or_else(|| path.clone());
+    // The running agent owns the index, it is only read
+    match Vault::open(config).restore(&path.display().to_string(), &at, &target) {
+        Ok(message) => { println!("{}", message); 0 },
+        Err(e) => { eprintln!("{}", e); 1 }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+
+    fn create_test_vault(dir: &str, max_versions: usize) -> Vault {
+        let mut config = create_test_config("info");
+        config.vault_dir = format!("{}/vault", dir);
+        config.vault_max_file_size = 1024;
+        config.vault_max_versions = max_versions;
+        // Test timestamps are decades old
+        config.vault_max_age = 0;
+        Vault::new(&config)
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_parse_at() {
+        assert_eq!(parse_at("1654041600000"), At::Time(1654041600000));
+        assert_eq!(parse_at("2022-06-01T00:00:00Z"), At::Time(1654041600000));
+        assert_eq!(parse_at("9f1c2b7e-uuid"), At::Event(String::from("9f1c2b7e-uuid")));
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_restore() {
+        let dir = "test_vault_restore";
+        fs::create_dir_all(dir).unwrap();
+        let file = format!("{}/app.conf", dir);
+        let vault = Arc::new(Mutex::new(create_test_vault(dir, 10)));
+        fs::write(&file, "v1\n").unwrap();
+        store(&vault, &file, None, "e1", "1000", "100640");
+        // Unchanged contents are not recorded again
+        store(&vault, &file, Some(&hash::get_data_checksum(b"v1\n")), "e2", "2000", "100640");
+        fs::write(&file, "v2\n").unwrap();
+        // The file changed since the event checksum, what was read is stored
+        store(&vault, &file, Some(&hash::get_data_checksum(b"v3\n")), "e3", "3000", "100644");
+        fs::remove_file(&file).unwrap();
+        lock(&vault).remove(&file, "e4", "4000");
+        assert_eq!(lock(&vault).versions[&file].len(), 3);
+        assert!(lock(&vault).writing.is_empty());
+
+        // The index is reloaded as written
+        let vault = create_test_vault(dir, 10);
+        assert_eq!(vault.versions[&file].iter().map(|v| v.id.as_str()).collect::<Vec<_>>(), vec!["e1", "e3", "e4"]);
+
+        let target = PathBuf::from(&file);
+        vault.restore(&file, &At::Time(2500), &target).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "v1\n");
+        assert_eq!(fs::metadata(&file).unwrap().permissions().mode() & 0o7777, 0o640);
+        // Right before the removal event
+        vault.restore(&file, &At::Event(String::from("e4")), &target).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "v2\n");
+        assert!(vault.restore(&file, &At::Time(4500), &target).is_err());
+        assert!(vault.restore(&file, &At::Time(500), &target).is_err());
+        assert!(vault.restore(&file, &At::Event(String::from("e9")), &target).is_err());
+        // Events that did not change the contents have no version to restore
+        let error = vault.restore(&file, &At::Event(String::from("e2")), &target).unwrap_err();
+        assert!(error.contains("did not change the stored contents"));
+        let error = vault.restore(&file, &At::Event(String::from("e1")), &target).unwrap_err();
+        assert!(error.contains("before event e1"));
+        assert!(vault.restore("/not/stored", &At::Time(500), &target).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_retention() {
+        let dir = "test_vault_retention";
+        fs::create_dir_all(dir).unwrap();
+        let file = format!("{}/hosts", dir);
+        let shared = Arc::new(Mutex::new(create_test_vault(dir, 2)));
+        for (index, contents) in ["a", "b", "c"].iter().enumerate() {
+            fs::write(&file, contents).unwrap();
+            store(&shared, &file, None, &format!("e{}", index), &format!("{}", (index + 1) * 1000), "100644");
+        }
+        let mut vault = lock(&shared);
+        assert_eq!(vault.versions[&file].len(), 2);
+        // Contents of the dropped version are removed, not those still used
+        assert!(! get_object(&vault.dir, &hash::get_data_checksum(b"a")).exists());
+        assert!(get_object(&vault.dir, &hash::get_data_checksum(b"b")).exists());
+        assert_eq!(create_test_vault(dir, 2).versions[&file].len(), 2);
+
+        // Old versions go but the current contents of the file stay
+        vault.max_age = 1000;
+        let pruned = vault.prune(&file, 10000);
+        assert_eq!(pruned.len(), 1);
+        assert_eq!(vault.versions[&file][0].id, "e2");
+
+        // Files over the size limit are not stored
+        fs::write(&file, "a".repeat(2048)).unwrap();
+        drop(vault);
+        store(&shared, &file, None, "e3", "11000", "100644");
+        assert_eq!(lock(&shared).versions[&file].len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,293 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To walk monitored directories
+use std::fs;
+// To manage paths
+use std::path::{Path, PathBuf};
+// To keep watched directories
+use std::collections::HashSet;
+// To watch directories
+use notify::{Error, ErrorKind, Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::event::ModifyKind;
+// To handle JSON objects
+use serde_json::{json, Value};
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+
+// ----------------------------------------------------------------------------
+
+// Watched directories of a monitor entry, subtrees that could not be watched
+// are scanned periodically instead
+pub struct Monitored {
+    pub path: PathBuf,
+    pub index: usize,
+    dirs: HashSet<PathBuf>,
+    unwatched: Vec<PathBuf>,
+    error: Option<String>,
+    // Watcher of the entries polled instead of natively watched
+    poller: Option<PollWatcher>,
+    interval: u64
+}
+
+impl Monitored {
+    fn new(path: PathBuf, index: usize) -> Self {
+        Monitored { path, index, dirs: HashSet::new(), unwatched: Vec::new(), error: None, poller: None, interval: 0 }
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn contains(&self, path: &Path) -> bool {
+        path.starts_with(&self.path)
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Directory watches of every monitor entry, each directory is watched on its
+// own so the ones over the system limit are known
+pub struct Watches {
+    watcher: RecommendedWatcher,
+    monitored: Vec<Monitored>
+}
+
+impl Watches {
+    pub fn new(watcher: RecommendedWatcher) -> Self {
+        Watches { watcher, monitored: Vec::new() }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Watch every directory of a monitor entry
+    pub fn add(&mut self, config: &config::Config, index: usize) {
+        let path = PathBuf::from(config.monitor[index]["path"].as_str().unwrap_or(""));
+        let mut monitored = Monitored::new(path.clone(), index);
+        watch_tree(&mut self.watcher, &mut monitored, &path);
+        if monitored.dirs.is_empty() && monitored.unwatched.is_empty() && monitored.error.is_none() {
+            // Single files are watched as they are
+            match self.watcher.watch(&path, RecursiveMode::NonRecursive) {
+                Ok(_) => { monitored.dirs.insert(path.clone()); },
+                Err(e) => monitored.error = Some(e.to_string())
+            }
+        }
+        match &monitored.error {
+            None => info!("Monitoring path: {}, {} directories watched", path.display(), monitored.dirs.len()),
+            Some(e) => error!("Monitoring path: {}, {} directories watched, {} subtrees not watched \
+                and scanned periodically instead, Err: [{}]", path.display(), monitored.dirs.len(),
+                monitored.unwatched.len(), e)
+        }
+        self.monitored.push(monitored);
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Poll a monitor entry with its own watcher, new directories are found
+    // by the poller itself
+    pub fn add_poll(&mut self, config: &config::Config, index: usize, mut poller: PollWatcher) {
+        let path = PathBuf::from(config.monitor[index]["path"].as_str().unwrap_or(""));
+        let mut monitored = Monitored::new(path.clone(), index);
+        monitored.interval = config.get_poll_interval(index);
+        match poller.watch(&path, RecursiveMode::Recursive) {
+            Ok(_) => {
+                count_dirs(&path, &mut monitored.dirs);
+                info!("Monitoring path: {}, {} directories polled every {} seconds", path.display(),
+                    monitored.dirs.len(), monitored.interval);
+            },
+            Err(e) => {
+                error!("Could not poll path {}, Err: [{}]", path.display(), e);
+                monitored.error = Some(e.to_string());
+            }
+        }
+        monitored.poller = Some(poller);
+        self.monitored.push(monitored);
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Keep watches up to date with directories created, moved or removed,
+    // returns the new directories, their files may predate the watch
+    pub fn update(&mut self, event: &Event) -> Vec<(usize, PathBuf)> {
+        let added = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_)));
+        let removed = matches!(event.kind, EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_)));
+        let mut new = Vec::new();
+        for path in &event.paths {
+            let monitored = match self.monitored.iter_mut().find(|m| m.contains(path)) {
+                Some(monitored) if monitored.poller.is_none() => monitored,
+                _ => continue
+            };
+            if removed && ! path.exists() {
+                monitored.dirs.retain(|d| ! d.starts_with(path));
+                monitored.unwatched.retain(|d| ! d.starts_with(path));
+            } else if added && path.is_dir() && ! monitored.dirs.contains(path) {
+                if watch_tree(&mut self.watcher, monitored, path) { new.push((monitored.index, path.clone())); }
+                debug!("New directory watched: {}", path.display());
+            }
+        }
+        new
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Try again to watch the subtrees that could not be watched, returns the
+    // ones still unwatched that have to be scanned
+    pub fn retry(&mut self) -> Vec<(usize, PathBuf)> {
+        let mut unwatched = Vec::new();
+        for monitored in self.monitored.iter_mut() {
+            let roots = std::mem::take(&mut monitored.unwatched);
+            if ! roots.is_empty() {
+                monitored.error = None;
+                for root in roots {
+                    if root.exists() { watch_tree(&mut self.watcher, monitored, &root); }
+                }
+                if monitored.unwatched.is_empty() {
+                    info!("Monitoring path: {}, every directory watched again", monitored.path.display());
+                }
+            }
+            unwatched.extend(monitored.unwatched.iter().map(|root| (monitored.index, root.clone())));
+        }
+        unwatched
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Directories watched or polled by every monitor entry
+    pub fn watched_dirs(&self) -> usize {
+        self.monitored.iter().map(|m| m.dirs.len()).sum()
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Watch state of every monitor entry
+    pub fn status(&self) -> Value {
+        json!(self.monitored.iter().map(|m| json!({
+            "path": m.path.display().to_string(),
+            "backend": if m.poller.is_some() { "poll" } else { "native" },
+            "interval": m.interval,
+            "watched_dirs": m.dirs.len(),
+            "unwatched": m.unwatched.iter().map(|p| p.display().to_string()).collect::<Vec<String>>(),
+            "error": m.error.clone()
+        })).collect::<Vec<Value>>())
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Watch a directory and its subdirectories, symlinks are not followed,
+// returns if the directory could be watched
+fn watch_tree(watcher: &mut RecommendedWatcher, monitored: &mut Monitored, dir: &Path) -> bool {
+    match fs::symlink_metadata(dir) {
+        Ok(metadata) if metadata.is_dir() => {},
+        Ok(_) => return true,
+        Err(e) => { monitored.error = Some(e.to_string()); return false }
+    }
+    if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+        debug!("Could not watch {}, Err: [{}]", dir.display(), e);
+        if is_limit(&e) || monitored.error.is_none() { monitored.error = Some(e.to_string()); }
+        monitored.unwatched.push(dir.to_path_buf());
+        return false
+    }
+    monitored.dirs.insert(dir.to_path_buf());
+    match fs::read_dir(dir) {
+        Ok(entries) => for entry in entries.flatten() {
+            if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                watch_tree(watcher, monitored, &entry.path());
+            }
+        },
+        Err(e) => warn!("Could not read directory {}, Err: [{}]", dir.display(), e)
+    }
+    true
+}
+
+// ----------------------------------------------------------------------------
+
+// Collect the directories below a polled path
+fn count_dirs(dir: &Path, dirs: &mut HashSet<PathBuf>) {
+    if ! fs::symlink_metadata(dir).map(|m| m.is_dir()).unwrap_or(false) { return }
+    dirs.insert(dir.to_path_buf());
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() { count_dirs(&entry.path(), dirs); }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Returns if a watch failed because of the system limit of watches
+fn is_limit(e: &Error) -> bool {
+    matches!(e.kind, ErrorKind::MaxFilesWatch)
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+    use yaml_rust::YamlLoader;
+
+    fn create_test_watches(dir: &str) -> (Watches, config::Config) {
+        fs::create_dir_all(format!("{}/a/b", dir)).unwrap();
+        fs::create_dir_all(format!("{}/c", dir)).unwrap();
+        let mut config = create_test_config("info");
+        let yaml = format!("- path: {}\n", dir);
+        config.monitor = YamlLoader::load_from_str(&yaml).unwrap()[0].as_vec().unwrap().to_vec();
+        let watcher = RecommendedWatcher::new(|_| {}, notify::Config::default()).unwrap();
+        (Watches::new(watcher), config)
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_add() {
+        let dir = "test_watch_add";
+        let (mut watches, config) = create_test_watches(dir);
+        watches.add(&config, 0);
+        let status = watches.status();
+        assert_eq!(status[0]["watched_dirs"], 4);
+        assert_eq!(watches.watched_dirs(), 4);
+        assert_eq!(status[0]["unwatched"], json!([]));
+        assert!(watches.retry().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_add_poll() {
+        let dir = "test_watch_poll";
+        let (mut watches, config) = create_test_watches(dir);
+        let poller = PollWatcher::new(|_| {}, notify::Config::default()).unwrap();
+        watches.add_poll(&config, 0, poller);
+        let status = watches.status();
+        assert_eq!(status[0]["backend"], "poll");
+        assert_eq!(status[0]["interval"], 10);
+        assert_eq!(status[0]["watched_dirs"], 4);
+        // Polled entries find new directories on their own
+        let new = PathBuf::from(format!("{}/d", dir));
+        fs::create_dir_all(&new).unwrap();
+        let created = Event::new(EventKind::Create(notify::event::CreateKind::Folder)).add_path(new);
+        assert!(watches.update(&created).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_update() {
+        let dir = "test_watch_update";
+        let (mut watches, config) = create_test_watches(dir);
+        watches.add(&config, 0);
+        let new = PathBuf::from(format!("{}/d", dir));
+        fs::create_dir_all(new.join("e")).unwrap();
+        let created = Event::new(EventKind::Create(notify::event::CreateKind::Folder)).add_path(new.clone());
+        assert_eq!(watches.update(&created), vec![(0, new.clone())]);
+        assert_eq!(watches.status()[0]["watched_dirs"], 6);
+
+        fs::remove_dir_all(&new).unwrap();
+        let removed = Event::new(EventKind::Remove(notify::event::RemoveKind::Folder)).add_path(new);
+        assert!(watches.update(&removed).is_empty());
+        assert_eq!(watches.status()[0]["watched_dirs"], 4);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
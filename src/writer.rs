@@ -0,0 +1,629 @@
+// Copyright 2022 Tintri by DDN, Inc. All rights reserved.
+
+// To handle files
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::MetadataExt;
+// To manage paths
+use std::path::{Path, PathBuf};
+// To keep events that could not be written yet
+use std::collections::VecDeque;
+// To manage date and time
+use std::time::{Duration, SystemTime};
+// To count write errors
+use std::sync::atomic::Ordering;
+// To communicate with the writer task
+use tokio::sync::{mpsc, oneshot};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+// To handle JSON objects
+use serde_json::Value;
+// To log the program process
+use log::*;
+
+// To get configuration constants
+use crate::config;
+// To render events in the configured format
+use crate::format;
+// To rotate the events file
+use crate::rotation;
+// To chain events hashes
+use crate::chain;
+// To sign events checkpoints
+use crate::signing;
+// To add agent metadata to checkpoints
+use crate::entry;
+// To send events to syslog
+use crate::syslog;
+// To count write errors
+use crate::metrics;
+// To run the hook commands of events
+use crate::hooks;
+// To store events in the SQLite database
+use crate::database;
+
+// Global constants definitions
+const CHANNEL_SIZE: usize = 1024;
+const MAX_PENDING: usize = 100000;
+
+// ----------------------------------------------------------------------------
+
+pub enum Message {
+    // Event object and its severity
+    Event(Value, u8),
+    // Write all buffered events and answer once done
+    Flush(oneshot::Sender<()>)
+}
+
+// ----------------------------------------------------------------------------
+
+// Cloneable handle used to feed the events writer task
+#[derive(Clone)]
+pub struct Handle {
+    tx: mpsc::Sender<Message>,
+    // Network output, started along the writer when configured
+    syslog: Option<syslog::Handle>,
+    // SQLite store, started along the writer when configured
+    database: Option<database::Handle>,
+    hooks: hooks::Handle
+}
+
+impl Handle {
+    // Queue an event to be written, waits while the queue is full
+    pub async fn write(&self, obj: Value, severity: u8) {
+        if let Some(database) = &self.database { database.insert(&obj, severity).await; }
+        if self.tx.send(Message::Event(obj, severity)).await.is_err() {
+            error!("Events writer is not running, event dropped");
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Queue an event line already rendered in the syslog format
+    pub async fn syslog(&self, line: &str, severity: u8) {
+        if let Some(syslog) = &self.syslog { syslog.log(line, severity).await; }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Start the hook commands of an event, waits while too many are running
+    pub async fn exec(&self, commands: &[String], obj: &Value, severity: u8) {
+        self.hooks.run(commands, obj, severity).await;
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Wait until every queued event has been written to disk
+    pub async fn flush(&self) {
+        let (tx, rx) = oneshot::channel();
+        if self.tx.send(Message::Flush(tx)).await.is_ok() {
+            let _ = rx.await;
+        }
+        if let Some(syslog) = &self.syslog { syslog.flush().await; }
+        if let Some(database) = &self.database { database.flush().await; }
+        self.hooks.wait().await;
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+struct Writer {
+    config: config::Config,
+    file: Option<BufWriter<File>>,
+    // Device and inode of the open file, to detect moves and removals
+    id: (u64, u64),
+    // Lines given to the buffered file since the last flush and file size
+    // at that flush, they go back to pending if the flush fails
+    unflushed: Vec<String>,
+    synced: u64,
+    pending: VecDeque<String>,
+    dropped: u64,
+    failing: bool,
+    started: SystemTime,
+    // Compression of the last rotated file, run on a blocking thread
+    compression: Option<JoinHandle<()>>,
+    chain: chain::Chain,
+    signer: signing::Signer
+}
+
+impl Writer {
+    fn new(config: config::Config) -> Self {
+        Writer {
+            file: None,
+            id: (0, 0),
+            unflushed: Vec::new(),
+            synced: 0,
+            pending: VecDeque::new(),
+            dropped: 0,
+            failing: false,
+            started: SystemTime::now(),
+            compression: None,
+            chain: chain::Chain::new(&config),
+            signer: signing::Signer::new(&config),
+            config
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn enabled(&self) -> bool {
+        self.config.events_file != "Not_used"
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Open the events file, creating its directory if it was removed
+    fn open(&mut self) -> std::io::Result<()> {
+        let path = Path::new(&self.config.events_file);
+        if let Some(parent) = path.parent() {
+            if ! parent.as_os_str().is_empty() { fs::create_dir_all(parent)?; }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let metadata = file.metadata()?;
+        self.id = (metadata.dev(), metadata.ino());
+        self.synced = metadata.len();
+        self.file = Some(BufWriter::new(file));
+        debug!("Events file opened: {}", self.config.events_file);
+        // Lines kept in memory were already chained to the previous file contents
+        if self.pending.is_empty() && self.unflushed.is_empty() { self.chain.resume(&self.config.events_file); }
+        self.signer.resume(&self.config.events_file);
+        Ok(())
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Flush and drop the open file, it is opened again on next write
+    fn close(&mut self) {
+        // Its last lines are signed even if it was moved, truncated files lost them
+        if ! self.is_truncated() { self.checkpoint(); }
+        if let Err(e) = self.sync() { self.fail(e); }
+        self.file = None;
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Returns if the events file path no longer points to the open file, or
+    // if it was truncated under it like logrotate copytruncate does
+    fn is_stale(&self) -> bool {
+        match fs::metadata(&self.config.events_file) {
+            Ok(metadata) => (metadata.dev(), metadata.ino()) != self.id || metadata.len() < self.synced,
+            Err(_) => true
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn is_truncated(&self) -> bool {
+        fs::metadata(&self.config.events_file)
+            .is_ok_and(|m| (m.dev(), m.ino()) == self.id && m.len() < self.synced)
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Keep a line in memory until the events file is writable again
+    fn keep(&mut self, line: String) {
+        if self.pending.len() >= MAX_PENDING {
+            self.pending.pop_front();
+            self.dropped += 1;
+        }
+        self.pending.push_back(line);
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn write_line(&mut self, line: String) -> std::io::Result<()> {
+        let opened = if self.file.is_none() { self.open() } else { Ok(()) };
+        // From now on a failure puts the line back in pending
+        self.unflushed.push(line);
+        opened?;
+        let file = self.file.as_mut().unwrap();
+        writeln!(file, "{}", self.unflushed.last().unwrap())?;
+        if self.config.flush_interval == 0 { self.sync()?; }
+        Ok(())
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Flush buffered data, with fsync if configured, flushed lines are the
+    // ones covered by the next checkpoint
+    fn sync(&mut self) -> std::io::Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.flush()?;
+            if self.config.fsync { file.get_ref().sync_data()?; }
+            for line in self.unflushed.drain(..) {
+                self.synced += line.len() as u64 + 1;
+                self.signer.push(&line);
+            }
+        }
+        Ok(())
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn write(&mut self, line: String) {
+        if ! self.pending.is_empty() {
+            self.keep(line);
+            return
+        }
+        if let Err(e) = self.write_line(line) { self.fail(e); }
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn fail(&mut self, e: std::io::Error) {
+        metrics::FILE_ERRORS.fetch_add(1, Ordering::Relaxed);
+        if ! self.failing {
+            error!("Events could not be written to {}, keeping them in memory, Err: [{}]",
+                self.config.events_file, e);
+            self.failing = true;
+        }
+        // Partially written buffers are discarded with the file handle and
+        // the file is cut back to its last flushed line
+        if let Some(file) = self.file.take() {
+            let _ = file.into_parts();
+            if ! self.is_stale() {
+                rotation::mark_truncated(Path::new(&self.config.events_file));
+                if let Err(e) = OpenOptions::new().write(true).open(&self.config.events_file)
+                    .and_then(|f| f.set_len(self.synced)) {
+                    debug!("Events file could not be truncated after a failure, Err: [{}]", e);
+                }
+            }
+        }
+        // Unflushed lines are older than the pending ones
+        for line in self.unflushed.drain(..).rev() { self.pending.push_front(line); }
+        while self.pending.len() > MAX_PENDING {
+            self.pending.pop_front();
+            self.dropped += 1;
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Try to write the events kept in memory after a failure
+    fn retry(&mut self) {
+        while let Some(line) = self.pending.pop_front() {
+            if let Err(e) = self.write_line(line) {
+                self.fail(e);
+                return
+            }
+        }
+        if self.failing {
+            if let Err(e) = self.sync() { self.fail(e); return }
+            info!("Events file {} writable again, {} events dropped meanwhile",
+                self.config.events_file, self.dropped);
+            self.failing = false;
+            self.dropped = 0;
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Periodic maintenance, flush, reopen moved or removed files and rotate
+    fn tick(&mut self) {
+        if ! self.enabled() { return }
+        self.retry();
+        if let Err(e) = self.sync() { self.fail(e); }
+        if self.file.is_some() && self.is_stale() {
+            info!("Events file {} was moved, removed or truncated, reopening", self.config.events_file);
+            self.close();
+        }
+        // The previous rotated file has to be compressed before shifting it
+        if self.compression.as_ref().is_some_and(|task| ! task.is_finished()) { return }
+        if rotation::needs_rotation(&self.config, self.started) {
+            self.close();
+            match rotation::rotate(&self.config.events_file, self.config.rotation_keep) {
+                Ok(rotated) => {
+                    info!("Events file rotated: {}", self.config.events_file);
+                    if let Some(path) = rotated.filter(|_| ! rotation::get_extension(&self.config.rotation_compress).is_empty()) {
+                        self.compress(path);
+                    }
+                },
+                Err(e) => error!("Events file could not be rotated, Err: [{}]", e)
+            }
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Compress a rotated file without blocking the writer task
+    fn compress(&mut self, path: PathBuf) {
+        let compression = self.config.rotation_compress.clone();
+        self.compression = Some(tokio::task::spawn_blocking(move || {
+            if let Err(e) = rotation::compress(&path, &compression) {
+                error!("Rotated events file {} could not be compressed, Err: [{}]", path.display(), e);
+            }
+        }));
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn handle(&mut self, obj: Value, severity: u8) {
+        if let Some(line) = self.render(obj, severity) { self.write(line); }
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Chain and render an event in the configured format
+    fn render(&mut self, mut obj: Value, severity: u8) -> Option<String> {
+        if ! self.enabled() { return None }
+        // The chain is resumed from the events file when it is opened
+        if self.chain.enabled && self.file.is_none() && self.pending.is_empty() {
+            if let Err(e) = self.open() { self.fail(e); }
+        }
+        self.chain.link(&mut obj);
+        let line = format::render(&self.config.events_format, &obj, severity, &self.config.version);
+        self.chain.push(&line);
+        Some(line)
+    }
+
+    // ------------------------------------------------------------------------
+
+    // Write a signed checkpoint over the events written to the open file since
+    // the last one, events kept in memory are signed once written
+    fn checkpoint(&mut self) {
+        if ! self.signer.enabled() || self.file.is_none() || ! self.pending.is_empty() { return }
+        if let Err(e) = self.sync() { self.fail(e); return }
+        if let Some(mut obj) = self.signer.checkpoint() {
+            entry::add_metadata(&mut obj, &self.config);
+            if let Some(line) = self.render(obj, format::get_severity("CHECKPOINT")) {
+                self.write(line);
+            }
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Start the events writer task
+pub fn spawn(config: config::Config) -> Handle {
+    let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+    let syslog = syslog::spawn(&config);
+    let database = database::spawn(&config);
+    let hooks = hooks::Handle::new(&config);
+    tokio::spawn(run(config, rx));
+    Handle { tx, syslog, database, hooks }
+}
+
+// ----------------------------------------------------------------------------
+
+async fn run(config: config::Config, mut rx: mpsc::Receiver<Message>) {
+    // Maintenance still runs every second when every event is flushed
+    let period = match config.flush_interval { 0 => 1000, ms => ms };
+    let mut interval = tokio::time::interval(Duration::from_millis(period));
+    let mut hangup = signal(SignalKind::hangup()).ok();
+    let mut signing = tokio::time::interval(Duration::from_secs(config.signing_interval.max(1)));
+    let mut writer = Writer::new(config);
+
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(Message::Event(obj, severity)) => writer.handle(obj, severity),
+                Some(Message::Flush(done)) => {
+                    writer.retry();
+                    writer.checkpoint();
+                    if let Err(e) = writer.sync() { writer.fail(e); }
+                    let _ = done.send(());
+                },
+                None => break
+            },
+            _ = interval.tick() => writer.tick(),
+            _ = signing.tick() => writer.checkpoint(),
+            // Reopen the events file on SIGHUP
+            Some(_) = async { match hangup.as_mut() { Some(s) => s.recv().await, None => None } } => {
+                info!("SIGHUP received, reopening events file");
+                writer.close();
+            }
+        }
+    }
+    writer.retry();
+    writer.close();
+    if let Some(task) = writer.compression.take() { let _ = task.await; }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_write_and_flush() {
+        let filename = String::from("test_writer_flush.json");
+        let mut config = create_test_config("info");
+        config.events_file = filename.clone();
+        config.flush_interval = 60000;
+
+        let events = spawn(config);
+        events.write(json!({"id": "1"}), 5).await;
+        events.write(json!({"id": "2"}), 5).await;
+        events.flush().await;
+        assert_eq!(fs::read_to_string(&filename).unwrap(), "{\"id\":\"1\"}\n{\"id\":\"2\"}\n");
+        fs::remove_file(filename).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_recover_removed_dir() {
+        let dir = "test_writer_removed";
+        let filename = format!("{}/events.json", dir);
+        let mut config = create_test_config("info");
+        config.events_file = filename.clone();
+
+        let mut writer = Writer::new(config);
+        writer.write(String::from("first"));
+        fs::remove_dir_all(dir).unwrap();
+        writer.tick();
+        writer.write(String::from("second"));
+        writer.sync().unwrap();
+        assert_eq!(fs::read_to_string(&filename).unwrap(), "second\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn test_chain() {
+        let dir = "test_writer_chain";
+        let filename = format!("{}/events.json", dir);
+        let mut config = create_test_config("info");
+        config.events_file = filename.clone();
+        config.chain = true;
+
+        let events = spawn(config);
+        for i in 0..3 { events.write(json!({"id": i}), 5).await; }
+        events.flush().await;
+        let seeds = chain::read_seeds(&chain::get_sidecar(&filename));
+        assert_eq!(seeds.len(), 1);
+        assert_eq!(chain::verify(Path::new(&filename), &seeds), Ok(3));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn test_signed_checkpoint() {
+        let dir = "test_writer_signing";
+        let filename = format!("{}/events.json", dir);
+        let (key, public) = create_test_key(dir);
+        let mut config = create_test_config("info");
+        config.events_file = filename.clone();
+        config.signing_key = key;
+        config.chain = true;
+
+        let events = spawn(config);
+        for i in 0..3 { events.write(json!({"id": i}), 5).await; }
+        events.flush().await;
+        assert_eq!(signing::verify(Path::new(&filename), &public), Ok((3, 0)));
+        let seeds = chain::read_seeds(&chain::get_sidecar(&filename));
+        assert_eq!(chain::verify(Path::new(&filename), &seeds), Ok(4));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_keep_pending() {
+        let mut config = create_test_config("info");
+        config.events_file = String::from("/dev/null/events.json");
+        let mut writer = Writer::new(config);
+        writer.write(String::from("first"));
+        writer.write(String::from("second"));
+        assert!(writer.failing);
+        assert_eq!(writer.pending.len(), 2);
+        assert_eq!(writer.pending[0], "first");
+    }
+
+    // ------------------------------------------------------------------------
+
+    fn create_test_key(dir: &str) -> (String, ed25519_dalek::VerifyingKey) {
+        let key = format!("{}/signing.key", dir);
+        fs::create_dir_all(dir).unwrap();
+        fs::write(&key, hex::encode([3u8; 32])).unwrap();
+        (key, ed25519_dalek::SigningKey::from_bytes(&[3u8; 32]).verifying_key())
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_keep_unflushed() {
+        let dir = "test_writer_unflushed";
+        let filename = format!("{}/events.json", dir);
+        let (key, public) = create_test_key(dir);
+        let mut config = create_test_config("info");
+        config.events_file = filename.clone();
+        config.flush_interval = 60000;
+        config.signing_key = key;
+
+        let mut writer = Writer::new(config);
+        writer.write(String::from("first"));
+        writer.sync().unwrap();
+        writer.write(String::from("second"));
+        writer.write(String::from("third"));
+        // Part of the buffer reached the file before the flush failed
+        OpenOptions::new().append(true).open(&filename).unwrap().write_all(b"sec").unwrap();
+        *writer.file.as_mut().unwrap().get_mut() = File::open(&filename).unwrap();
+        writer.tick();
+        assert!(writer.failing);
+        assert_eq!(writer.pending, ["second", "third"]);
+        assert_eq!(fs::read_to_string(&filename).unwrap(), "first\n");
+        writer.tick();
+        assert!(! writer.failing);
+        assert_eq!(fs::read_to_string(&filename).unwrap(), "first\nsecond\nthird\n");
+        // Truncated lines are signed once written again
+        writer.checkpoint();
+        writer.sync().unwrap();
+        assert_eq!(signing::verify(Path::new(&filename), &public), Ok((3, 0)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_signed_rotation() {
+        let dir = "test_writer_signed_rotation";
+        let filename = format!("{}/events.json", dir);
+        let (key, public) = create_test_key(dir);
+        let mut config = create_test_config("info");
+        config.events_file = filename.clone();
+        config.signing_key = key;
+        config.rotation_size = 10;
+
+        let mut writer = Writer::new(config);
+        writer.handle(json!({"id": 1}), 5);
+        writer.handle(json!({"id": 2}), 5);
+        writer.tick();
+        writer.handle(json!({"id": 3}), 5);
+        writer.close();
+        let rotated = format!("{}.1", filename);
+        assert_eq!(signing::verify(Path::new(&rotated), &public), Ok((2, 0)));
+        assert_eq!(signing::verify(Path::new(&filename), &public), Ok((1, 0)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn test_reopen_truncated() {
+        let dir = "test_writer_truncated";
+        let filename = format!("{}/events.json", dir);
+        let mut config = create_test_config("info");
+        config.events_file = filename.clone();
+
+        let mut writer = Writer::new(config);
+        writer.write(String::from("first"));
+        // Like logrotate copytruncate, lines keep being appended from the start
+        File::create(&filename).unwrap();
+        writer.tick();
+        writer.write(String::from("second"));
+        assert_eq!(writer.synced, 7);
+        writer.fail(std::io::Error::other("test"));
+        assert_eq!(fs::read_to_string(&filename).unwrap(), "second\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn test_rotate_compress() {
+        let dir = "test_writer_rotate";
+        let filename = format!("{}/events.json", dir);
+        let mut config = create_test_config("info");
+        config.events_file = filename.clone();
+        config.rotation_size = 1;
+        config.rotation_compress = String::from("gzip");
+
+        let mut writer = Writer::new(config);
+        writer.write(String::from("first"));
+        writer.tick();
+        writer.compression.take().unwrap().await.unwrap();
+        assert!(Path::new(&format!("{}.1.gz", filename)).exists());
+        assert!(!Path::new(&format!("{}.1", filename)).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}